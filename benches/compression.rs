@@ -0,0 +1,86 @@
+//! Measures how compression changes the responses of a text server.
+//!
+//! Before timing, the number of fragments of each response is printed with and without
+//! compression. Run with `cargo bench --bench compression`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use postcard::to_allocvec;
+use rust_roveri_api::{ContentRequest, Request};
+use server::metrics::Metrics;
+use server::{compression, AssembledResponse, Fragmenter, RequestKind};
+use server::{SpecializedBehavior, TextBehavior};
+use std::fs;
+use wg_2024::network::NodeId;
+
+const CLIENT_ID: NodeId = 1;
+
+const WORDS: [&str; 16] = [
+    "drone", "packet", "route", "server", "client", "flood", "fragment", "network", "the", "a",
+    "is", "sends", "through", "every", "lost", "again",
+];
+
+/// Generates some text of the given size, made of words picked by a fixed pseudo-random
+/// sequence.
+fn text(size: usize) -> Vec<u8> {
+    let mut state: u32 = 12345;
+    let mut text = String::with_capacity(size + 16);
+
+    while text.len() < size {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        text.push_str(WORDS[(state >> 16) as usize % WORDS.len()]);
+        text.push(if state % 11 == 0 { '\n' } else { ' ' });
+    }
+    text.truncate(size);
+    text.into_bytes()
+}
+
+fn bench_compression(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join("server_compression_bench");
+    fs::create_dir_all(&dir).expect("Could not create the content directory");
+
+    let mut behavior = TextBehavior::new();
+    if behavior.set_path(dir.clone()).is_err() {
+        panic!("Could not set the content directory");
+    }
+
+    let mut group = c.benchmark_group("compression");
+
+    for size in [1024usize, 16 * 1024, 256 * 1024] {
+        let name = format!("{}.txt", size);
+        fs::write(dir.join(&name), text(size)).expect("Could not write the content");
+
+        let request = Request::Content(ContentRequest::Content(name));
+        let data = to_allocvec(&request).expect("Could not serialize the request");
+        let response = behavior.handle_assembled(data, CLIENT_ID, &mut Metrics::new());
+
+        let fragments = |data: Vec<u8>| {
+            Fragmenter::new()
+                .to_fragment_vec(AssembledResponse {
+                    data,
+                    dest: CLIENT_ID,
+                    kind: RequestKind::Content,
+                })
+                .len()
+        };
+        let encoded = compression::encode(&response.data, compression::DEFAULT_THRESHOLD);
+        println!(
+            "text/{}: {} fragments, {} compressed",
+            size,
+            fragments(response.data.clone()),
+            fragments(encoded.clone())
+        );
+
+        group.bench_with_input(BenchmarkId::new("encode", size), &response.data, |b, d| {
+            b.iter(|| compression::encode(black_box(d), compression::DEFAULT_THRESHOLD))
+        });
+        group.bench_with_input(BenchmarkId::new("decode", size), &encoded, |b, e| {
+            b.iter(|| compression::decode(black_box(e.clone())))
+        });
+    }
+
+    group.finish();
+    let _ = fs::remove_dir_all(&dir);
+}
+
+criterion_group!(benches, bench_compression);
+criterion_main!(benches);
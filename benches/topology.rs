@@ -0,0 +1,107 @@
+//! Compares the sparse `Topology` with the fixed-size `DenseTopology` on networks of
+//! increasing size.
+//!
+//! Run with `cargo bench --features bench --bench topology`.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use server::bench::{DenseTopology, Topology};
+use std::time::Instant;
+use wg_2024::{network::NodeId, packet::NodeType};
+
+const SERVER_ID: NodeId = 0;
+
+/// Builds the edge list of a ladder-shaped network: two rows of drones connected rung by rung,
+/// with the server on one end and a client on the other.
+fn ladder(drones: u8) -> Vec<((NodeId, NodeType), (NodeId, NodeType))> {
+    let half = drones / 2;
+    let client = (drones + 1, NodeType::Client);
+    let server = (SERVER_ID, NodeType::Server);
+    let top = |i: u8| (1 + i, NodeType::Drone);
+    let bottom = |i: u8| (1 + half + i, NodeType::Drone);
+
+    let mut edges = vec![(server, top(0)), (server, bottom(0))];
+    for i in 0..half {
+        edges.push((top(i), bottom(i)));
+        if i + 1 < half {
+            edges.push((top(i), top(i + 1)));
+            edges.push((bottom(i), bottom(i + 1)));
+        }
+    }
+    edges.push((top(half - 1), client));
+    edges.push((bottom(half - 1), client));
+    edges
+}
+
+fn build_sparse(edges: &[((NodeId, NodeType), (NodeId, NodeType))]) -> Topology {
+    let mut topology = Topology::new(SERVER_ID);
+    for (node1, node2) in edges {
+        topology.insert_edge(*node1, *node2);
+    }
+    topology
+}
+
+fn build_dense(edges: &[((NodeId, NodeType), (NodeId, NodeType))]) -> DenseTopology {
+    let mut topology = DenseTopology::new(SERVER_ID);
+    for (node1, node2) in edges {
+        topology.insert_edge(*node1, *node2);
+    }
+    topology
+}
+
+fn bench_topology(c: &mut Criterion) {
+    let mut group = c.benchmark_group("topology");
+
+    for drones in [4u8, 16, 64, 200] {
+        let edges = ladder(drones);
+        let client = drones + 1;
+
+        group.bench_with_input(BenchmarkId::new("build/sparse", drones), &edges, |b, e| {
+            b.iter(|| black_box(build_sparse(e)))
+        });
+        group.bench_with_input(BenchmarkId::new("build/dense", drones), &edges, |b, e| {
+            b.iter(|| black_box(build_dense(e)))
+        });
+
+        let sparse = build_sparse(&edges);
+        let dense = build_dense(&edges);
+
+        group.bench_function(BenchmarkId::new("bfs/sparse", drones), |b| {
+            b.iter(|| sparse.bfs(black_box(SERVER_ID), black_box(client)))
+        });
+        group.bench_function(BenchmarkId::new("bfs/dense", drones), |b| {
+            b.iter(|| dense.bfs(black_box(SERVER_ID), black_box(client)))
+        });
+        group.bench_function(BenchmarkId::new("dijkstra/sparse", drones), |b| {
+            b.iter(|| sparse.dijkstra(black_box(SERVER_ID), black_box(client)))
+        });
+        group.bench_function(BenchmarkId::new("dijkstra/dense", drones), |b| {
+            b.iter(|| dense.dijkstra(black_box(SERVER_ID), black_box(client)))
+        });
+        // Every iteration resets a populated topology, rebuilt outside the measurement
+        group.bench_function(BenchmarkId::new("reset/sparse", drones), |b| {
+            b.iter_batched(
+                || build_sparse(&edges),
+                |mut topology| {
+                    topology.reset(Instant::now());
+                    topology
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_function(BenchmarkId::new("reset/dense", drones), |b| {
+            b.iter_batched(
+                || build_dense(&edges),
+                |mut topology| {
+                    topology.reset(Instant::now());
+                    topology
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_topology);
+criterion_main!(benches);
@@ -0,0 +1,187 @@
+//! Implements the account management messages of chat clients.
+//!
+//! `ChatRequest` only covers registering, logging in and logging out, so the other account
+//! operations travel in the account envelope, see the `envelope` module, which holds a
+//! serialized `AccountRequest` or `AccountResponse`.
+//!
+//! A client sends `encode(&request)` where it would send a serialized `Request`, and reads
+//! responses, and the presence notifications other clients cause, with `decode`.
+//!
+//! Presence notifications are only sent to clients that can read them: those holding a session
+//! token, and those that sent an account request since they logged in.
+
+use crate::envelope;
+use postcard::{from_bytes, to_allocvec};
+use rust_roveri_api::{Password, UserName};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// First byte of an account message.
+const MARKER: u8 = envelope::ACCOUNT;
+
+/// Maximum length of a display name, in characters.
+pub const MAX_DISPLAY_NAME_LEN: usize = 32;
+
+/// The availability a client shows to the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+    Busy,
+}
+
+/// What a client shows to the others.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct Profile {
+    /// The name shown in place of the username, if any.
+    pub display_name: Option<String>,
+    pub status: Status,
+}
+
+impl Profile {
+    /// Checks whether the display name, if any, is not blank and not too long.
+    pub fn is_valid(&self) -> bool {
+        match &self.display_name {
+            Some(name) => !name.trim().is_empty() && name.chars().count() <= MAX_DISPLAY_NAME_LEN,
+            None => true,
+        }
+    }
+}
+
+/// Which users a client list includes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum ClientListScope {
+    /// Every registered user.
+    #[default]
+    Registered,
+    /// Only the logged in users.
+    Online,
+}
+
+/// An account operation of a logged in client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountRequest {
+    /// Changes the password, given the current one and the new one.
+    ChangePassword(UserName, Password, Password),
+    /// Deletes the account, given its password.
+    DeleteAccount(UserName, Password),
+    /// Replaces the profile.
+    SetProfile(UserName, Profile),
+    /// Asks for the profiles of the logged in clients.
+    Profiles(UserName),
+    /// Blocks the messages between the two users, and hides them from each other.
+    Block(UserName, UserName),
+    /// Lifts a block set with `Block`.
+    Unblock(UserName, UserName),
+    /// Hides the user from the client lists and the profiles the others receive, or shows it
+    /// again.
+    SetHidden(UserName, bool),
+    /// Asks for the users in the given scope.
+    ClientList(UserName, ClientListScope),
+}
+
+impl AccountRequest {
+    /// Returns the user the request is made on behalf of.
+    pub fn username(&self) -> &UserName {
+        match self {
+            AccountRequest::ChangePassword(username, ..)
+            | AccountRequest::DeleteAccount(username, _)
+            | AccountRequest::SetProfile(username, _)
+            | AccountRequest::Profiles(username)
+            | AccountRequest::Block(username, _)
+            | AccountRequest::Unblock(username, _)
+            | AccountRequest::SetHidden(username, _)
+            | AccountRequest::ClientList(username, _) => username,
+        }
+    }
+}
+
+/// Why an account operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountError {
+    NotLogged,
+    WrongPassword,
+    InvalidProfile,
+    /// The other user of the operation is not registered.
+    UnknownUser,
+}
+
+/// The answer to an `AccountRequest`, or a presence notification.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccountResponse {
+    PasswordChanged(UserName),
+    AccountDeleted(UserName),
+    ProfileSet(UserName),
+    Profiles(UserName, Vec<(UserName, Profile)>),
+    /// Sent to the logged in clients when another one logs in or changes its profile, with
+    /// `None` when it logs out or deletes its account.
+    Presence(UserName, Option<Profile>),
+    Failure(UserName, AccountError),
+    Blocked(UserName, UserName),
+    Unblocked(UserName, UserName),
+    HiddenSet(UserName, bool),
+    ClientList(UserName, Vec<UserName>),
+}
+
+/// Checks whether a payload is an account message.
+pub fn is_account(data: &[u8]) -> bool {
+    data.first() == Some(&MARKER)
+}
+
+/// Serializes an account message in its envelope.
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, postcard::Error> {
+    let mut encoded = vec![MARKER];
+    encoded.extend(to_allocvec(message)?);
+    Ok(encoded)
+}
+
+/// Deserializes an account message.
+///
+/// # Returns
+///
+/// - `Ok(Some(T))` with the message if the payload is an account message.
+/// - `Ok(None)` if it is not.
+/// - `Err(postcard::Error)` if the message is invalid.
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<Option<T>, postcard::Error> {
+    match data.split_first() {
+        Some((&MARKER, message)) => from_bytes(message).map(Some),
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_roveri_api::{ChatRequest, Request};
+
+    #[test]
+    fn test_roundtrip() {
+        let request = AccountRequest::SetProfile(
+            "ciao".to_string(),
+            Profile {
+                display_name: Some("Cane".to_string()),
+                status: Status::Away,
+            },
+        );
+        let encoded = encode(&request).unwrap();
+        assert!(is_account(&encoded));
+        assert_eq!(decode::<AccountRequest>(&encoded).unwrap(), Some(request));
+
+        let chat = to_allocvec(&Request::Chat(ChatRequest::Logout("ciao".to_string()))).unwrap();
+        assert!(!is_account(&chat));
+        assert_eq!(decode::<AccountRequest>(&chat).unwrap(), None);
+        assert!(decode::<AccountRequest>(&[MARKER, 200]).is_err());
+    }
+
+    #[test]
+    fn test_profile_validation() {
+        let profile = |name: &str| Profile {
+            display_name: Some(name.to_string()),
+            status: Status::Online,
+        };
+        assert!(Profile::default().is_valid());
+        assert!(profile("Cane").is_valid());
+        assert!(!profile("  ").is_valid());
+        assert!(!profile(&"a".repeat(MAX_DISPLAY_NAME_LEN + 1)).is_valid());
+    }
+}
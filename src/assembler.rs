@@ -1,23 +1,64 @@
 //! Provides the functionality to assemble fragments of data into a complete set (`Vec<u8>`).
 
+use crate::fec::{self, ParityIndex};
+use crate::integrity::{self, IntegrityError};
+use std::fmt;
+use std::time::{Duration, Instant};
 use wg_2024::packet::{Fragment, FRAGMENT_DSIZE};
 
 /// Represents the status of the assembler after a fragment is inserted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AssemblerStatus {
     Complete,
     Incomplete,
 }
 
 /// Errors that can occur while inserting a fragment into the assembler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum InsertFragmentError {
     CapacityDoesNotMatch,
     IndexOutOfBounds,
 }
 
+impl fmt::Display for InsertFragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InsertFragmentError::CapacityDoesNotMatch => {
+                write!(f, "the number of fragments does not match")
+            }
+            InsertFragmentError::IndexOutOfBounds => write!(f, "fragment index out of bounds"),
+        }
+    }
+}
+
+impl std::error::Error for InsertFragmentError {}
+
 /// Errors that can occur while retrieving assembled data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RetrieveError {
     Incomplete,
     UnknownSessionId,
+    /// The message does not match its integrity trailer.
+    Corrupted(IntegrityError),
+}
+
+impl fmt::Display for RetrieveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetrieveError::Incomplete => write!(f, "the message is incomplete"),
+            RetrieveError::UnknownSessionId => write!(f, "unknown session id"),
+            RetrieveError::Corrupted(err) => write!(f, "the message is corrupted: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for RetrieveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RetrieveError::Corrupted(err) => Some(err),
+            RetrieveError::Incomplete | RetrieveError::UnknownSessionId => None,
+        }
+    }
 }
 
 /// Responsible for assembling data fragments into a complete set.
@@ -25,19 +66,42 @@ pub enum RetrieveError {
 /// The `Assembler` maintains an internal buffer to store fragments and track the
 /// assembly process. Fragments are inserted one by one, and the assembler checks for
 /// completeness after each insertion.
+///
+/// Parity fragments are kept aside and used to rebuild a missing data fragment as soon as it is
+/// the only one missing in its group, see the `fec` module.
+#[derive(Debug)]
 pub struct Assembler {
-    data: Vec<Option<[u8; FRAGMENT_DSIZE]>>,
+    data: Vec<Option<([u8; FRAGMENT_DSIZE], usize)>>,
+    parity: Vec<(ParityIndex, [u8; FRAGMENT_DSIZE], u8)>,
     fragments_left: usize,
+    created: Instant,
+    integrity_required: bool,
 }
 
 impl Assembler {
+    /// Creates an assembler for a message split into `total_fragments` fragments.
     pub fn new(total_fragments: usize) -> Self {
         Self {
             data: vec![None; total_fragments],
+            parity: Vec::new(),
             fragments_left: total_fragments,
+            created: Instant::now(),
+            integrity_required: false,
         }
     }
 
+    /// Sets whether the message must end with an integrity trailer, see the `integrity`
+    /// module. Disabled by default, so that messages without one are accepted too.
+    pub fn set_integrity_required(&mut self, required: bool) {
+        self.integrity_required = required;
+    }
+
+    /// Returns the time elapsed since the assembler was created, i.e. since the first fragment
+    /// of the message was received.
+    pub fn elapsed(&self) -> Duration {
+        self.created.elapsed()
+    }
+
     /// Checks whether the assembly is complete.
     pub fn is_complete(&self) -> bool {
         self.fragments_left == 0
@@ -56,6 +120,9 @@ impl Assembler {
     /// - `Err(InsertFragmentError::CapacityDoesNotMatch)` if the total_n_fragments field does not match
     ///   the assembler's capacity.
     /// - `Err(InsertFragmentError::IndexOutOfBounds)` if the fragment's index is invalid.
+    ///
+    /// Parity fragments are accepted too: they complete the assembly if they let the assembler
+    /// rebuild the last missing data fragments.
     pub fn insert_fragment(
         &mut self,
         fragment: Fragment,
@@ -68,6 +135,15 @@ impl Assembler {
             return Err(InsertFragmentError::CapacityDoesNotMatch);
         }
 
+        if let Some(parity_index) = ParityIndex::decode(fragment.fragment_index) {
+            if !self.is_complete() {
+                self.parity
+                    .push((parity_index, fragment.data, fragment.length));
+                self.recover();
+            }
+            return Ok(self.status());
+        }
+
         //Check if index is in bounds
         if index >= self.data.len() {
             return Err(InsertFragmentError::IndexOutOfBounds);
@@ -78,13 +154,58 @@ impl Assembler {
             self.fragments_left -= 1;
         }
 
-        self.data[index] = Some(fragment.data);
+        let length = usize::min(fragment.length as usize, FRAGMENT_DSIZE);
+        self.data[index] = Some((fragment.data, length));
+        self.recover();
+
+        Ok(self.status())
+    }
 
+    fn status(&self) -> AssemblerStatus {
         if self.is_complete() {
-            Ok(AssemblerStatus::Complete)
+            AssemblerStatus::Complete
         } else {
-            Ok(AssemblerStatus::Incomplete)
+            AssemblerStatus::Incomplete
+        }
+    }
+
+    /// Rebuilds every data fragment that is the only one missing in the group of a parity
+    /// fragment, then drops the parity fragments that are no longer needed.
+    fn recover(&mut self) {
+        let total = self.data.len() as u64;
+
+        for (parity_index, parity_data, parity_length) in self.parity.iter() {
+            let mut missing = None;
+            let mut data = *parity_data;
+            let mut length = *parity_length;
+
+            for index in parity_index.covers(total) {
+                match &self.data[index as usize] {
+                    Some((fragment_data, fragment_length)) => {
+                        fec::xor(&mut data, fragment_data);
+                        length ^= *fragment_length as u8;
+                    }
+                    None if missing.is_none() => missing = Some(index as usize),
+                    None => {
+                        missing = None;
+                        break;
+                    }
+                }
+            }
+
+            if let Some(index) = missing {
+                let length = usize::min(length as usize, FRAGMENT_DSIZE);
+                self.data[index] = Some((data, length));
+                self.fragments_left -= 1;
+            }
         }
+
+        let data = &self.data;
+        self.parity.retain(|(parity_index, _, _)| {
+            parity_index
+                .covers(total)
+                .any(|index| data[index as usize].is_none())
+        });
     }
 
     /// Retrieves the assembled data as a single continuous byte vector.
@@ -93,22 +214,26 @@ impl Assembler {
     ///
     /// - `Ok(Vec<u8>)` if all fragments are present and the data is successfully assembled.
     /// - `Err(RetrieveError::Incomplete)` if one or more fragments are missing.
+    /// - `Err(RetrieveError::Corrupted)` if the data does not match its integrity trailer.
     ///
     /// # Behavior
     ///
     /// The function checks if all fragments are present. If any fragment is missing, it returns
-    /// an error. If all fragments are present, it concatenates them and
-    /// returns the resulting byte vector.
+    /// an error. If all fragments are present, it concatenates the first `length` bytes of each
+    /// of them. If the result ends with an integrity trailer, the trailer is verified and removed
+    /// before the resulting byte vector is returned. If a trailer is required, a result without
+    /// one is rejected.
     pub fn retrieve_assembled(self) -> Result<Vec<u8>, RetrieveError> {
         let mut assembled = Vec::with_capacity(self.data.len() * FRAGMENT_DSIZE);
 
         for fragment in self.data {
             match fragment {
-                Some(data) => assembled.extend_from_slice(&data),
+                Some((data, length)) => assembled.extend_from_slice(&data[..length]),
                 None => return Err(RetrieveError::Incomplete),
             }
         }
 
-        Ok(assembled)
+        integrity::strip_trailer(assembled, self.integrity_required)
+            .map_err(RetrieveError::Corrupted)
     }
 }
@@ -1,15 +1,17 @@
 //! Provides the functionality to manage multiple Assemblers.
 
 use crate::assembler::{Assembler, AssemblerStatus, InsertFragmentError, RetrieveError};
+use crate::fec;
 use rust_roveri_api::SessionId;
 use std::collections::{hash_map::Entry, HashMap};
+use std::time::Duration;
 use wg_2024::packet::Fragment;
 
 /// Manages the assembly of fragmented data for multiple sessions.
 ///
 /// # Overview
 ///
-/// The `Manager` is responsible for handling the lifecycle of fragmented data across multiple
+/// The `AssemblersManager` is responsible for handling the lifecycle of fragmented data across multiple
 /// sessions. It provides methods to:
 /// - Insert new fragments into the appropriate session.
 /// - Retrieve assembled data for a session once all fragments have been received.
@@ -17,17 +19,27 @@ use wg_2024::packet::Fragment;
 ///
 /// Each session is identified by a unique `SessionId`, and the manager internally uses
 /// an `assembly_buffer` (HashMap) to store the fragments for each session.
+#[derive(Debug, Default)]
 pub struct AssemblersManager {
     assembly_buffer: HashMap<SessionId, Assembler>,
+    integrity_required: bool,
 }
 
 impl AssemblersManager {
+    /// Creates a manager with no session being assembled.
     pub fn new() -> Self {
         Self {
             assembly_buffer: HashMap::new(),
+            integrity_required: false,
         }
     }
 
+    /// Sets whether the messages of the sessions started from now on must end with an
+    /// integrity trailer, see `Assembler::set_integrity_required`.
+    pub fn set_integrity_required(&mut self, required: bool) {
+        self.integrity_required = required;
+    }
+
     /// Inserts a fragment into the assembly buffer for a given session.
     ///
     /// # Arguments
@@ -51,6 +63,9 @@ impl AssemblersManager {
     /// provided `fragment`. Then, the fragment is inserted into the corresponding assembler for the
     /// session.
     ///
+    /// Parity fragments never create a session: they may arrive after the message they protect
+    /// has been completed and retrieved, and are then ignored with `AssemblerStatus::Incomplete`.
+    ///
     /// If the insertion is successful, the function returns the updated status of the assembler. If
     /// there are errors, they are propagated as `InsertFragmentError`.
     pub fn insert_fragment(
@@ -60,11 +75,20 @@ impl AssemblersManager {
     ) -> Result<AssemblerStatus, InsertFragmentError> {
         let total_fragments = fragment.total_n_fragments as usize;
 
+        if fec::is_parity(fragment.fragment_index) {
+            return match self.assembly_buffer.get_mut(&session_id) {
+                Some(assembler) => assembler.insert_fragment(fragment),
+                None => Ok(AssemblerStatus::Incomplete),
+            };
+        }
+
         //Get the entry or create a new entry
-        let entry = self
-            .assembly_buffer
-            .entry(session_id)
-            .or_insert_with(|| Assembler::new(total_fragments));
+        let integrity_required = self.integrity_required;
+        let entry = self.assembly_buffer.entry(session_id).or_insert_with(|| {
+            let mut assembler = Assembler::new(total_fragments);
+            assembler.set_integrity_required(integrity_required);
+            assembler
+        });
 
         entry.insert_fragment(fragment)
     }
@@ -80,6 +104,8 @@ impl AssemblersManager {
     /// - `Ok(Vec<u8>)` if the data for the session is complete and successfully retrieved.
     /// - `Err(RetrieveError::Incomplete)` if the data is incomplete and cannot be retrieved yet.
     /// - `Err(RetrieveError::UnknownSessionId)` if the given session ID does not exist.
+    /// - `Err(RetrieveError::Corrupted)` if the data does not match its integrity trailer. The
+    ///   session is removed, so the message can be received again from scratch.
     ///
     /// # Behavior
     ///
@@ -99,4 +125,21 @@ impl AssemblersManager {
             Entry::Vacant(_) => Err(RetrieveError::UnknownSessionId),
         }
     }
+
+    /// Returns the time elapsed since the first fragment of a session was received.
+    ///
+    /// # Returns
+    ///
+    /// - `Some(Duration)` if the session is in the buffer.
+    /// - `None` if the session ID is unknown.
+    pub fn elapsed(&self, session_id: SessionId) -> Option<Duration> {
+        self.assembly_buffer
+            .get(&session_id)
+            .map(|assembler| assembler.elapsed())
+    }
+
+    /// Returns the number of sessions whose assembly is still in progress.
+    pub fn pending_sessions(&self) -> usize {
+        self.assembly_buffer.len()
+    }
 }
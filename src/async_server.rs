@@ -0,0 +1,248 @@
+//! Implements `AsyncServer`, a tokio front-end for `Server`.
+//!
+//! The wrapper owns a regular `Server` and forwards the messages it receives on tokio channels
+//! to it, so packet handling, routing and behaviors are shared with the blocking version. The
+//! server is driven through `Server::step`, sleeping on a tokio timer when it asks to be woken
+//! at a given time.
+//!
+//! There is no retransmission timer to drive: like the blocking version, the server only
+//! retransmits a fragment when a Nack asks for it or the neighbor it was sent through is
+//! removed, so the only timer it asks for is the graceful stop deadline. Adding timed
+//! retransmissions would change the protocol of both versions and is out of scope here.
+
+use crate::control::{ControlCommand, ControlEvent};
+use crate::server::{Server, Wake};
+use crossbeam_channel::{unbounded, Sender};
+use rust_roveri_api::{ServerCommand, ServerEvent, ServerType};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{sleep_until, Instant};
+use wg_2024::{network::NodeId, packet::Packet};
+
+pub struct AsyncServer {
+    server: Server,
+    command_recv: UnboundedReceiver<ServerCommand>,
+    packet_recv: UnboundedReceiver<Packet>,
+    control_recv: Option<UnboundedReceiver<ControlCommand>>,
+    command_forward: Sender<ServerCommand>,
+    packet_forward: Sender<Packet>,
+    control_forward: Sender<ControlCommand>,
+}
+
+impl AsyncServer {
+    /// Creates a server reading commands and packets from tokio channels.
+    ///
+    /// Events and outbound packets are still sent on the crossbeam senders of the network
+    /// API, which never block.
+    pub fn new(
+        id: NodeId,
+        command_recv: UnboundedReceiver<ServerCommand>,
+        packet_recv: UnboundedReceiver<Packet>,
+        controller_send: Sender<ServerEvent>,
+        server_type: ServerType,
+    ) -> Self {
+        let (command_forward, server_command_recv) = unbounded();
+        let (packet_forward, server_packet_recv) = unbounded();
+
+        Self {
+            server: Server::new(
+                id,
+                server_command_recv,
+                server_packet_recv,
+                controller_send,
+                server_type,
+            ),
+            command_recv,
+            packet_recv,
+            control_recv: None,
+            command_forward,
+            packet_forward,
+            control_forward: unbounded().0,
+        }
+    }
+
+    /// Installs the channels of the server-specific controller interface.
+    ///
+    /// See `Server::set_control_channels`.
+    pub fn set_control_channels(
+        &mut self,
+        control_recv: UnboundedReceiver<ControlCommand>,
+        control_send: Sender<ControlEvent>,
+    ) {
+        let (control_forward, server_control_recv) = unbounded();
+        self.server
+            .set_control_channels(server_control_recv, control_send);
+        self.control_forward = control_forward;
+        self.control_recv = Some(control_recv);
+    }
+
+    /// Gives access to the wrapped server, e.g. to enable capture before running it.
+    pub fn server_mut(&mut self) -> &mut Server {
+        &mut self.server
+    }
+
+    /// Runs the server until it terminates or every input channel is closed.
+    ///
+    /// The messages waiting on the tokio channels are forwarded before every step, so a server
+    /// that keeps asking to be stepped right away, e.g. while it has fragments to send, still
+    /// receives its inputs.
+    pub async fn run(mut self) {
+        loop {
+            self.forward_pending();
+            let outcome = self.server.step();
+            if outcome.terminated {
+                break;
+            }
+
+            let deadline = match outcome.wake {
+                Wake::Immediately => {
+                    tokio::task::yield_now().await;
+                    continue;
+                }
+                Wake::At(deadline) => Some(Instant::from_std(deadline)),
+                Wake::OnInput => None,
+            };
+
+            let control_recv = self.control_recv.as_mut();
+            let has_control = control_recv.is_some();
+
+            tokio::select! {
+                biased;
+
+                Some(command) = self.command_recv.recv() => {
+                    let _ = self.command_forward.send(command);
+                }
+                Some(command) = async { control_recv?.recv().await }, if has_control => {
+                    let _ = self.control_forward.send(command);
+                }
+                Some(packet) = self.packet_recv.recv() => {
+                    let _ = self.packet_forward.send(packet);
+                }
+                _ = async { sleep_until(deadline?).await; Some(()) }, if deadline.is_some() => {}
+                else => break,
+            }
+        }
+    }
+
+    /// Forwards to the server every message already waiting on the tokio channels.
+    fn forward_pending(&mut self) {
+        while let Ok(command) = self.command_recv.try_recv() {
+            let _ = self.command_forward.send(command);
+        }
+        if let Some(control_recv) = self.control_recv.as_mut() {
+            while let Ok(command) = control_recv.try_recv() {
+                let _ = self.control_forward.send(command);
+            }
+        }
+        while let Ok(packet) = self.packet_recv.try_recv() {
+            let _ = self.packet_forward.send(packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::AssemblerStatus;
+    use crate::assemblers_manager::AssemblersManager;
+    use crate::fragmenter::Fragmenter;
+    use crate::specialized_behavior::{AssembledResponse, RequestKind};
+    use crate::ShutdownSummary;
+    use postcard::{from_bytes, to_allocvec};
+    use rust_roveri_api::{ChatRequest, ChatResponse, Request, Response};
+    use std::time::Duration;
+    use tokio::sync::mpsc::unbounded_channel;
+    use wg_2024::network::SourceRoutingHeader;
+    use wg_2024::packet::PacketType;
+
+    const CLIENT_ID: NodeId = 80;
+    const DRONE_1_ID: NodeId = 81;
+    const SERVER_ID: NodeId = 82;
+
+    /// Polls a crossbeam receiver without blocking the runtime.
+    async fn recv_async<T>(receiver: &crossbeam_channel::Receiver<T>) -> T {
+        let wait = async {
+            loop {
+                if let Ok(value) = receiver.try_recv() {
+                    return value;
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), wait)
+            .await
+            .expect("Nothing received")
+    }
+
+    #[tokio::test]
+    async fn test_async_request() {
+        let (command_tx, command_rx) = unbounded_channel();
+        let (packet_tx, packet_rx) = unbounded_channel();
+        let (control_tx, control_rx) = unbounded_channel();
+        let (controller_send, _controller_recv) = unbounded();
+        let (event_tx, event_rx) = unbounded();
+        let (drone_tx, drone_rx) = unbounded();
+
+        let mut server = AsyncServer::new(
+            SERVER_ID,
+            command_rx,
+            packet_rx,
+            controller_send,
+            ServerType::Chat,
+        );
+        server.set_control_channels(control_rx, event_tx);
+        let handle = tokio::spawn(server.run());
+
+        let _ = command_tx.send(ServerCommand::AddDrone(DRONE_1_ID, drone_tx));
+
+        let request = Request::Chat(ChatRequest::Register(
+            "ciao".to_string(),
+            "cane".to_string(),
+        ));
+        let fragments = Fragmenter::new().to_fragment_vec(AssembledResponse {
+            data: to_allocvec(&request).expect("Could not serialize the request"),
+            dest: SERVER_ID,
+            kind: RequestKind::from(&request),
+        });
+        for to_be_sent in fragments {
+            let _ = packet_tx.send(Packet {
+                routing_header: SourceRoutingHeader {
+                    hop_index: 2,
+                    hops: vec![CLIENT_ID, DRONE_1_ID, SERVER_ID],
+                },
+                session_id: 1,
+                pack_type: PacketType::MsgFragment(to_be_sent.fragment),
+            });
+        }
+
+        let packet = recv_async(&drone_rx).await;
+        let fragment = match packet.pack_type {
+            PacketType::MsgFragment(fragment) => fragment,
+            other => panic!("Expected a MsgFragment, got {:?}", other),
+        };
+        let mut assemblers_manager = AssemblersManager::new();
+        assert!(matches!(
+            assemblers_manager.insert_fragment(fragment, packet.session_id),
+            Ok(AssemblerStatus::Complete)
+        ));
+        let data = match assemblers_manager.retrieve_assembled(packet.session_id) {
+            Ok(data) => data,
+            Err(_) => panic!("Could not retrieve the assembled response"),
+        };
+        assert!(matches!(
+            from_bytes::<Response>(&data),
+            Ok(Response::Chat(ChatResponse::ClientList(..)))
+        ));
+
+        // The response is never acknowledged, so the stop ends on the timer
+        let _ = control_tx.send(ControlCommand::GracefulStop(Duration::from_millis(50)));
+        match recv_async(&event_rx).await {
+            ControlEvent::ShutdownSummary(ShutdownSummary { drained, .. }) => assert!(!drained),
+            other => panic!("Expected a ShutdownSummary, got {:?}", other),
+        }
+
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("Server did not terminate")
+            .expect("Server panicked");
+    }
+}
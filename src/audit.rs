@@ -0,0 +1,132 @@
+//! Keeps an audit log of the authentication events of a chat server.
+//!
+//! The most recent records are kept in memory and every record is logged with the `audit` log
+//! target. If a file is set, records are appended to it too, one per line.
+
+use log::{error, info};
+use rust_roveri_api::UserName;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use wg_2024::network::NodeId;
+
+/// Number of records kept in memory.
+const MAX_RECORDS: usize = 1024;
+
+/// An authentication event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthEvent {
+    Registered,
+    AlreadyRegistered,
+    LoggedIn,
+    LoggedOut,
+    WrongPassword,
+    NotRegistered,
+    AlreadyLogged,
+    PasswordChanged,
+    AccountDeleted,
+    /// Failed attempts locked the username or the node out for the given duration.
+    LockedOut(Duration),
+    /// The attempt was rejected without checking the password, because of a lockout that
+    /// expires after the given duration.
+    Rejected(Duration),
+}
+
+impl fmt::Display for AuthEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthEvent::Registered => write!(f, "registered"),
+            AuthEvent::AlreadyRegistered => write!(f, "register failed: already registered"),
+            AuthEvent::LoggedIn => write!(f, "logged in"),
+            AuthEvent::LoggedOut => write!(f, "logged out"),
+            AuthEvent::WrongPassword => write!(f, "login failed: wrong password"),
+            AuthEvent::NotRegistered => write!(f, "login failed: not registered"),
+            AuthEvent::AlreadyLogged => write!(f, "login failed: already logged"),
+            AuthEvent::PasswordChanged => write!(f, "password changed"),
+            AuthEvent::AccountDeleted => write!(f, "account deleted"),
+            AuthEvent::LockedOut(duration) => {
+                write!(f, "locked out for {}s", duration.as_secs_f64())
+            }
+            AuthEvent::Rejected(remaining) => {
+                write!(
+                    f,
+                    "login rejected: locked out for {}s",
+                    remaining.as_secs_f64()
+                )
+            }
+        }
+    }
+}
+
+/// An entry of the audit log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub time: SystemTime,
+    pub node_id: NodeId,
+    pub username: UserName,
+    pub event: AuthEvent,
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let timestamp = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        write!(
+            f,
+            "{} node {} user {:?} {}",
+            timestamp, self.node_id, self.username, self.event
+        )
+    }
+}
+
+/// The audit log of a chat server.
+#[derive(Debug, Default)]
+pub(crate) struct AuditLog {
+    records: VecDeque<AuditRecord>,
+    file: Option<PathBuf>,
+}
+
+impl AuditLog {
+    /// Sets the file records are appended to from now on.
+    pub(crate) fn set_file(&mut self, file: PathBuf) {
+        self.file = Some(file);
+    }
+
+    /// Adds a record to the log.
+    pub(crate) fn record(&mut self, node_id: NodeId, username: &UserName, event: AuthEvent) {
+        let record = AuditRecord {
+            time: SystemTime::now(),
+            node_id,
+            username: username.clone(),
+            event,
+        };
+        info!(target: "audit", "{}", record);
+
+        if let Some(file) = &self.file {
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .and_then(|mut file| writeln!(file, "{}", record));
+            if let Err(err) = result {
+                error!("Could not write the audit log to {:?}: {}", file, err);
+            }
+        }
+
+        if self.records.len() == MAX_RECORDS {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Returns the most recent records, oldest first.
+    pub(crate) fn records(&self) -> impl Iterator<Item = &AuditRecord> {
+        self.records.iter()
+    }
+}
@@ -0,0 +1,173 @@
+//! Runs a single server described by a TOML configuration file.
+//!
+//! The server talks to the nodes of the other processes over sockets: it listens for packets
+//! on its own endpoint and connects to the endpoint of every neighbor. Endpoints are written
+//! as `host:port` for TCP and `unix:<path>` for Unix domain sockets.
+//!
+//! ```toml
+//! id = 30
+//! server_type = "chat"
+//! listen = "127.0.0.1:7030"
+//! storage_dir = "/var/lib/roveri/30"
+//!
+//! [[neighbors]]
+//! id = 11
+//! address = "127.0.0.1:7011"
+//! ```
+//!
+//! Content servers take a `content_path` instead of `storage_dir`. Writing `stop` on the
+//! standard input stops the server gracefully.
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use log::{error, info};
+use rust_roveri_api::{ServerCommand, ServerEvent, ServerType};
+use serde::Deserialize;
+use server::{ControlCommand, ControlEvent, Endpoint, PacketListener, Server, SocketTransport};
+use std::io::{self, BufRead};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+use std::{env, fs};
+use wg_2024::network::NodeId;
+
+/// How long a graceful stop waits for outstanding fragments.
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ConfigServerType {
+    Chat,
+    ContentText,
+    ContentMedia,
+}
+
+impl From<ConfigServerType> for ServerType {
+    fn from(server_type: ConfigServerType) -> Self {
+        match server_type {
+            ConfigServerType::Chat => ServerType::Chat,
+            ConfigServerType::ContentText => ServerType::ContentText,
+            ConfigServerType::ContentMedia => ServerType::ContentMedia,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NeighborConfig {
+    id: NodeId,
+    address: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    id: NodeId,
+    server_type: ConfigServerType,
+    listen: String,
+    content_path: Option<PathBuf>,
+    storage_dir: Option<PathBuf>,
+    #[serde(default)]
+    neighbors: Vec<NeighborConfig>,
+}
+
+fn read_config(path: &str) -> Result<Config, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path, err))?;
+    toml::from_str(&text).map_err(|err| format!("Invalid config {}: {}", path, err))
+}
+
+/// Stops the server gracefully when `stop` is written on the standard input.
+fn read_stdin(control_send: Sender<ControlCommand>) {
+    for line in io::stdin().lock().lines() {
+        match line {
+            Ok(line) if line.trim() == "stop" => {
+                let _ = control_send.send(ControlCommand::GracefulStop(STOP_TIMEOUT));
+                return;
+            }
+            Ok(_) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+fn log_events(event_recv: Receiver<ServerEvent>, control_event_recv: Receiver<ControlEvent>) {
+    loop {
+        crossbeam_channel::select! {
+            recv(event_recv) -> event => match event {
+                Ok(ServerEvent::PacketSent(_)) => {}
+                Ok(event) => info!("{:?}", event),
+                Err(_) => return,
+            },
+            recv(control_event_recv) -> event => match event {
+                Ok(event) => info!("{:?}", event),
+                Err(_) => return,
+            },
+        }
+    }
+}
+
+fn run(config: Config) -> Result<(), String> {
+    let (command_send, command_recv) = unbounded();
+    let (packet_send, packet_recv) = unbounded();
+    let (event_send, event_recv) = unbounded();
+    let (control_send, control_recv) = unbounded();
+    let (control_event_send, control_event_recv) = unbounded();
+
+    let mut server = Server::new(
+        config.id,
+        command_recv,
+        packet_recv,
+        event_send,
+        config.server_type.into(),
+    );
+    server.set_control_channels(control_recv, control_event_send);
+
+    if let Some(dir) = config.storage_dir {
+        server
+            .set_storage_dir(dir.clone())
+            .map_err(|err| format!("Cannot use storage dir {:?}: {}", dir, err))?;
+    }
+    if let Some(path) = config.content_path {
+        let _ = command_send.send(ServerCommand::SetMediaPath(path));
+    }
+
+    let listen: Endpoint = config.listen.parse()?;
+    let listener = PacketListener::bind(&listen)
+        .map_err(|err| format!("Cannot listen on {}: {}", listen, err))?;
+    listener.spawn(packet_send);
+
+    for neighbor in config.neighbors {
+        let endpoint: Endpoint = neighbor.address.parse()?;
+        let _ = control_send.send(ControlCommand::AddNeighbor(
+            neighbor.id,
+            Box::new(SocketTransport::connect(endpoint)),
+        ));
+    }
+
+    thread::spawn(move || log_events(event_recv, control_event_recv));
+    thread::spawn(move || read_stdin(control_send));
+
+    info!("Server {} listening on {}", config.id, listen);
+    server.run();
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("Usage: roveri-server <config.toml>");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match read_config(&path).and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            error!("{}", err);
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,328 @@
+//! Implements packet capture to a file and replay of a capture into a fresh `Server`.
+//!
+//! A capture is a sequence of frames, each made of the length of a postcard-serialized
+//! `CaptureRecord` as a little-endian `u32` followed by the record itself. Like the frames of
+//! the `transport` module, frames are at most `MAX_FRAME_LEN` bytes long.
+
+use crate::server::Server;
+use crate::transport::{frame_too_long, MAX_FRAME_LEN};
+use crossbeam_channel::unbounded;
+use rust_roveri_api::{ServerCommand, ServerEvent, ServerType};
+use serde::{Deserialize, Serialize};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use wg_2024::{
+    network::NodeId,
+    packet::{Packet, PacketType},
+};
+
+/// Whether a captured packet was received or sent by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A captured packet.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CaptureRecord {
+    /// Microseconds since the Unix epoch at which the packet was captured.
+    pub timestamp_micros: u64,
+    pub direction: Direction,
+    pub packet: Packet,
+}
+
+/// Writes every packet it is given to a capture file.
+///
+/// Records are buffered: they reach the file when `flush` is called or the recorder is
+/// dropped.
+pub struct Recorder {
+    writer: RefCell<BufWriter<File>>,
+}
+
+impl Recorder {
+    /// Creates a recorder writing to a new capture file, truncating any existing one.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: RefCell::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    /// Appends a packet to the capture, timestamped with the current time.
+    pub fn record(&self, direction: Direction, packet: &Packet) -> io::Result<()> {
+        let timestamp_micros = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_micros() as u64)
+            .unwrap_or(0);
+
+        let record = CaptureRecord {
+            timestamp_micros,
+            direction,
+            packet: packet.clone(),
+        };
+        let bytes = postcard::to_allocvec(&record)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if bytes.len() > MAX_FRAME_LEN {
+            return Err(frame_too_long(bytes.len()));
+        }
+
+        let mut writer = self.writer.borrow_mut();
+        writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        writer.write_all(&bytes)
+    }
+
+    /// Writes the buffered records to the capture file.
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.borrow_mut().flush()
+    }
+}
+
+/// Reads every record of a capture file.
+pub fn read_capture(path: &Path) -> io::Result<Vec<CaptureRecord>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = Vec::new();
+
+    loop {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+
+        let len = u32::from_le_bytes(len) as usize;
+        if len > MAX_FRAME_LEN {
+            return Err(frame_too_long(len));
+        }
+        let mut bytes = vec![0u8; len];
+        reader.read_exact(&mut bytes)?;
+
+        let record = postcard::from_bytes(&bytes)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Parameters of a replay.
+pub struct ReplayOptions {
+    pub server_id: NodeId,
+    pub server_type: ServerType,
+    /// Content path of the replayed server, if it is a content server.
+    pub media_path: Option<PathBuf>,
+    /// Upper bound of the pause between two inbound packets, which otherwise reproduces the
+    /// one observed in the capture.
+    pub max_gap: Duration,
+    /// How long the server must stay silent after the last inbound packet for the replay to
+    /// be considered over.
+    pub quiet_period: Duration,
+}
+
+/// Outcome of a replay.
+#[derive(Debug)]
+pub struct ReplayReport {
+    /// Packets the server sent in the capture.
+    pub expected: Vec<Packet>,
+    /// Packets the server sent during the replay.
+    pub actual: Vec<Packet>,
+    /// Captured packets the server did not send during the replay.
+    pub missing: Vec<Packet>,
+    /// Packets the server sent during the replay that are not in the capture.
+    pub unexpected: Vec<Packet>,
+}
+
+impl ReplayReport {
+    /// Checks whether the replay produced exactly the captured packets, in any order.
+    pub fn is_match(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
+}
+
+/// Returns the neighbor a captured packet came from or went to, if it can be told.
+fn neighbor_of(record: &CaptureRecord) -> Option<NodeId> {
+    let header = &record.packet.routing_header;
+
+    match (record.direction, &record.packet.pack_type) {
+        (Direction::Inbound, PacketType::FloodRequest(flood_req)) => {
+            flood_req.path_trace.last().map(|(id, _)| *id)
+        }
+        (Direction::Inbound, _) => header
+            .hop_index
+            .checked_sub(1)
+            .and_then(|index| header.hops.get(index))
+            .copied(),
+        (Direction::Outbound, _) => header.hops.get(header.hop_index).copied(),
+    }
+}
+
+/// Feeds the inbound packets of a capture into a fresh `Server` and compares what it sends
+/// with the outbound packets of the capture.
+///
+/// Every neighbor seen in the capture is added to the server before the first packet is
+/// delivered, and inbound packets are delivered in the captured order through the server's
+/// packet channel.
+pub fn replay(records: &[CaptureRecord], options: ReplayOptions) -> ReplayReport {
+    let (packet_recv_tx, packet_recv_rx) = unbounded::<Packet>();
+    let (command_recv_tx, command_recv_rx) = unbounded::<ServerCommand>();
+    let (event_send_tx, _event_send_rx) = unbounded::<ServerEvent>();
+    let (neighbor_tx, neighbor_rx) = unbounded::<Packet>();
+
+    let mut server = Server::new(
+        options.server_id,
+        command_recv_rx,
+        packet_recv_rx,
+        event_send_tx,
+        options.server_type,
+    );
+    let server_handle = thread::spawn(move || server.run());
+
+    let neighbors: BTreeSet<NodeId> = records.iter().filter_map(neighbor_of).collect();
+    for neighbor in neighbors {
+        let _ = command_recv_tx.send(ServerCommand::AddDrone(neighbor, neighbor_tx.clone()));
+    }
+    if let Some(path) = options.media_path {
+        let _ = command_recv_tx.send(ServerCommand::SetMediaPath(path));
+    }
+
+    let mut last_timestamp = None;
+    for record in records
+        .iter()
+        .filter(|record| record.direction == Direction::Inbound)
+    {
+        if let Some(last) = last_timestamp {
+            let gap = Duration::from_micros(record.timestamp_micros.saturating_sub(last));
+            thread::sleep(gap.min(options.max_gap));
+        }
+        last_timestamp = Some(record.timestamp_micros);
+
+        let _ = packet_recv_tx.send(record.packet.clone());
+    }
+
+    let mut actual = Vec::new();
+    while let Ok(packet) = neighbor_rx.recv_timeout(options.quiet_period) {
+        actual.push(packet);
+    }
+
+    let _ = command_recv_tx.send(ServerCommand::Crash);
+    let _ = server_handle.join();
+
+    let expected: Vec<Packet> = records
+        .iter()
+        .filter(|record| record.direction == Direction::Outbound)
+        .map(|record| record.packet.clone())
+        .collect();
+
+    let (missing, unexpected) = difference(&expected, &actual);
+
+    ReplayReport {
+        expected,
+        actual,
+        missing,
+        unexpected,
+    }
+}
+
+/// Computes the multiset differences `expected - actual` and `actual - expected`.
+///
+/// Packets are compared through their serialized form.
+fn difference(expected: &[Packet], actual: &[Packet]) -> (Vec<Packet>, Vec<Packet>) {
+    let key = |packet: &Packet| postcard::to_allocvec(packet).unwrap_or_default();
+
+    let mut remaining: HashMap<Vec<u8>, usize> = HashMap::new();
+    for packet in expected {
+        *remaining.entry(key(packet)).or_insert(0) += 1;
+    }
+
+    let mut unexpected = Vec::new();
+    for packet in actual {
+        match remaining.get_mut(&key(packet)) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => unexpected.push(packet.clone()),
+        }
+    }
+
+    let mut missing = Vec::new();
+    for packet in expected {
+        if let Some(count) = remaining.get_mut(&key(packet)) {
+            if *count > 0 {
+                *count -= 1;
+                missing.push(packet.clone());
+            }
+        }
+    }
+
+    (missing, unexpected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wg_2024::network::SourceRoutingHeader;
+    use wg_2024::packet::Ack;
+
+    fn ack(fragment_index: u64) -> Packet {
+        Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: vec![1, 2],
+            },
+            session_id: 7,
+            pack_type: PacketType::Ack(Ack { fragment_index }),
+        }
+    }
+
+    #[test]
+    fn test_record_and_read() {
+        let path = std::env::temp_dir().join(format!(
+            "server_capture_roundtrip_{}.cap",
+            std::process::id()
+        ));
+
+        let recorder = Recorder::create(&path).expect("Could not create capture");
+        recorder
+            .record(Direction::Inbound, &ack(0))
+            .expect("Could not record packet");
+        recorder
+            .record(Direction::Outbound, &ack(1))
+            .expect("Could not record packet");
+        drop(recorder);
+
+        let records = read_capture(&path).expect("Could not read capture");
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].direction, Direction::Inbound);
+        assert_eq!(records[1].direction, Direction::Outbound);
+        assert!(records[0].timestamp_micros <= records[1].timestamp_micros);
+        assert!(matches!(
+            records[1].packet.pack_type,
+            PacketType::Ack(Ack { fragment_index: 1 })
+        ));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_difference() {
+        let expected = vec![ack(0), ack(1), ack(1)];
+        let actual = vec![ack(1), ack(2), ack(0)];
+
+        let (missing, unexpected) = difference(&expected, &actual);
+        assert_eq!(missing.len(), 1);
+        assert!(matches!(
+            missing[0].pack_type,
+            PacketType::Ack(Ack { fragment_index: 1 })
+        ));
+        assert_eq!(unexpected.len(), 1);
+        assert!(matches!(
+            unexpected[0].pack_type,
+            PacketType::Ack(Ack { fragment_index: 2 })
+        ));
+    }
+}
@@ -1,44 +1,212 @@
 //! Implements the `ChatBehavior` struct for managing chat clients and handling requests.
 
-use crate::specialized_behavior::{ProcessError, SpecializedBehavior};
+use crate::account::{
+    self, AccountError, AccountRequest, AccountResponse, ClientListScope, Profile,
+};
+use crate::audit::{AuditLog, AuditRecord, AuthEvent};
+use crate::clock::{Clock, SystemClock};
+use crate::history::{self, History, HistoryError, HistoryRequest, HistoryResponse, MAX_PAGE_LEN};
+use crate::login_guard::{LoginGuard, LoginPolicy};
+use crate::metrics::{self, MetricKey, Metrics};
+use crate::password::PasswordHash;
+use crate::specialized_behavior::{
+    AssembledResponse, ProcessError, RequestKind, SetPathError, SpecializedBehavior,
+};
+use crate::token::{Presented, SessionToken};
+use log::error;
+use postcard::{from_bytes, to_allocvec};
 use rust_roveri_api::{
     ChatRequest, ChatResponse, ClientListError, LoginError, LogoutError, MessageError, Password,
     RegisterError, Request, Response, UserName,
 };
-use std::collections::{hash_map::Entry, HashMap};
+use serde::{Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use wg_2024::network::NodeId;
 
 type Logged = bool;
 
-pub struct ChatBehavior {
-    clients: HashMap<UserName, (Password, NodeId, Logged)>,
+/// Name of the file, inside the storage directory, where registered accounts are kept.
+const ACCOUNTS_FILE: &str = "accounts.bin";
+
+/// Name of the file, inside the storage directory, where the audit log is appended.
+const AUDIT_FILE: &str = "audit.log";
+
+/// Name of the file, inside the storage directory, where the privacy settings are kept.
+const PRIVACY_FILE: &str = "privacy.bin";
+
+/// Name of the file, inside the storage directory, where the message history is kept.
+const HISTORY_FILE: &str = "history.bin";
+
+/// How long a session token is valid, unless set otherwise.
+const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The privacy settings of a user.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Privacy {
+    /// Whether the user is left out of the client lists of the others.
+    hidden: bool,
+    /// The users it exchanges no message with.
+    blocked: HashSet<UserName>,
 }
 
 /// Handles chat client management and network request processing.
 ///
 /// The `ChatBehavior` struct keeps track of registered clients, their authentication states,
 /// and their associated network identifiers.
+///
+/// Repeated failed logins lock the username or the node out, see `LoginPolicy`, and every
+/// authentication event is recorded in an audit log.
+///
+/// Clients that support session tokens, see the `token` module, are issued one when they log
+/// in or register, and must present it with every following request until they log out or it
+/// expires. The `NodeId` the requests come from must still match the one of the session.
+/// Clients that do not support them are accepted as a compatibility mode, with sessions only
+/// bound to their `NodeId`, unless tokens are required with `set_tokens_required`.
+///
+/// Logged in clients can also change their password, delete their account and set a profile,
+/// see the `account` module. Logins, logouts, profile changes and deletions are notified to the
+/// other logged in clients that can read presence notifications.
+///
+/// Passwords are only kept as salted hashes, see the `password` module. If a storage directory
+/// is set, the accounts are saved to it whenever one is registered or changes its password, and
+/// the whole state whenever one is deleted.
+///
+/// Delivered messages are kept in a bounded history of every conversation, which the two users
+/// can read and search, see the `history` module. It is persisted with the accounts.
+///
+/// Users can block each other and hide from the client lists. Blocked users cannot exchange
+/// messages and do not see each other, and `ClientListScope` sets whether the client lists
+/// include every registered user or only the logged in ones.
+#[derive(Debug)]
+pub struct ChatBehavior {
+    clients: HashMap<UserName, (PasswordHash, NodeId, Logged)>,
+    storage_dir: Option<PathBuf>,
+    login_guard: LoginGuard,
+    audit_log: AuditLog,
+    tokens: HashMap<UserName, (SessionToken, Instant)>,
+    token_ttl: Duration,
+    /// Whether requests outside a token envelope are rejected.
+    tokens_required: bool,
+    /// How the request being processed presented its token.
+    presented: Presented,
+    /// The token issued by the request being processed, if any.
+    issued: Option<SessionToken>,
+    profiles: HashMap<UserName, Profile>,
+    /// Presence notifications waiting to be sent.
+    notifications: Vec<AssembledResponse>,
+    /// Users that sent an account request since they logged in, and so read presence
+    /// notifications.
+    presence_readers: HashSet<UserName>,
+    history: History,
+    privacy: HashMap<UserName, Privacy>,
+    /// Which users `ChatRequest::ClientList` returns.
+    client_list_scope: ClientListScope,
+    /// Measures the lockouts and the expiry of the session tokens.
+    clock: Arc<dyn Clock>,
+}
+
+impl Default for ChatBehavior {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ChatBehavior {
+    /// Creates a chat behavior with no registered client and no persistent storage.
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            storage_dir: None,
+            login_guard: LoginGuard::default(),
+            audit_log: AuditLog::default(),
+            tokens: HashMap::new(),
+            token_ttl: DEFAULT_TOKEN_TTL,
+            tokens_required: false,
+            presented: Presented::Plain,
+            issued: None,
+            profiles: HashMap::new(),
+            notifications: Vec::new(),
+            presence_readers: HashSet::new(),
+            history: History::default(),
+            privacy: HashMap::new(),
+            client_list_scope: ClientListScope::Registered,
+            clock: Arc::new(SystemClock),
         }
     }
 
-    /// Retrieves a list of all registered usernames.
-    fn get_client_list(&self) -> Vec<UserName> {
+    /// Sets which users the client lists sent in response to chat requests include. Clients
+    /// can ask for the other scope with `AccountRequest::ClientList`.
+    pub fn set_client_list_scope(&mut self, scope: ClientListScope) {
+        self.client_list_scope = scope;
+    }
+
+    /// Sets how many messages the history of every conversation keeps. Zero disables the
+    /// history.
+    pub fn set_history_limit(&mut self, max_messages: usize) {
+        self.history.set_limit(max_messages);
+    }
+
+    /// Sets how long the session tokens issued from now on are valid.
+    pub fn set_token_ttl(&mut self, ttl: Duration) {
+        self.token_ttl = ttl;
+    }
+
+    /// Sets when failed logins lock a username or a node out.
+    pub fn set_login_policy(&mut self, policy: LoginPolicy) {
+        self.login_guard.set_policy(policy);
+    }
+
+    /// Returns the most recent authentication events, oldest first.
+    pub fn audit_log(&self) -> impl Iterator<Item = &AuditRecord> {
+        self.audit_log.records()
+    }
+
+    /// Retrieves the usernames `viewer` can see in the given scope: every registered or logged
+    /// in user, but the ones hidden from it.
+    fn get_client_list(&self, viewer: &UserName, scope: ClientListScope) -> Vec<UserName> {
         self.clients
-            .iter()
-            .map(|(username, _)| username.clone())
+            .keys()
+            .filter(|username| self.is_visible_to(username, viewer))
+            .filter(|username| match scope {
+                ClientListScope::Registered => true,
+                ClientListScope::Online => self.is_logged(username),
+            })
+            .cloned()
             .collect()
     }
 
+    /// Checks whether `username` blocked `other`.
+    fn blocks(&self, username: &UserName, other: &UserName) -> bool {
+        self.privacy
+            .get(username)
+            .is_some_and(|privacy| privacy.blocked.contains(other))
+    }
+
+    /// Checks whether `viewer` can see `username` in client lists, profiles and presence
+    /// notifications: users always see themselves, and never see hidden users or users they
+    /// blocked or were blocked by.
+    fn is_visible_to(&self, username: &UserName, viewer: &UserName) -> bool {
+        if username == viewer {
+            return true;
+        }
+        let hidden = self
+            .privacy
+            .get(username)
+            .is_some_and(|privacy| privacy.hidden);
+        !hidden && !self.blocks(username, viewer) && !self.blocks(viewer, username)
+    }
+
     /// Registers a new client with the given username, password, and node ID.
     ///
     /// # Arguments
     ///
     /// * `username` - New client's username.
-    /// * `password` - The password associated with the client.
+    /// * `password` - The hash of the password associated with the client.
     /// * `node_id` - The `NodeId` associated with the client connection.
     ///
     /// # Returns
@@ -48,15 +216,23 @@ impl ChatBehavior {
     fn register(
         &mut self,
         username: UserName,
-        password: Password,
+        password: PasswordHash,
         node_id: NodeId,
     ) -> Result<(), RegisterError> {
-        match self.clients.entry(username) {
+        match self.clients.entry(username.clone()) {
             Entry::Vacant(entry) => {
                 entry.insert((password, node_id, true));
+                self.audit_log
+                    .record(node_id, &username, AuthEvent::Registered);
+                self.persist_accounts();
+                self.notify_presence(&username, Some(Profile::default()));
                 Ok(())
             }
-            Entry::Occupied(_) => Err(RegisterError::AlreadyRegistered),
+            Entry::Occupied(_) => {
+                self.audit_log
+                    .record(node_id, &username, AuthEvent::AlreadyRegistered);
+                Err(RegisterError::AlreadyRegistered)
+            }
         }
     }
 
@@ -69,14 +245,68 @@ impl ChatBehavior {
     ///
     /// # Returns
     ///
-    /// `true` if the client is authenticated and their `NodeId` matches; otherwise, `false`.
+    /// `true` if the client is authenticated, their `NodeId` matches and the request presents
+    /// the token of their session, if it has one; otherwise, `false`.
     fn is_auth(&self, username: &UserName, node_id: NodeId) -> bool {
         match self.clients.get(username) {
-            Some((_, id, logged)) => node_id == *id && *logged,
+            Some((_, id, logged)) => node_id == *id && *logged && self.presents_token(username),
+            None => false,
+        }
+    }
+
+    /// Checks whether a client is logged in, with a session that has not expired.
+    fn is_logged(&self, username: &UserName) -> bool {
+        let logged = matches!(self.clients.get(username), Some((_, _, true)));
+        logged && !self.is_expired(username)
+    }
+
+    /// Checks whether the token of a client's session has expired.
+    fn is_expired(&self, username: &UserName) -> bool {
+        match self.tokens.get(username) {
+            Some((_, expires)) => self.clock.now() >= *expires,
             None => false,
         }
     }
 
+    /// Checks whether the request being processed presents the valid token of a client's
+    /// session. Sessions opened by clients that do not support tokens have none, and only rely
+    /// on the `NodeId`.
+    fn presents_token(&self, username: &UserName) -> bool {
+        match self.tokens.get(username) {
+            Some((token, expires)) => {
+                self.clock.now() < *expires && self.presented.token().as_ref() == Some(token)
+            }
+            None => true,
+        }
+    }
+
+    /// Opens the session of a client that just logged in or registered.
+    ///
+    /// If the request came in an envelope, a new token is issued to the client. If it cannot be
+    /// generated, the client is logged out again.
+    fn open_session(&mut self, username: &UserName) -> Result<(), ProcessError> {
+        self.tokens.remove(username);
+        self.presence_readers.remove(username);
+        if let Presented::Plain = self.presented {
+            return Ok(());
+        }
+
+        match SessionToken::generate() {
+            Ok(token) => {
+                let expires = self.clock.now() + self.token_ttl;
+                self.tokens.insert(username.clone(), (token, expires));
+                self.issued = Some(token);
+                Ok(())
+            }
+            Err(err) => {
+                if let Some(client) = self.clients.get_mut(username) {
+                    client.2 = false;
+                }
+                Err(ProcessError::TokenGeneration(err))
+            }
+        }
+    }
+
     /// Verifies if the sender is authenticated and the recipient can receive a message.
     ///
     /// # Arguments
@@ -91,6 +321,8 @@ impl ChatBehavior {
     ///   message can be sent.
     /// - `Err(MessageError)` if the sender is not authenticated, the recipient is not registered,
     ///   or the recipient is not logged in.
+    /// - `Err(MessageError::RecipientNotLogged)` if one of the two blocked the other, so that
+    ///   the sender does not learn that it is blocked.
     fn can_send_message(
         &mut self,
         sender: UserName,
@@ -101,11 +333,13 @@ impl ChatBehavior {
             return Err(MessageError::SenderNotLogged(sender));
         }
 
+        let logged = self.is_logged(&recipient);
+        let blocked = self.blocks(&recipient, &sender) || self.blocks(&sender, &recipient);
         match self.clients.entry(recipient.clone()) {
             Entry::Occupied(entry) => {
                 let client = entry.get();
 
-                if client.2 {
+                if logged && !blocked {
                     Ok((client.1, sender))
                 } else {
                     Err(MessageError::RecipientNotLogged(recipient))
@@ -117,30 +351,79 @@ impl ChatBehavior {
 
     /// Signin a client if its credentials are valid.
     ///
+    /// The session is bound to the node the client logs in from, which may not be the one it
+    /// registered from, so that a user reconnecting from another client keeps its history.
+    ///
     /// # Arguments
     ///
     /// * `username` - The username of the client.
     /// * `password` - The password provided by the client.
+    /// * `node_id` - The `NodeId` the attempt comes from.
     ///
     /// # Returns
     ///
     /// - `Ok(())` if the login is successful.
     /// - `Err(LoginError)` if the client is already logged in, not registered, or the password is incorrect.
-    pub fn login(&mut self, username: &UserName, password: &Password) -> Result<(), LoginError> {
-        match self.clients.entry(username.clone()) {
+    ///   A client whose session expired is not logged in anymore.
+    /// - `Err(LoginError::WrongPassword)` if the username or the node is locked out, whatever the
+    ///   password, so that a locked out attacker learns nothing from its attempts.
+    pub fn login(
+        &mut self,
+        username: &UserName,
+        password: &Password,
+        node_id: NodeId,
+    ) -> Result<(), LoginError> {
+        if let Err(remaining) = self.login_guard.check(username, node_id, self.clock.now()) {
+            self.audit_log
+                .record(node_id, username, AuthEvent::Rejected(remaining));
+            return Err(LoginError::WrongPassword);
+        }
+
+        let logged = self.is_logged(username);
+        let result = match self.clients.entry(username.clone()) {
             Entry::Occupied(mut entry) => {
                 let client = entry.get_mut();
-                if client.2 {
+                if logged {
                     Err(LoginError::AlreadyLogged)
-                } else if &client.0 != password {
+                } else if !client.0.verify(password) {
                     Err(LoginError::WrongPassword)
                 } else {
+                    client.1 = node_id;
                     client.2 = true;
                     Ok(())
                 }
             }
             Entry::Vacant(_) => Err(LoginError::NotRegistered),
+        };
+
+        let (event, lockout) = match &result {
+            Ok(()) => {
+                self.login_guard.record_success(username);
+                (AuthEvent::LoggedIn, None)
+            }
+            Err(LoginError::WrongPassword) => (
+                AuthEvent::WrongPassword,
+                self.login_guard
+                    .record_failure(Some(username), node_id, self.clock.now()),
+            ),
+            Err(LoginError::NotRegistered) => (
+                AuthEvent::NotRegistered,
+                self.login_guard
+                    .record_failure(None, node_id, self.clock.now()),
+            ),
+            Err(_) => (AuthEvent::AlreadyLogged, None),
+        };
+        self.audit_log.record(node_id, username, event);
+        if let Some(lockout) = lockout {
+            self.audit_log
+                .record(node_id, username, AuthEvent::LockedOut(lockout));
+        }
+        if result.is_ok() {
+            let profile = self.profile(username);
+            self.notify_presence(username, Some(profile));
         }
+
+        result
     }
 
     /// Signout a client if they are currently logged in.
@@ -155,25 +438,594 @@ impl ChatBehavior {
     /// - `Ok(())` if the logout is successful.
     /// - `Err(LogoutError)` if the client is not logged in, the `NodeId` does not match,
     ///   or the client is not registered.
+    /// - `Err(LogoutError::InvalidNodeId)` if the request does not present the token of the
+    ///   session too.
+    ///
+    /// The token of the session, if any, is revoked.
     pub fn logout(&mut self, username: &UserName, node_id: NodeId) -> Result<(), LogoutError> {
+        let logged = self.is_logged(username);
+        let presents_token = self.presents_token(username);
         match self.clients.entry(username.clone()) {
             Entry::Occupied(mut entry) => {
                 let client = entry.get_mut();
-                if !client.2 {
+                if !logged {
                     return Err(LogoutError::NotLogged);
                 }
-                if client.1 != node_id {
+                if client.1 != node_id || !presents_token {
                     return Err(LogoutError::InvalidNodeId);
                 }
                 client.2 = false;
+                self.tokens.remove(username);
+                self.presence_readers.remove(username);
+                self.audit_log
+                    .record(node_id, username, AuthEvent::LoggedOut);
+                self.notify_presence(username, None);
                 Ok(())
             }
             Entry::Vacant(_) => Err(LogoutError::NotRegistered),
         }
     }
+
+    /// Changes the password of a logged in client.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())` if the password is changed.
+    /// - `Err(AccountError::NotLogged)` if the client is not authenticated.
+    /// - `Err(AccountError::WrongPassword)` if `old` is not the current password.
+    fn change_password(
+        &mut self,
+        username: &UserName,
+        old: &Password,
+        new: PasswordHash,
+        node_id: NodeId,
+    ) -> Result<(), AccountError> {
+        if !self.is_auth(username, node_id) {
+            return Err(AccountError::NotLogged);
+        }
+        self.check_password(username, old, node_id)?;
+
+        if let Some(client) = self.clients.get_mut(username) {
+            client.0 = new;
+        }
+        self.audit_log
+            .record(node_id, username, AuthEvent::PasswordChanged);
+        self.persist_accounts();
+        Ok(())
+    }
+
+    /// Deletes the account of a logged in client, together with its session, its profile, its
+    /// failed logins and its conversations, and saves the state. The other logged in clients
+    /// are notified.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())` if the account is deleted.
+    /// - `Err(AccountError::NotLogged)` if the client is not authenticated.
+    /// - `Err(AccountError::WrongPassword)` if `password` is not its password.
+    pub fn delete_account(
+        &mut self,
+        username: &UserName,
+        password: &Password,
+        node_id: NodeId,
+    ) -> Result<(), AccountError> {
+        if !self.is_auth(username, node_id) {
+            return Err(AccountError::NotLogged);
+        }
+        self.check_password(username, password, node_id)?;
+
+        self.clients.remove(username);
+        self.tokens.remove(username);
+        self.presence_readers.remove(username);
+        self.profiles.remove(username);
+        self.login_guard.forget(username);
+        self.history.forget(username);
+        self.privacy.remove(username);
+        for privacy in self.privacy.values_mut() {
+            privacy.blocked.remove(username);
+        }
+        self.audit_log
+            .record(node_id, username, AuthEvent::AccountDeleted);
+        self.persist();
+        self.notify_presence(username, None);
+        Ok(())
+    }
+
+    /// Replaces the profile of a logged in client. If it changed, the other logged in clients
+    /// are notified.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())` if the profile is set.
+    /// - `Err(AccountError::NotLogged)` if the client is not authenticated.
+    /// - `Err(AccountError::InvalidProfile)` if the display name is blank or too long.
+    pub fn set_profile(
+        &mut self,
+        username: &UserName,
+        profile: Profile,
+        node_id: NodeId,
+    ) -> Result<(), AccountError> {
+        if !self.is_auth(username, node_id) {
+            return Err(AccountError::NotLogged);
+        }
+        if !profile.is_valid() {
+            return Err(AccountError::InvalidProfile);
+        }
+
+        if self.profile(username) != profile {
+            self.profiles.insert(username.clone(), profile.clone());
+            self.notify_presence(username, Some(profile));
+        }
+        Ok(())
+    }
+
+    /// Returns the profile of a client, the default one if it never set one.
+    fn profile(&self, username: &UserName) -> Profile {
+        self.profiles.get(username).cloned().unwrap_or_default()
+    }
+
+    /// Retrieves the profiles of the logged in clients `viewer` can see.
+    fn get_profiles(&self, viewer: &UserName) -> Vec<(UserName, Profile)> {
+        self.get_client_list(viewer, ClientListScope::Online)
+            .into_iter()
+            .map(|username| {
+                let profile = self.profile(&username);
+                (username, profile)
+            })
+            .collect()
+    }
+
+    /// Blocks the messages between a logged in client and another user.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())` if the user is blocked, or was already.
+    /// - `Err(AccountError::NotLogged)` if the client is not authenticated.
+    /// - `Err(AccountError::UnknownUser)` if the other user is not registered.
+    pub fn block(
+        &mut self,
+        username: &UserName,
+        blocked: &UserName,
+        node_id: NodeId,
+    ) -> Result<(), AccountError> {
+        if !self.is_auth(username, node_id) {
+            return Err(AccountError::NotLogged);
+        }
+        if !self.clients.contains_key(blocked) {
+            return Err(AccountError::UnknownUser);
+        }
+
+        self.privacy
+            .entry(username.clone())
+            .or_default()
+            .blocked
+            .insert(blocked.clone());
+        Ok(())
+    }
+
+    /// Lifts a block set by a logged in client.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())` if the user is not blocked anymore, or was not.
+    /// - `Err(AccountError::NotLogged)` if the client is not authenticated.
+    pub fn unblock(
+        &mut self,
+        username: &UserName,
+        blocked: &UserName,
+        node_id: NodeId,
+    ) -> Result<(), AccountError> {
+        if !self.is_auth(username, node_id) {
+            return Err(AccountError::NotLogged);
+        }
+
+        if let Some(privacy) = self.privacy.get_mut(username) {
+            privacy.blocked.remove(blocked);
+        }
+        Ok(())
+    }
+
+    /// Hides a logged in client from the client lists and the profiles the others receive, or
+    /// shows it again. Hidden clients can still send and receive messages.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())` if the setting is changed.
+    /// - `Err(AccountError::NotLogged)` if the client is not authenticated.
+    pub fn set_hidden(
+        &mut self,
+        username: &UserName,
+        hidden: bool,
+        node_id: NodeId,
+    ) -> Result<(), AccountError> {
+        if !self.is_auth(username, node_id) {
+            return Err(AccountError::NotLogged);
+        }
+
+        self.privacy.entry(username.clone()).or_default().hidden = hidden;
+        Ok(())
+    }
+
+    /// Saves the registered accounts to the storage directory, if one is set.
+    fn save_accounts(&self) -> Result<(), ProcessError> {
+        let dir = match &self.storage_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        let accounts: Vec<(&UserName, &PasswordHash, NodeId)> = self
+            .clients
+            .iter()
+            .map(|(username, (password, node_id, _))| (username, password, *node_id))
+            .collect();
+        let bytes = to_allocvec(&accounts).map_err(ProcessError::Serialize)?;
+        fs::write(dir.join(ACCOUNTS_FILE), bytes).map_err(ProcessError::FileSystem)
+    }
+
+    /// Saves the accounts after a change, logging the failure, if any: the change is already
+    /// done, and is saved again by the next one or the final flush.
+    fn persist_accounts(&self) {
+        if let Err(err) = self.save_accounts() {
+            error!("Failed to save the accounts: {}", err);
+        }
+    }
+
+    /// Saves the whole state after a change that does not only touch the accounts, logging the
+    /// failure like `persist_accounts`.
+    fn persist(&mut self) {
+        if let Err(err) = self.flush() {
+            error!("Failed to save the chat state: {}", err);
+        }
+    }
+
+    /// Checks the password of an authenticated client before a sensitive operation.
+    ///
+    /// Wrong passwords count as failed logins, see `LoginPolicy`, so that a hijacked session
+    /// cannot be used to guess the password.
+    fn check_password(
+        &mut self,
+        username: &UserName,
+        password: &Password,
+        node_id: NodeId,
+    ) -> Result<(), AccountError> {
+        if let Err(remaining) = self.login_guard.check(username, node_id, self.clock.now()) {
+            self.audit_log
+                .record(node_id, username, AuthEvent::Rejected(remaining));
+            return Err(AccountError::WrongPassword);
+        }
+
+        if matches!(self.clients.get(username), Some((current, _, _)) if current.verify(password)) {
+            return Ok(());
+        }
+
+        self.audit_log
+            .record(node_id, username, AuthEvent::WrongPassword);
+        if let Some(lockout) =
+            self.login_guard
+                .record_failure(Some(username), node_id, self.clock.now())
+        {
+            self.audit_log
+                .record(node_id, username, AuthEvent::LockedOut(lockout));
+        }
+        Err(AccountError::WrongPassword)
+    }
+
+    /// Checks whether the session of a client reads presence notifications: it holds a session
+    /// token, or sent an account request since it logged in.
+    fn reads_presence(&self, username: &UserName) -> bool {
+        self.tokens.contains_key(username) || self.presence_readers.contains(username)
+    }
+
+    /// Queues a presence notification of `username` for every other logged in client that reads
+    /// them.
+    fn notify_presence(&mut self, username: &UserName, profile: Option<Profile>) {
+        let notification = AccountResponse::Presence(username.clone(), profile);
+        let data = match account::encode(&notification) {
+            Ok(data) => data,
+            Err(err) => {
+                error!("Failed to serialize presence notification: {:?}", err);
+                return;
+            }
+        };
+
+        let dests: Vec<NodeId> = self
+            .clients
+            .iter()
+            .filter(|(other, _)| {
+                *other != username
+                    && self.is_logged(other)
+                    && self.reads_presence(other)
+                    && self.is_visible_to(username, other)
+            })
+            .map(|(_, (_, node_id, _))| *node_id)
+            .collect();
+        for dest in dests {
+            self.notifications.push(AssembledResponse {
+                data: data.clone(),
+                dest,
+                kind: RequestKind::Account,
+            });
+        }
+    }
+
+    /// Processes an account request.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(AccountResponse)` with the response, which tells whether the operation succeeded.
+    /// - `Err(ProcessError::PasswordHashing)` if a new password cannot be hashed.
+    fn process_account(
+        &mut self,
+        request: AccountRequest,
+        initiator_id: NodeId,
+    ) -> Result<AccountResponse, ProcessError> {
+        if self.is_auth(request.username(), initiator_id) {
+            self.presence_readers.insert(request.username().clone());
+        }
+
+        let response = match request {
+            AccountRequest::ChangePassword(username, old, new) => {
+                let new = PasswordHash::new(&new).map_err(ProcessError::PasswordHashing)?;
+                match self.change_password(&username, &old, new, initiator_id) {
+                    Ok(()) => AccountResponse::PasswordChanged(username),
+                    Err(err) => AccountResponse::Failure(username, err),
+                }
+            }
+            AccountRequest::DeleteAccount(username, password) => {
+                match self.delete_account(&username, &password, initiator_id) {
+                    Ok(()) => AccountResponse::AccountDeleted(username),
+                    Err(err) => AccountResponse::Failure(username, err),
+                }
+            }
+            AccountRequest::SetProfile(username, profile) => {
+                match self.set_profile(&username, profile, initiator_id) {
+                    Ok(()) => AccountResponse::ProfileSet(username),
+                    Err(err) => AccountResponse::Failure(username, err),
+                }
+            }
+            AccountRequest::Profiles(username) => {
+                if self.is_auth(&username, initiator_id) {
+                    let profiles = self.get_profiles(&username);
+                    AccountResponse::Profiles(username, profiles)
+                } else {
+                    AccountResponse::Failure(username, AccountError::NotLogged)
+                }
+            }
+            AccountRequest::Block(username, blocked) => {
+                match self.block(&username, &blocked, initiator_id) {
+                    Ok(()) => AccountResponse::Blocked(username, blocked),
+                    Err(err) => AccountResponse::Failure(username, err),
+                }
+            }
+            AccountRequest::Unblock(username, blocked) => {
+                match self.unblock(&username, &blocked, initiator_id) {
+                    Ok(()) => AccountResponse::Unblocked(username, blocked),
+                    Err(err) => AccountResponse::Failure(username, err),
+                }
+            }
+            AccountRequest::SetHidden(username, hidden) => {
+                match self.set_hidden(&username, hidden, initiator_id) {
+                    Ok(()) => AccountResponse::HiddenSet(username, hidden),
+                    Err(err) => AccountResponse::Failure(username, err),
+                }
+            }
+            AccountRequest::ClientList(username, scope) => {
+                if self.is_auth(&username, initiator_id) {
+                    let clients = self.get_client_list(&username, scope);
+                    AccountResponse::ClientList(username, clients)
+                } else {
+                    AccountResponse::Failure(username, AccountError::NotLogged)
+                }
+            }
+        };
+        Ok(response)
+    }
+
+    /// Processes a history request.
+    fn process_history(&self, request: HistoryRequest, initiator_id: NodeId) -> HistoryResponse {
+        match request {
+            HistoryRequest::History(username, other, before, len) => {
+                if !self.is_auth(&username, initiator_id) {
+                    return HistoryResponse::Failure(username, HistoryError::NotLogged);
+                }
+                let len = usize::from(len).min(MAX_PAGE_LEN);
+                let (messages, more) = self.history.page(&username, &other, before, len);
+                HistoryResponse::History(other, messages, more)
+            }
+            HistoryRequest::Search(username, query) => {
+                if !self.is_auth(&username, initiator_id) {
+                    HistoryResponse::Failure(username, HistoryError::NotLogged)
+                } else if query.trim().is_empty() {
+                    HistoryResponse::Failure(username, HistoryError::EmptyQuery)
+                } else {
+                    let messages = self.history.search(&username, &query, MAX_PAGE_LEN);
+                    HistoryResponse::SearchResults(username, messages)
+                }
+            }
+        }
+    }
+
+    /// Handles an account request, counting it and its errors like `handle_assembled` does.
+    fn handle_account(
+        &mut self,
+        assembled: &[u8],
+        initiator_id: NodeId,
+        metrics: &mut Metrics,
+    ) -> AssembledResponse {
+        let kind = RequestKind::Account;
+        let result = account::decode::<AccountRequest>(assembled)
+            .map_err(ProcessError::Deserialize)
+            .and_then(|request| {
+                let request = request.ok_or(ProcessError::UnexpectedRequest)?;
+                metrics.increment(MetricKey::labeled(metrics::REQUESTS, "kind", kind.as_str()));
+                let response = self.process_account(request, initiator_id)?;
+                account::encode(&response).map_err(ProcessError::Serialize)
+            });
+        self.envelope_response(kind, result, initiator_id, metrics)
+    }
+
+    /// Handles a history request, counting it and its errors like `handle_assembled` does.
+    fn handle_history(
+        &mut self,
+        assembled: &[u8],
+        initiator_id: NodeId,
+        metrics: &mut Metrics,
+    ) -> AssembledResponse {
+        let kind = RequestKind::History;
+        let result = history::decode::<HistoryRequest>(assembled)
+            .map_err(ProcessError::Deserialize)
+            .and_then(|request| {
+                let request = request.ok_or(ProcessError::UnexpectedRequest)?;
+                metrics.increment(MetricKey::labeled(metrics::REQUESTS, "kind", kind.as_str()));
+                let response = self.process_history(request, initiator_id);
+                history::encode(&response).map_err(ProcessError::Serialize)
+            });
+        self.envelope_response(kind, result, initiator_id, metrics)
+    }
+
+    /// Builds the response to a request in an envelope, or the error response if it failed.
+    fn envelope_response(
+        &self,
+        kind: RequestKind,
+        result: Result<Vec<u8>, ProcessError>,
+        initiator_id: NodeId,
+        metrics: &mut Metrics,
+    ) -> AssembledResponse {
+        match result {
+            Ok(data) => AssembledResponse {
+                data,
+                dest: initiator_id,
+                kind,
+            },
+            Err(err) => {
+                metrics.increment(MetricKey::labeled(
+                    metrics::REQUEST_ERRORS,
+                    "error",
+                    err.as_str(),
+                ));
+                AssembledResponse {
+                    kind,
+                    ..self.handle_error(err, initiator_id)
+                }
+            }
+        }
+    }
 }
 
 impl SpecializedBehavior for ChatBehavior {
+    /// Handles a request, checking the session token it presents and issuing a new one if it
+    /// logs the initiator in.
+    fn handle_authenticated(
+        &mut self,
+        assembled: Vec<u8>,
+        initiator_id: NodeId,
+        presented: Presented,
+        metrics: &mut Metrics,
+    ) -> (AssembledResponse, Option<SessionToken>) {
+        self.presented = presented;
+        let response = if self.tokens_required && presented == Presented::Plain {
+            let err = ProcessError::TokenRequired;
+            metrics.increment(MetricKey::labeled(
+                metrics::REQUEST_ERRORS,
+                "error",
+                err.as_str(),
+            ));
+            self.handle_error(err, initiator_id)
+        } else if account::is_account(&assembled) {
+            self.handle_account(&assembled, initiator_id, metrics)
+        } else if history::is_history(&assembled) {
+            self.handle_history(&assembled, initiator_id, metrics)
+        } else {
+            self.handle_assembled(assembled, initiator_id, metrics)
+        };
+        self.presented = Presented::Plain;
+        (response, self.issued.take())
+    }
+
+    fn take_notifications(&mut self) -> Vec<AssembledResponse> {
+        std::mem::take(&mut self.notifications)
+    }
+
+    fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Sets whether requests outside a token envelope, including logins and registrations,
+    /// are rejected, so that no session is only bound to its `NodeId`.
+    fn set_tokens_required(&mut self, required: bool) {
+        self.tokens_required = required;
+    }
+
+    /// Sets the directory where registered accounts are persisted and loads the ones saved
+    /// there, together with their privacy settings and the message history.
+    ///
+    /// Loaded accounts start logged out. Accounts registered before the call are kept, and
+    /// win over saved accounts with the same username.
+    fn set_storage_dir(&mut self, dir: PathBuf) -> Result<(), SetPathError> {
+        fs::create_dir_all(&dir).map_err(SetPathError::FileSystem)?;
+
+        match fs::read(dir.join(ACCOUNTS_FILE)) {
+            Ok(bytes) => {
+                let accounts = from_bytes::<Vec<(UserName, PasswordHash, NodeId)>>(&bytes)
+                    .map_err(|err| {
+                        SetPathError::FileSystem(io::Error::new(io::ErrorKind::InvalidData, err))
+                    })?;
+
+                for (username, password, node_id) in accounts {
+                    self.clients
+                        .entry(username)
+                        .or_insert((password, node_id, false));
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(SetPathError::FileSystem(err)),
+        }
+
+        match fs::read(dir.join(PRIVACY_FILE)) {
+            Ok(bytes) => {
+                let settings = from_bytes::<Vec<(UserName, Privacy)>>(&bytes).map_err(|err| {
+                    SetPathError::FileSystem(io::Error::new(io::ErrorKind::InvalidData, err))
+                })?;
+
+                for (username, privacy) in settings {
+                    self.privacy.entry(username).or_insert(privacy);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(SetPathError::FileSystem(err)),
+        }
+
+        match fs::read(dir.join(HISTORY_FILE)) {
+            Ok(bytes) => self.history.load(&bytes).map_err(|err| {
+                SetPathError::FileSystem(io::Error::new(io::ErrorKind::InvalidData, err))
+            })?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(SetPathError::FileSystem(err)),
+        }
+
+        self.audit_log.set_file(dir.join(AUDIT_FILE));
+        self.storage_dir = Some(dir);
+        Ok(())
+    }
+
+    /// Saves the registered accounts, their privacy settings and the message history to the
+    /// storage directory, if one is set.
+    fn flush(&mut self) -> Result<(), ProcessError> {
+        let dir = match &self.storage_dir {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+
+        self.save_accounts()?;
+
+        let settings: Vec<(&UserName, &Privacy)> = self.privacy.iter().collect();
+        let bytes = to_allocvec(&settings).map_err(ProcessError::Serialize)?;
+        fs::write(dir.join(PRIVACY_FILE), bytes).map_err(ProcessError::FileSystem)?;
+
+        let bytes = self.history.to_bytes().map_err(ProcessError::Serialize)?;
+        fs::write(dir.join(HISTORY_FILE), bytes).map_err(ProcessError::FileSystem)
+    }
+
     /// Processes a chat request and generates an appropriate response.
     ///
     /// # Arguments
@@ -208,7 +1060,8 @@ impl SpecializedBehavior for ChatBehavior {
             // - `ClientList`: Returns the list of connected clients.
             ChatRequest::ClientList(username) => {
                 let response = if self.is_auth(&username, initiator_id) {
-                    ChatResponse::ClientList(username, self.get_client_list())
+                    let clients = self.get_client_list(&username, self.client_list_scope);
+                    ChatResponse::ClientList(username, clients)
                 } else {
                     ChatResponse::ClientListFailure(username, ClientListError::NotLogged)
                 };
@@ -219,13 +1072,14 @@ impl SpecializedBehavior for ChatBehavior {
 
             // - `Message`: Sends a message from one client to another.
             ChatRequest::Message(sender, recipient, msg) => {
-                let (response, dest) = match self.can_send_message(sender, initiator_id, recipient)
-                {
-                    Ok((recipient_id, sender)) => {
-                        (ChatResponse::Message(sender, msg), recipient_id)
-                    }
-                    Err(err) => (ChatResponse::MessageFailure(err), initiator_id),
-                };
+                let (response, dest) =
+                    match self.can_send_message(sender, initiator_id, recipient.clone()) {
+                        Ok((recipient_id, sender)) => {
+                            self.history.record(&sender, &recipient, msg.clone());
+                            (ChatResponse::Message(sender, msg), recipient_id)
+                        }
+                        Err(err) => (ChatResponse::MessageFailure(err), initiator_id),
+                    };
 
                 let response = Response::Chat(response);
                 (response, dest)
@@ -233,8 +1087,14 @@ impl SpecializedBehavior for ChatBehavior {
 
             // - `Register`: Registers a new client with a username and password.
             ChatRequest::Register(username, password) => {
+                let password =
+                    PasswordHash::new(&password).map_err(ProcessError::PasswordHashing)?;
                 let response = match self.register(username.clone(), password, initiator_id) {
-                    Ok(_) => ChatResponse::ClientList(username, self.get_client_list()),
+                    Ok(_) => {
+                        self.open_session(&username)?;
+                        let clients = self.get_client_list(&username, self.client_list_scope);
+                        ChatResponse::ClientList(username, clients)
+                    }
                     Err(err) => ChatResponse::RegisterFailure(username, err),
                 };
 
@@ -244,8 +1104,12 @@ impl SpecializedBehavior for ChatBehavior {
 
             // - `Login`: Authenticates a client and logs them in.
             ChatRequest::Login(username, password) => {
-                let response = match self.login(&username, &password) {
-                    Ok(()) => ChatResponse::ClientList(username, self.get_client_list()),
+                let response = match self.login(&username, &password, initiator_id) {
+                    Ok(()) => {
+                        self.open_session(&username)?;
+                        let clients = self.get_client_list(&username, self.client_list_scope);
+                        ChatResponse::ClientList(username, clients)
+                    }
                     Err(err) => ChatResponse::LoginFailure(username, err),
                 };
 
@@ -268,3 +1132,657 @@ impl SpecializedBehavior for ChatBehavior {
         Ok((response, dest))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account::Status;
+    use crate::clock::ManualClock;
+    use rust_roveri_api::ContentResponse;
+
+    fn hashed(password: &str) -> PasswordHash {
+        PasswordHash::new(password).unwrap()
+    }
+
+    #[test]
+    fn test_login_lockout() {
+        let mut chat = ChatBehavior::new();
+        let clock = ManualClock::new();
+        chat.set_clock(Arc::new(clock.clone()));
+        chat.set_login_policy(LoginPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_millis(100),
+            max_lockout: Duration::from_secs(1),
+        });
+        let username = "ciao".to_string();
+        let password = "cane".to_string();
+        assert!(chat
+            .register(username.clone(), hashed(&password), 7)
+            .is_ok());
+        assert!(chat.logout(&username, 7).is_ok());
+
+        for _ in 0..3 {
+            assert!(matches!(
+                chat.login(&username, &"gatto".to_string(), 8),
+                Err(LoginError::WrongPassword)
+            ));
+        }
+
+        // Even the right password is rejected during the lockout, from any node
+        assert!(matches!(
+            chat.login(&username, &password, 7),
+            Err(LoginError::WrongPassword)
+        ));
+        assert!(!chat.is_auth(&username, 7));
+
+        clock.advance(Duration::from_millis(150));
+        assert!(chat.login(&username, &password, 7).is_ok());
+
+        let events: Vec<AuthEvent> = chat.audit_log().map(|record| record.event).collect();
+        assert_eq!(events.len(), 8);
+        assert_eq!(
+            events[..6],
+            [
+                AuthEvent::Registered,
+                AuthEvent::LoggedOut,
+                AuthEvent::WrongPassword,
+                AuthEvent::WrongPassword,
+                AuthEvent::WrongPassword,
+                AuthEvent::LockedOut(Duration::from_millis(100)),
+            ]
+        );
+        assert_eq!(events[6], AuthEvent::Rejected(Duration::from_millis(100)));
+        assert_eq!(events[7], AuthEvent::LoggedIn);
+    }
+
+    #[test]
+    fn test_node_lockout() {
+        let mut chat = ChatBehavior::new();
+        chat.set_login_policy(LoginPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_millis(100),
+            max_lockout: Duration::from_secs(1),
+        });
+        assert!(chat.register("ciao".to_string(), hashed("cane"), 7).is_ok());
+        assert!(chat.logout(&"ciao".to_string(), 7).is_ok());
+
+        // A node guessing usernames is locked out for every account
+        for username in ["a", "b", "c"] {
+            assert!(matches!(
+                chat.login(&username.to_string(), &"cane".to_string(), 9),
+                Err(LoginError::NotRegistered)
+            ));
+        }
+        assert!(matches!(
+            chat.login(&"ciao".to_string(), &"cane".to_string(), 9),
+            Err(LoginError::WrongPassword)
+        ));
+        assert!(chat
+            .login(&"ciao".to_string(), &"cane".to_string(), 7)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_storage_roundtrip() {
+        let dir = std::env::temp_dir().join(format!(
+            "server_chat_storage_roundtrip_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+
+        // Accounts are saved when they change, without waiting for a flush
+        let mut chat = ChatBehavior::new();
+        assert!(chat.set_storage_dir(dir.clone()).is_ok());
+        assert!(chat.register("ciao".to_string(), hashed("cane"), 7).is_ok());
+        assert!(chat
+            .change_password(&"ciao".to_string(), &"cane".to_string(), hashed("gatto"), 7)
+            .is_ok());
+
+        // The passwords are not saved in clear
+        let saved = fs::read(dir.join(ACCOUNTS_FILE)).unwrap();
+        assert!(!saved.windows(5).any(|window| window == b"gatto"));
+
+        let mut restored = ChatBehavior::new();
+        assert!(restored.set_storage_dir(dir.clone()).is_ok());
+        assert_eq!(
+            restored.get_client_list(&"ciao".to_string(), ClientListScope::Registered),
+            vec!["ciao".to_string()]
+        );
+        assert!(!restored.is_auth(&"ciao".to_string(), 7));
+        assert!(matches!(
+            restored.login(&"ciao".to_string(), &"cane".to_string(), 7),
+            Err(LoginError::WrongPassword)
+        ));
+        assert!(restored
+            .login(&"ciao".to_string(), &"gatto".to_string(), 7)
+            .is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn send(
+        chat: &mut ChatBehavior,
+        request: ChatRequest,
+        node_id: NodeId,
+        presented: Presented,
+    ) -> (ChatResponse, Option<SessionToken>) {
+        let bytes = to_allocvec(&Request::Chat(request)).unwrap();
+        let (response, issued) =
+            chat.handle_authenticated(bytes, node_id, presented, &mut Metrics::new());
+        match from_bytes::<Response>(&response.data).unwrap() {
+            Response::Chat(response) => (response, issued),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_session_token() {
+        let mut chat = ChatBehavior::new();
+        let username = "ciao".to_string();
+
+        let (response, issued) = send(
+            &mut chat,
+            ChatRequest::Register(username.clone(), "cane".to_string()),
+            7,
+            Presented::Enveloped(None),
+        );
+        assert!(matches!(response, ChatResponse::ClientList(..)));
+        let token = issued.unwrap();
+
+        // Requests from the right node but without the token are rejected
+        let list = || ChatRequest::ClientList(username.clone());
+        for presented in [
+            Presented::Plain,
+            Presented::Enveloped(None),
+            Presented::Enveloped(Some(SessionToken::from_bytes([0; 16]))),
+        ] {
+            let (response, _) = send(&mut chat, list(), 7, presented);
+            assert!(matches!(response, ChatResponse::ClientListFailure(..)));
+        }
+        let (response, _) = send(&mut chat, list(), 8, Presented::Enveloped(Some(token)));
+        assert!(matches!(response, ChatResponse::ClientListFailure(..)));
+        let (response, issued) = send(&mut chat, list(), 7, Presented::Enveloped(Some(token)));
+        assert!(matches!(response, ChatResponse::ClientList(..)));
+        assert!(issued.is_none());
+
+        // Logging out revokes the token
+        let logout = || ChatRequest::Logout(username.clone());
+        let (response, _) = send(&mut chat, logout(), 7, Presented::Plain);
+        assert!(matches!(
+            response,
+            ChatResponse::LogoutFailure(_, LogoutError::InvalidNodeId)
+        ));
+        let (response, _) = send(&mut chat, logout(), 7, Presented::Enveloped(Some(token)));
+        assert!(matches!(response, ChatResponse::LogoutSuccess(_)));
+        let (response, _) = send(&mut chat, list(), 7, Presented::Enveloped(Some(token)));
+        assert!(matches!(response, ChatResponse::ClientListFailure(..)));
+
+        // Clients that do not support tokens keep relying on the node
+        let (_, issued) = send(
+            &mut chat,
+            ChatRequest::Login(username.clone(), "cane".to_string()),
+            7,
+            Presented::Plain,
+        );
+        assert!(issued.is_none());
+        assert!(chat.is_auth(&username, 7));
+    }
+
+    #[test]
+    fn test_tokens_required() {
+        let mut chat = ChatBehavior::new();
+        chat.set_tokens_required(true);
+        let username = "ciao".to_string();
+        let rejected = |chat: &mut ChatBehavior, request: ChatRequest, node_id: NodeId| {
+            let bytes = to_allocvec(&Request::Chat(request)).unwrap();
+            let (response, issued) =
+                chat.handle_authenticated(bytes, node_id, Presented::Plain, &mut Metrics::new());
+            assert!(issued.is_none());
+            matches!(
+                from_bytes::<Response>(&response.data),
+                Ok(Response::Content(ContentResponse::InternalServerError(_)))
+            )
+        };
+
+        // Plain registrations are rejected
+        let register = || ChatRequest::Register(username.clone(), "cane".to_string());
+        assert!(rejected(&mut chat, register(), 7));
+        assert!(!chat.clients.contains_key(&username));
+
+        let (response, issued) = send(&mut chat, register(), 7, Presented::Enveloped(None));
+        assert!(matches!(response, ChatResponse::ClientList(..)));
+        assert!(issued.is_some());
+
+        // A node spoofing the one of the session cannot send plain requests on its behalf
+        let message = ChatRequest::Message(username.clone(), username.clone(), "Ciao".to_string());
+        assert!(rejected(&mut chat, message, 7));
+        assert!(chat
+            .history
+            .search(&username, "ciao", MAX_PAGE_LEN)
+            .is_empty());
+        assert!(rejected(
+            &mut chat,
+            ChatRequest::Logout(username.clone()),
+            7
+        ));
+        assert!(chat.is_logged(&username));
+    }
+
+    #[test]
+    fn test_session_token_expiry() {
+        let mut chat = ChatBehavior::new();
+        let clock = ManualClock::new();
+        chat.set_clock(Arc::new(clock.clone()));
+        chat.set_token_ttl(Duration::from_millis(50));
+        let username = "ciao".to_string();
+        let login = || ChatRequest::Login(username.clone(), "cane".to_string());
+        assert!(chat.register(username.clone(), hashed("cane"), 7).is_ok());
+        assert!(chat.logout(&username, 7).is_ok());
+
+        let (_, issued) = send(&mut chat, login(), 7, Presented::Enveloped(None));
+        let token = issued.unwrap();
+        let (response, _) = send(&mut chat, login(), 7, Presented::Enveloped(None));
+        assert!(matches!(
+            response,
+            ChatResponse::LoginFailure(_, LoginError::AlreadyLogged)
+        ));
+
+        clock.advance(Duration::from_millis(80));
+        let list = || ChatRequest::ClientList(username.clone());
+        let (response, _) = send(&mut chat, list(), 7, Presented::Enveloped(Some(token)));
+        assert!(matches!(response, ChatResponse::ClientListFailure(..)));
+
+        // An expired session does not keep the client logged in
+        let (response, issued) = send(&mut chat, login(), 7, Presented::Enveloped(None));
+        assert!(matches!(response, ChatResponse::ClientList(..)));
+        assert_ne!(issued, Some(token));
+    }
+
+    fn send_account(
+        chat: &mut ChatBehavior,
+        request: AccountRequest,
+        node_id: NodeId,
+    ) -> AccountResponse {
+        let bytes = account::encode(&request).unwrap();
+        let (response, _) =
+            chat.handle_authenticated(bytes, node_id, Presented::Plain, &mut Metrics::new());
+        assert_eq!(response.kind, RequestKind::Account);
+        account::decode(&response.data).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_account_management() {
+        let mut chat = ChatBehavior::new();
+        let ciao = "ciao".to_string();
+        let cane = "cane".to_string();
+        assert!(chat.register(ciao.clone(), hashed("pass"), 7).is_ok());
+        assert!(chat.register(cane.clone(), hashed("word"), 8).is_ok());
+
+        // Only logged in clients can manage their account
+        assert_eq!(
+            send_account(&mut chat, AccountRequest::Profiles(ciao.clone()), 8),
+            AccountResponse::Failure(ciao.clone(), AccountError::NotLogged)
+        );
+
+        // Profile changes are notified to the other logged in clients that read presence
+        // notifications, which cane does once it sends an account request
+        let profile = Profile {
+            display_name: Some("Ciao".to_string()),
+            status: Status::Busy,
+        };
+        send_account(
+            &mut chat,
+            AccountRequest::SetProfile(ciao.clone(), profile.clone()),
+            7,
+        );
+        assert!(chat.take_notifications().is_empty());
+        send_account(&mut chat, AccountRequest::Profiles(cane.clone()), 8);
+        send_account(
+            &mut chat,
+            AccountRequest::SetProfile(ciao.clone(), Profile::default()),
+            7,
+        );
+        chat.take_notifications();
+        let request = AccountRequest::SetProfile(ciao.clone(), profile.clone());
+        assert_eq!(
+            send_account(&mut chat, request.clone(), 7),
+            AccountResponse::ProfileSet(ciao.clone())
+        );
+        let notifications = chat.take_notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].dest, 8);
+        assert_eq!(
+            account::decode::<AccountResponse>(&notifications[0].data).unwrap(),
+            Some(AccountResponse::Presence(
+                ciao.clone(),
+                Some(profile.clone())
+            ))
+        );
+        // Setting the same profile again is not notified
+        send_account(&mut chat, request, 7);
+        assert!(chat.take_notifications().is_empty());
+        assert!(matches!(
+            send_account(&mut chat, AccountRequest::Profiles(cane.clone()), 8),
+            AccountResponse::Profiles(_, profiles) if profiles.contains(&(ciao.clone(), profile.clone()))
+        ));
+
+        let invalid = Profile {
+            display_name: Some(String::new()),
+            status: Status::Online,
+        };
+        assert_eq!(
+            send_account(
+                &mut chat,
+                AccountRequest::SetProfile(ciao.clone(), invalid),
+                7
+            ),
+            AccountResponse::Failure(ciao.clone(), AccountError::InvalidProfile)
+        );
+
+        // The current password is required to change it
+        let request =
+            AccountRequest::ChangePassword(ciao.clone(), "nope".to_string(), "new".to_string());
+        assert_eq!(
+            send_account(&mut chat, request, 7),
+            AccountResponse::Failure(ciao.clone(), AccountError::WrongPassword)
+        );
+        let request =
+            AccountRequest::ChangePassword(ciao.clone(), "pass".to_string(), "new".to_string());
+        assert_eq!(
+            send_account(&mut chat, request, 7),
+            AccountResponse::PasswordChanged(ciao.clone())
+        );
+        assert!(chat.logout(&ciao, 7).is_ok());
+        assert!(matches!(
+            chat.login(&ciao, &"pass".to_string(), 7),
+            Err(LoginError::WrongPassword)
+        ));
+        assert!(chat.login(&ciao, &"new".to_string(), 7).is_ok());
+        let presence: Vec<_> = chat
+            .take_notifications()
+            .iter()
+            .map(|notification| {
+                assert_eq!(notification.dest, 8);
+                account::decode::<AccountResponse>(&notification.data)
+                    .unwrap()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            presence,
+            [
+                AccountResponse::Presence(ciao.clone(), None),
+                AccountResponse::Presence(ciao.clone(), Some(profile.clone())),
+            ]
+        );
+
+        // Deleting the account forgets everything about it
+        let request = AccountRequest::DeleteAccount(ciao.clone(), "new".to_string());
+        assert_eq!(
+            send_account(&mut chat, request, 7),
+            AccountResponse::AccountDeleted(ciao.clone())
+        );
+        let notifications = chat.take_notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(
+            account::decode::<AccountResponse>(&notifications[0].data).unwrap(),
+            Some(AccountResponse::Presence(ciao.clone(), None))
+        );
+        assert_eq!(
+            chat.get_client_list(&cane, ClientListScope::Registered),
+            vec![cane]
+        );
+        assert!(!chat.profiles.contains_key(&ciao));
+        assert!(chat.register(ciao.clone(), hashed("pass"), 9).is_ok());
+        assert_eq!(chat.profile(&ciao), Profile::default());
+        assert!(matches!(
+            chat.audit_log().last(),
+            Some(AuditRecord {
+                event: AuthEvent::Registered,
+                ..
+            })
+        ));
+    }
+
+    fn send_history(
+        chat: &mut ChatBehavior,
+        request: HistoryRequest,
+        node_id: NodeId,
+    ) -> HistoryResponse {
+        let bytes = history::encode(&request).unwrap();
+        let (response, _) =
+            chat.handle_authenticated(bytes, node_id, Presented::Plain, &mut Metrics::new());
+        assert_eq!(response.kind, RequestKind::History);
+        history::decode(&response.data).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_history() {
+        let dir = std::env::temp_dir().join(format!("server_chat_history_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut chat = ChatBehavior::new();
+        assert!(chat.set_storage_dir(dir.clone()).is_ok());
+        let ciao = "ciao".to_string();
+        let cane = "cane".to_string();
+        assert!(chat.register(ciao.clone(), hashed("pass"), 7).is_ok());
+        assert!(chat.register(cane.clone(), hashed("word"), 8).is_ok());
+
+        for i in 0..5 {
+            let message = ChatRequest::Message(ciao.clone(), cane.clone(), format!("Ciao {}", i));
+            send(&mut chat, message, 7, Presented::Plain);
+        }
+        let message = ChatRequest::Message(cane.clone(), ciao.clone(), "Ciao a te".to_string());
+        send(&mut chat, message, 8, Presented::Plain);
+        // Messages that are not delivered are not recorded
+        let message = ChatRequest::Message(cane.clone(), "gatto".to_string(), "Ciao".to_string());
+        send(&mut chat, message, 8, Presented::Plain);
+
+        // Both users read the same conversation, one page at a time
+        let request = HistoryRequest::History(cane.clone(), ciao.clone(), None, 4);
+        let HistoryResponse::History(_, page, true) = send_history(&mut chat, request, 8) else {
+            panic!("expected a page with older messages");
+        };
+        assert_eq!(page.last().unwrap().message, "Ciao a te");
+        let request = HistoryRequest::History(ciao.clone(), cane.clone(), Some(page[0].id), 4);
+        let HistoryResponse::History(_, page, false) = send_history(&mut chat, request, 7) else {
+            panic!("expected the first page");
+        };
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].message, "Ciao 0");
+
+        // Requests on behalf of another user are rejected
+        let request = HistoryRequest::History(ciao.clone(), cane.clone(), None, 4);
+        assert_eq!(
+            send_history(&mut chat, request, 8),
+            HistoryResponse::Failure(ciao.clone(), HistoryError::NotLogged)
+        );
+
+        let request = HistoryRequest::Search(ciao.clone(), "A TE".to_string());
+        let HistoryResponse::SearchResults(_, found) = send_history(&mut chat, request, 7) else {
+            panic!("expected search results");
+        };
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].sender, cane);
+        let request = HistoryRequest::Search(ciao.clone(), " ".to_string());
+        assert_eq!(
+            send_history(&mut chat, request, 7),
+            HistoryResponse::Failure(ciao.clone(), HistoryError::EmptyQuery)
+        );
+
+        // A user reconnecting from another client reads the same conversation
+        assert!(chat.logout(&ciao, 7).is_ok());
+        assert!(chat.login(&ciao, &"pass".to_string(), 9).is_ok());
+        let request = HistoryRequest::History(ciao.clone(), cane.clone(), None, 10);
+        let HistoryResponse::History(_, page, false) = send_history(&mut chat, request, 9) else {
+            panic!("expected the whole conversation");
+        };
+        assert_eq!(page.len(), 6);
+        let request = HistoryRequest::History(ciao.clone(), cane.clone(), None, 10);
+        assert_eq!(
+            send_history(&mut chat, request, 7),
+            HistoryResponse::Failure(ciao.clone(), HistoryError::NotLogged)
+        );
+
+        // The history is persisted with the accounts
+        assert!(chat.flush().is_ok());
+        let mut restored = ChatBehavior::new();
+        assert!(restored.set_storage_dir(dir.clone()).is_ok());
+        assert_eq!(
+            restored.history.search(&cane, "ciao", MAX_PAGE_LEN).len(),
+            6
+        );
+
+        // Deleting an account saves the state without waiting for a flush
+        assert!(chat.delete_account(&cane, &"word".to_string(), 8).is_ok());
+        let mut restored = ChatBehavior::new();
+        assert!(restored.set_storage_dir(dir.clone()).is_ok());
+        assert!(!restored.clients.contains_key(&cane));
+        assert!(restored
+            .history
+            .search(&ciao, "ciao", MAX_PAGE_LEN)
+            .is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    fn register_three(chat: &mut ChatBehavior) -> (UserName, UserName, UserName) {
+        let names = ("ciao".to_string(), "cane".to_string(), "gatto".to_string());
+        assert!(chat.register(names.0.clone(), hashed("pass"), 7).is_ok());
+        assert!(chat.register(names.1.clone(), hashed("pass"), 8).is_ok());
+        assert!(chat.register(names.2.clone(), hashed("pass"), 9).is_ok());
+        names
+    }
+
+    fn sorted(mut usernames: Vec<UserName>) -> Vec<UserName> {
+        usernames.sort();
+        usernames
+    }
+
+    #[test]
+    fn test_block_list() {
+        let mut chat = ChatBehavior::new();
+        let (ciao, cane, gatto) = register_three(&mut chat);
+
+        assert_eq!(
+            chat.block(&ciao, &"topo".to_string(), 7),
+            Err(AccountError::UnknownUser)
+        );
+        assert_eq!(chat.block(&ciao, &cane, 8), Err(AccountError::NotLogged));
+        assert_eq!(
+            send_account(
+                &mut chat,
+                AccountRequest::Block(ciao.clone(), cane.clone()),
+                7
+            ),
+            AccountResponse::Blocked(ciao.clone(), cane.clone())
+        );
+
+        // Neither of the two can message the other, and the block looks like being offline
+        assert!(matches!(
+            chat.can_send_message(cane.clone(), 8, ciao.clone()),
+            Err(MessageError::RecipientNotLogged(_))
+        ));
+        assert!(chat
+            .can_send_message(ciao.clone(), 7, cane.clone())
+            .is_err());
+        assert!(chat
+            .can_send_message(gatto.clone(), 9, ciao.clone())
+            .is_ok());
+
+        // Nor do they see each other
+        assert_eq!(
+            sorted(chat.get_client_list(&cane, ClientListScope::Registered)),
+            [cane.clone(), gatto.clone()]
+        );
+        assert!(!chat
+            .get_client_list(&ciao, ClientListScope::Registered)
+            .contains(&cane));
+        let profile = Profile {
+            display_name: None,
+            status: Status::Away,
+        };
+        send_account(&mut chat, AccountRequest::Profiles(gatto.clone()), 9);
+        assert!(chat.set_profile(&ciao, profile, 7).is_ok());
+        let notifications = chat.take_notifications();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].dest, 9);
+
+        assert!(chat.unblock(&ciao, &cane, 7).is_ok());
+        assert!(chat.can_send_message(cane.clone(), 8, ciao.clone()).is_ok());
+
+        // Deleting an account lifts the blocks it is part of
+        assert!(chat.block(&ciao, &gatto, 7).is_ok());
+        assert!(chat.delete_account(&gatto, &"pass".to_string(), 9).is_ok());
+        assert!(chat.register(gatto.clone(), hashed("pass"), 9).is_ok());
+        assert!(chat.can_send_message(gatto, 9, ciao).is_ok());
+    }
+
+    #[test]
+    fn test_hidden_client() {
+        let mut chat = ChatBehavior::new();
+        let (ciao, cane, gatto) = register_three(&mut chat);
+
+        assert_eq!(
+            send_account(&mut chat, AccountRequest::SetHidden(ciao.clone(), true), 7),
+            AccountResponse::HiddenSet(ciao.clone(), true)
+        );
+        assert_eq!(
+            sorted(chat.get_client_list(&cane, ClientListScope::Registered)),
+            [cane.clone(), gatto.clone()]
+        );
+        assert!(chat
+            .get_client_list(&ciao, ClientListScope::Online)
+            .contains(&ciao));
+        assert!(!chat
+            .get_profiles(&gatto)
+            .iter()
+            .any(|(name, _)| *name == ciao));
+
+        // Hidden clients can still be messaged by the ones that know them
+        assert!(chat.can_send_message(cane.clone(), 8, ciao.clone()).is_ok());
+
+        assert!(chat.set_hidden(&ciao, false, 7).is_ok());
+        assert_eq!(
+            chat.get_client_list(&cane, ClientListScope::Registered)
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_client_list_scope() {
+        let mut chat = ChatBehavior::new();
+        let (ciao, cane, gatto) = register_three(&mut chat);
+        assert!(chat.logout(&gatto, 9).is_ok());
+
+        // Chat client lists include every registered user, unless set otherwise
+        let (response, _) = send(
+            &mut chat,
+            ChatRequest::ClientList(ciao.clone()),
+            7,
+            Presented::Plain,
+        );
+        assert!(matches!(response, ChatResponse::ClientList(_, clients) if clients.len() == 3));
+
+        chat.set_client_list_scope(ClientListScope::Online);
+        let (response, _) = send(
+            &mut chat,
+            ChatRequest::ClientList(ciao.clone()),
+            7,
+            Presented::Plain,
+        );
+        let ChatResponse::ClientList(_, clients) = response else {
+            panic!("expected a client list");
+        };
+        assert_eq!(sorted(clients), [cane.clone(), ciao.clone()]);
+
+        // Clients can ask for either scope
+        let request = AccountRequest::ClientList(ciao.clone(), ClientListScope::Registered);
+        let AccountResponse::ClientList(_, clients) = send_account(&mut chat, request, 7) else {
+            panic!("expected a client list");
+        };
+        assert_eq!(sorted(clients), [cane, ciao, gatto]);
+    }
+}
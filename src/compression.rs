@@ -0,0 +1,147 @@
+//! Implements the optional compression of message payloads.
+//!
+//! A node that supports compression wraps its payloads in the compression envelope, see the
+//! `envelope` module: the codec, then the payload, compressed with deflate if that makes it
+//! shorter. Sending a request in an envelope tells the server that the client can decode one,
+//! so only those clients receive compressed responses; everyone else keeps receiving plain
+//! payloads.
+//!
+//! A client advertises support without compressing its request with
+//! `encode(&request, usize::MAX)`, and reads every response with `decode`.
+
+use crate::envelope;
+use std::fmt;
+
+/// Size, in bytes, above which the server compresses responses by default.
+pub const DEFAULT_THRESHOLD: usize = 1024;
+
+/// First byte of a payload wrapped in an envelope.
+const MARKER: u8 = envelope::COMPRESSION;
+
+const CODEC_NONE: u8 = 0;
+const CODEC_DEFLATE: u8 = 1;
+
+/// Deflate compression level, from 0 (fastest) to 10 (smallest).
+const LEVEL: u8 = 6;
+
+/// Upper bound to the size of a decompressed payload, so that a small message cannot exhaust
+/// the memory of its receiver.
+const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+/// Errors that can occur while unwrapping a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionError {
+    /// The envelope has no codec.
+    Truncated,
+    /// The envelope uses a codec this node does not know.
+    UnknownCodec(u8),
+    /// The compressed payload is invalid or too large.
+    Corrupted,
+}
+
+impl fmt::Display for CompressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionError::Truncated => write!(f, "truncated envelope"),
+            CompressionError::UnknownCodec(codec) => write!(f, "unknown codec {}", codec),
+            CompressionError::Corrupted => write!(f, "invalid compressed payload"),
+        }
+    }
+}
+
+impl std::error::Error for CompressionError {}
+
+/// Wraps a payload in an envelope.
+///
+/// The payload is compressed if it is longer than `threshold` bytes and compressing it makes it
+/// shorter, otherwise it is stored as it is.
+pub fn encode(data: &[u8], threshold: usize) -> Vec<u8> {
+    if data.len() > threshold {
+        let compressed = miniz_oxide::deflate::compress_to_vec(data, LEVEL);
+        if compressed.len() < data.len() {
+            let mut encoded = Vec::with_capacity(compressed.len() + 2);
+            encoded.extend_from_slice(&[MARKER, CODEC_DEFLATE]);
+            encoded.extend_from_slice(&compressed);
+            return encoded;
+        }
+    }
+
+    let mut encoded = Vec::with_capacity(data.len() + 2);
+    encoded.extend_from_slice(&[MARKER, CODEC_NONE]);
+    encoded.extend_from_slice(data);
+    encoded
+}
+
+/// Unwraps a payload, decompressing it if needed.
+///
+/// # Returns
+///
+/// - `Ok((Vec<u8>, true))` with the original payload if it was in an envelope.
+/// - `Ok((Vec<u8>, false))` with the payload unchanged if it was not.
+/// - `Err(CompressionError)` if the envelope is invalid.
+pub fn decode(mut data: Vec<u8>) -> Result<(Vec<u8>, bool), CompressionError> {
+    if data.first() != Some(&MARKER) {
+        return Ok((data, false));
+    }
+
+    match data.get(1).copied() {
+        None => Err(CompressionError::Truncated),
+        Some(CODEC_NONE) => {
+            data.drain(..2);
+            Ok((data, true))
+        }
+        Some(CODEC_DEFLATE) => {
+            miniz_oxide::inflate::decompress_to_vec_with_limit(&data[2..], MAX_DECOMPRESSED_SIZE)
+                .map(|decompressed| (decompressed, true))
+                .map_err(|_| CompressionError::Corrupted)
+        }
+        Some(codec) => Err(CompressionError::UnknownCodec(codec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard::to_allocvec;
+    use rust_roveri_api::{ContentRequest, ContentResponse, ContentType, Request, Response};
+
+    #[test]
+    fn test_roundtrip() {
+        let small = b"ciao cane".to_vec();
+        let encoded = encode(&small, DEFAULT_THRESHOLD);
+        assert_eq!(encoded.len(), small.len() + 2);
+        assert_eq!(decode(encoded), Ok((small, true)));
+
+        let large = "ciao cane ".repeat(500).into_bytes();
+        let encoded = encode(&large, DEFAULT_THRESHOLD);
+        assert!(encoded.len() < large.len() / 10);
+        assert_eq!(decode(encoded), Ok((large, true)));
+    }
+
+    #[test]
+    fn test_plain_payloads_pass_through() {
+        let request = to_allocvec(&Request::Content(ContentRequest::List)).unwrap();
+        assert_eq!(decode(request.clone()), Ok((request, false)));
+
+        let response = to_allocvec(&Response::Content(ContentResponse::Content(
+            "ciao.txt".to_string(),
+            ContentType::Text,
+            vec![MARKER; 16],
+        )))
+        .unwrap();
+        assert_eq!(decode(response.clone()), Ok((response, false)));
+    }
+
+    #[test]
+    fn test_invalid_envelopes() {
+        assert_eq!(decode(vec![MARKER]), Err(CompressionError::Truncated));
+        assert_eq!(
+            decode(vec![MARKER, 7, 1, 2]),
+            Err(CompressionError::UnknownCodec(7))
+        );
+        assert_eq!(
+            decode(vec![MARKER, CODEC_DEFLATE, 0xFF, 0xFF, 0xFF]),
+            Err(CompressionError::Corrupted)
+        );
+    }
+}
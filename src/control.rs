@@ -0,0 +1,194 @@
+//! Defines the server-specific controller interface.
+//!
+//! `ServerCommand` and `ServerEvent` are shared by every node type of the network, so requests
+//! that only make sense for this server travel on a dedicated pair of channels, installed with
+//! `Server::set_control_channels`.
+
+use crate::error::Error;
+use crate::metrics::Metrics;
+use crate::rate_limiter::RateLimits;
+use crate::specialized_behavior::RequestKind;
+use crate::topology::TopologySnapshot;
+use crate::transport::Transport;
+use rust_roveri_api::SessionId;
+use std::collections::HashSet;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use wg_2024::network::NodeId;
+use wg_2024::packet::{NackType, Packet};
+
+/// Commands the controller can send to the server on the control channel.
+#[derive(Debug)]
+pub enum ControlCommand {
+    /// Asks the server for its current view of the network.
+    GetTopology,
+    /// Stops the server once its outstanding transfers are acknowledged or the given timeout
+    /// expires, whichever comes first.
+    ///
+    /// While stopping, new requests are refused with an error response. Unlike
+    /// `ServerCommand::Crash`, the specialized behavior is flushed and a
+    /// `ControlEvent::ShutdownSummary` is sent before `Server::run` returns.
+    GracefulStop(Duration),
+    /// Replaces the set of telemetry events the server emits.
+    ///
+    /// No telemetry is emitted until the controller subscribes to it, while replies to other
+    /// commands are always sent.
+    Subscribe(EventFilter),
+    /// Asks the server for a snapshot of its metrics.
+    GetMetrics,
+    /// Writes the server metrics to a file in the Prometheus text format.
+    ExportMetrics(PathBuf),
+    /// Adds a neighbor reached through any `Transport`, e.g. a node of another process.
+    ///
+    /// Behaves like `ServerCommand::AddDrone`, which only accepts in-process channels.
+    AddNeighbor(NodeId, Box<dyn Transport>),
+    /// Replaces the limits applied to the requests of every client, see `Server::set_rate_limits`.
+    SetRateLimits(RateLimits),
+}
+
+/// Events the server sends back on the control channel.
+#[derive(Debug)]
+pub enum ControlEvent {
+    /// The server's current view of the network, sent in reply to `ControlCommand::GetTopology`.
+    ///
+    /// `TopologySnapshot::to_dot` exports it for Graphviz.
+    Topology(TopologySnapshot),
+    /// Final report of a graceful stop, sent right before `Server::run` returns.
+    ShutdownSummary(ShutdownSummary),
+    /// Snapshot of the server metrics, sent in reply to `ControlCommand::GetMetrics`.
+    Metrics(Metrics),
+    /// Outcome of a `ControlCommand::ExportMetrics`.
+    MetricsExported(PathBuf, io::Result<()>),
+    /// A packet was received from a neighbor.
+    PacketReceived(Packet),
+    /// All the fragments of an incoming message were received.
+    MessageAssembled {
+        session_id: SessionId,
+        initiator_id: NodeId,
+        size: usize,
+    },
+    /// A request was handed to the specialized behavior and its response queued.
+    ///
+    /// The latency is measured from the reception of the first fragment of the request.
+    RequestProcessed {
+        kind: RequestKind,
+        initiator_id: NodeId,
+        latency: Duration,
+    },
+    /// Every fragment of a response was acknowledged by its destination.
+    ResponseAcknowledged { session_id: SessionId, dest: NodeId },
+    /// A fragment was queued again for transmission.
+    Retransmission {
+        session_id: SessionId,
+        fragment_index: u64,
+        reason: RetransmissionReason,
+    },
+    /// No route to the destination of a fragment is known.
+    RouteNotFound { dest: NodeId },
+    /// Flood requests were sent to every neighbor to rediscover the network.
+    DiscoveryStarted { neighbors: usize },
+    /// Handling a packet or a command failed.
+    Error(Error),
+}
+
+impl ControlEvent {
+    /// Returns the telemetry kind of the event, or `None` for replies to commands.
+    pub fn kind(&self) -> Option<EventKind> {
+        match self {
+            ControlEvent::Topology(_)
+            | ControlEvent::ShutdownSummary(_)
+            | ControlEvent::Metrics(_)
+            | ControlEvent::MetricsExported(..) => None,
+            ControlEvent::PacketReceived(_) => Some(EventKind::PacketReceived),
+            ControlEvent::MessageAssembled { .. } => Some(EventKind::MessageAssembled),
+            ControlEvent::RequestProcessed { .. } => Some(EventKind::RequestProcessed),
+            ControlEvent::ResponseAcknowledged { .. } => Some(EventKind::ResponseAcknowledged),
+            ControlEvent::Retransmission { .. } => Some(EventKind::Retransmission),
+            ControlEvent::RouteNotFound { .. } => Some(EventKind::RouteNotFound),
+            ControlEvent::DiscoveryStarted { .. } => Some(EventKind::DiscoveryStarted),
+            ControlEvent::Error(_) => Some(EventKind::Error),
+        }
+    }
+}
+
+/// Why a fragment was queued again for transmission.
+#[derive(Debug, Clone)]
+pub enum RetransmissionReason {
+    /// A drone on the route sent back a Nack.
+    Nack(NackType),
+    /// The neighbor the fragment was sent through was removed.
+    NeighborRemoved(NodeId),
+}
+
+/// The kinds of telemetry events a controller can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    PacketReceived,
+    MessageAssembled,
+    RequestProcessed,
+    ResponseAcknowledged,
+    Retransmission,
+    RouteNotFound,
+    DiscoveryStarted,
+    Error,
+}
+
+impl EventKind {
+    pub const ALL: [EventKind; 8] = [
+        EventKind::PacketReceived,
+        EventKind::MessageAssembled,
+        EventKind::RequestProcessed,
+        EventKind::ResponseAcknowledged,
+        EventKind::Retransmission,
+        EventKind::RouteNotFound,
+        EventKind::DiscoveryStarted,
+        EventKind::Error,
+    ];
+}
+
+/// The set of telemetry events a controller is subscribed to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+    kinds: HashSet<EventKind>,
+}
+
+impl EventFilter {
+    /// A filter that lets no telemetry event through.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    /// A filter that lets every telemetry event through.
+    pub fn all() -> Self {
+        Self {
+            kinds: EventKind::ALL.into_iter().collect(),
+        }
+    }
+
+    /// Adds a kind of event to the filter.
+    pub fn with(mut self, kind: EventKind) -> Self {
+        self.kinds.insert(kind);
+        self
+    }
+
+    /// Checks whether events of the given kind pass the filter.
+    pub fn allows(&self, kind: EventKind) -> bool {
+        self.kinds.contains(&kind)
+    }
+}
+
+/// Describes how a graceful stop went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownSummary {
+    /// Whether every outstanding transfer was acknowledged before the timeout.
+    pub drained: bool,
+    /// Fragments still waiting for an acknowledgment when the server stopped.
+    pub unacknowledged_fragments: usize,
+    /// Incoming messages that were still being assembled when the server stopped.
+    pub abandoned_sessions: usize,
+    /// Requests refused because the server was stopping.
+    pub refused_requests: usize,
+    /// Whether the specialized behavior flushed its state successfully.
+    pub flushed: bool,
+}
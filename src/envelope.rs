@@ -0,0 +1,61 @@
+//! Describes the envelopes clients wrap their payloads in, to use the features a plain
+//! `Request` or `Response` has no room for.
+//!
+//! An envelope is a marker byte followed by what its module defines, ending with the wrapped
+//! payload. Postcard writes the variant index of a `Request` or `Response` first, and every
+//! marker is above the highest one, so a receiver tells an envelope from a plain payload by its
+//! first byte, and nodes that know no envelope keep working.
+//!
+//! Envelopes nest in a fixed order, from the outermost one:
+//!
+//! | Marker | Module        | Wraps                                                          |
+//! |--------|---------------|----------------------------------------------------------------|
+//! | `0xF8` | `fec`         | a request whose sender asks for parity fragments               |
+//! | `0xFC` | `compression` | a payload of a node that decodes compressed ones               |
+//! | `0xFB` | `token`       | a chat request or response, with the session token             |
+//! | `0xFA` | `account`     | an `AccountRequest` or `AccountResponse`, instead of a message |
+//! | `0xF9` | `history`     | a `HistoryRequest` or `HistoryResponse`, instead of a message  |
+//!
+//! A client wraps a request starting from the innermost envelope it uses, and unwraps the
+//! response starting from the outermost one. Every envelope is optional.
+
+/// Marker of the forward error correction envelope, see the `fec` module.
+pub const FEC: u8 = 0xF8;
+
+/// Marker of the compression envelope, see the `compression` module.
+pub const COMPRESSION: u8 = 0xFC;
+
+/// Marker of the session token envelope, see the `token` module.
+pub const TOKEN: u8 = 0xFB;
+
+/// Marker of the account envelope, see the `account` module.
+pub const ACCOUNT: u8 = 0xFA;
+
+/// Marker of the history envelope, see the `history` module.
+pub const HISTORY: u8 = 0xF9;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard::to_allocvec;
+    use rust_roveri_api::{ChatRequest, ChatResponse, ContentRequest, Request, Response};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_markers() {
+        let markers = [FEC, COMPRESSION, TOKEN, ACCOUNT, HISTORY];
+        assert_eq!(markers.iter().collect::<HashSet<_>>().len(), markers.len());
+
+        let messages = [
+            to_allocvec(&Request::Content(ContentRequest::List)).unwrap(),
+            to_allocvec(&Request::Chat(ChatRequest::Logout("ciao".to_string()))).unwrap(),
+            to_allocvec(&Response::Chat(ChatResponse::LogoutSuccess(
+                "ciao".to_string(),
+            )))
+            .unwrap(),
+        ];
+        for message in messages {
+            assert!(!markers.contains(&message[0]));
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Defines `Error`, the failures the server reports to its controller.
+
+use crate::assembler::{InsertFragmentError, RetrieveError};
+use crate::compression::CompressionError;
+use crate::specialized_behavior::{ProcessError, SetPathError};
+use crate::token::TokenError;
+use crate::topology::RoutingError;
+use crate::transport::TransportError;
+use rust_roveri_api::{FragmentId, SessionId};
+use std::fmt;
+use wg_2024::network::NodeId;
+
+/// Every failure the server can run into while handling packets and commands.
+///
+/// Errors are propagated up to a single point in `Server`, which logs them, counts them in
+/// the metrics and reports them with `ControlEvent::Error`.
+#[derive(Debug)]
+pub enum Error {
+    /// A fragment could not be added to the message it belongs to.
+    Assembly {
+        session_id: SessionId,
+        source: InsertFragmentError,
+    },
+    /// A message could not be taken out of the reassembly buffer.
+    Retrieve {
+        session_id: SessionId,
+        source: RetrieveError,
+    },
+    /// A message could not be decompressed.
+    Compression {
+        session_id: SessionId,
+        source: CompressionError,
+    },
+    /// A message has an invalid session token envelope.
+    Token {
+        session_id: SessionId,
+        source: TokenError,
+    },
+    /// A response produced no fragments, so nothing could be sent to its destination.
+    Fragmentation { dest: NodeId },
+    /// A fragment could not be routed to its destination.
+    Routing { dest: NodeId, source: RoutingError },
+    /// A Nack refers to a fragment that is not waiting for an acknowledgment.
+    UnknownFragment(FragmentId),
+    /// A packet has no hop at its hop index, or an empty route.
+    MalformedHeader,
+    /// The next hop of a packet is not a neighbor of the server.
+    UnknownNeighbor(NodeId),
+    /// A packet could not be handed to a neighbor.
+    Transport {
+        neighbor: NodeId,
+        source: TransportError,
+    },
+    /// The specialized behavior failed.
+    Behavior(ProcessError),
+    /// The content path or the storage directory could not be set.
+    Path(SetPathError),
+}
+
+impl Error {
+    /// Returns the name of the error, used as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Error::Assembly { .. } => "assembly",
+            Error::Retrieve { .. } => "retrieve",
+            Error::Compression { .. } => "compression",
+            Error::Token { .. } => "token",
+            Error::Fragmentation { .. } => "fragmentation",
+            Error::Routing { .. } => "routing",
+            Error::UnknownFragment(_) => "unknown_fragment",
+            Error::MalformedHeader => "malformed_header",
+            Error::UnknownNeighbor(_) => "unknown_neighbor",
+            Error::Transport { .. } => "transport",
+            Error::Behavior(_) => "behavior",
+            Error::Path(_) => "path",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Assembly { session_id, source } => {
+                write!(f, "cannot assemble session {}: {}", session_id, source)
+            }
+            Error::Retrieve { session_id, source } => {
+                write!(f, "cannot retrieve session {}: {}", session_id, source)
+            }
+            Error::Compression { session_id, source } => {
+                write!(f, "cannot decompress session {}: {}", session_id, source)
+            }
+            Error::Token { session_id, source } => {
+                write!(f, "invalid token in session {}: {}", session_id, source)
+            }
+            Error::Fragmentation { dest } => write!(f, "empty response for {}", dest),
+            Error::Routing { dest, source } => write!(f, "cannot route to {}: {}", dest, source),
+            Error::UnknownFragment((session_id, fragment_index)) => write!(
+                f,
+                "fragment {} of session {} is not waiting for an acknowledgment",
+                fragment_index, session_id
+            ),
+            Error::MalformedHeader => write!(f, "malformed routing header"),
+            Error::UnknownNeighbor(id) => write!(f, "{} is not a neighbor", id),
+            Error::Transport { neighbor, source } => {
+                write!(f, "cannot send to {}: {}", neighbor, source)
+            }
+            Error::Behavior(source) => write!(f, "behavior failure: {}", source),
+            Error::Path(source) => write!(f, "cannot set path: {}", source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Assembly { source, .. } => Some(source),
+            Error::Retrieve { source, .. } => Some(source),
+            Error::Compression { source, .. } => Some(source),
+            Error::Token { source, .. } => Some(source),
+            Error::Routing { source, .. } => Some(source),
+            Error::Transport { source, .. } => Some(source),
+            Error::Behavior(source) => Some(source),
+            Error::Path(source) => Some(source),
+            Error::Fragmentation { .. }
+            | Error::UnknownFragment(_)
+            | Error::MalformedHeader
+            | Error::UnknownNeighbor(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn test_display_and_source() {
+        let error = Error::Routing {
+            dest: 3,
+            source: RoutingError::NoPathFound,
+        };
+        assert_eq!(error.to_string(), "cannot route to 3: no path found");
+        assert_eq!(error.as_str(), "routing");
+        assert!(error.source().is_some());
+
+        let error = Error::UnknownFragment((1, 2));
+        assert_eq!(
+            error.to_string(),
+            "fragment 2 of session 1 is not waiting for an acknowledgment"
+        );
+        assert!(error.source().is_none());
+    }
+}
@@ -0,0 +1,318 @@
+//! Implements forward error correction for fragmented messages.
+//!
+//! The data fragments of a message are split in blocks of `Redundancy::block_size` fragments,
+//! and every block is followed by `Redundancy::parity` parity fragments. Parity fragment `j` of a
+//! block is the XOR of the data fragments whose position in the block is `j` modulo the number
+//! of parity fragments, so each of them can rebuild one missing fragment of its group without
+//! waiting for a retransmission.
+//!
+//! Parity fragments travel as regular `MsgFragment`s with the highest bit of their
+//! `fragment_index` set, followed by the redundancy of the message and their position, so the
+//! receiver needs no other information to use them. Receivers that do not know about them
+//! would reject them as out of bounds, so the server only sends them to clients that ask for
+//! them by wrapping their request with `encode`, see the `envelope` module.
+//!
+//! Parity fragments only reduce latency: the server cannot tell which fragments a client
+//! rebuilt, so fragments reported lost by a `Nack` are retransmitted all the same, and a client
+//! ignores the copies it no longer needs.
+
+use crate::envelope;
+use wg_2024::packet::{Fragment, FRAGMENT_DSIZE};
+
+/// First byte of a payload whose sender asks for parity fragments.
+const MARKER: u8 = envelope::FEC;
+
+/// Marks the index of a parity fragment.
+const PARITY_FLAG: u64 = 1 << 63;
+
+/// Highest number of parity fragments per block that fits in a parity index.
+const PARITY_MASK: u16 = 0x7FFF;
+
+/// Number of data fragments in a block, when the redundancy depends on the drop rate.
+const BLOCK_SIZE: u16 = 8;
+
+/// Highest number of parity fragments per block, when the redundancy depends on the drop rate.
+const MAX_PARITY: u16 = BLOCK_SIZE / 2;
+
+/// How many parity fragments protect each block of data fragments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redundancy {
+    pub block_size: u16,
+    pub parity: u16,
+}
+
+impl Redundancy {
+    /// No parity fragment at all.
+    pub const NONE: Redundancy = Redundancy {
+        block_size: BLOCK_SIZE,
+        parity: 0,
+    };
+
+    /// Creates a redundancy of `parity` parity fragments every `block_size` data fragments.
+    ///
+    /// The block size is at least 1 and there are never more parity fragments than data
+    /// fragments in a block.
+    pub fn new(block_size: u16, parity: u16) -> Self {
+        let block_size = block_size.max(1);
+        Self {
+            block_size,
+            parity: parity.min(block_size).min(PARITY_MASK),
+        }
+    }
+
+    /// Chooses a redundancy able to rebuild the fragments expected to be lost on a path with the
+    /// given drop rate, with some margin.
+    pub fn for_drop_rate(drop_rate: f64) -> Self {
+        let expected_losses = BLOCK_SIZE as f64 * drop_rate.clamp(0.0, 1.0);
+        let parity = (expected_losses * 1.5).ceil() as u16;
+        Self::new(BLOCK_SIZE, parity.min(MAX_PARITY))
+    }
+}
+
+/// Wraps a request payload in the envelope that asks for parity fragments.
+pub fn encode(data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + 1);
+    encoded.push(MARKER);
+    encoded.extend_from_slice(data);
+    encoded
+}
+
+/// Unwraps a request payload.
+///
+/// # Returns
+///
+/// The payload without the envelope, and whether its sender asked for parity fragments. Payloads
+/// without the envelope are returned unchanged.
+pub fn decode(mut data: Vec<u8>) -> (Vec<u8>, bool) {
+    if data.first() != Some(&MARKER) {
+        return (data, false);
+    }
+
+    data.remove(0);
+    (data, true)
+}
+
+/// Checks whether a fragment index belongs to a parity fragment.
+pub fn is_parity(fragment_index: u64) -> bool {
+    fragment_index & PARITY_FLAG != 0
+}
+
+/// The position of a parity fragment and the data fragments it protects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ParityIndex {
+    redundancy: Redundancy,
+    ordinal: u32,
+}
+
+impl ParityIndex {
+    /// Decodes the index of a parity fragment, or returns `None` for data fragments.
+    pub(crate) fn decode(fragment_index: u64) -> Option<Self> {
+        if !is_parity(fragment_index) {
+            return None;
+        }
+
+        let parity = (fragment_index >> 48) as u16 & PARITY_MASK;
+        let block_size = ((fragment_index >> 32) & 0xFFFF) as u16;
+        if parity == 0 || block_size == 0 {
+            return None;
+        }
+
+        Some(Self {
+            redundancy: Redundancy::new(block_size, parity),
+            ordinal: fragment_index as u32,
+        })
+    }
+
+    fn encode(&self) -> u64 {
+        PARITY_FLAG
+            | (self.redundancy.parity as u64) << 48
+            | (self.redundancy.block_size as u64) << 32
+            | self.ordinal as u64
+    }
+
+    /// Returns the indexes of the data fragments protected by this parity fragment, in a
+    /// message of `total` data fragments.
+    pub(crate) fn covers(&self, total: u64) -> impl Iterator<Item = u64> {
+        let parity = self.redundancy.parity as u64;
+        let block = self.ordinal as u64 / parity;
+        let start = block * self.redundancy.block_size as u64;
+        let end = u64::min(start + self.redundancy.block_size as u64, total);
+
+        (start + self.ordinal as u64 % parity..end).step_by(parity as usize)
+    }
+}
+
+/// XORs `data` into `into`.
+pub(crate) fn xor(into: &mut [u8; FRAGMENT_DSIZE], data: &[u8; FRAGMENT_DSIZE]) {
+    for (byte, other) in into.iter_mut().zip(data.iter()) {
+        *byte ^= *other;
+    }
+}
+
+/// Interleaves parity fragments with the data fragments of a message: each block of data
+/// fragments is followed by its parity fragments.
+pub(crate) fn protect(fragments: Vec<Fragment>, redundancy: Redundancy) -> Vec<Fragment> {
+    let redundancy = Redundancy::new(redundancy.block_size, redundancy.parity);
+    if redundancy.parity == 0 || fragments.is_empty() {
+        return fragments;
+    }
+
+    let total = fragments.len() as u64;
+    let block_size = redundancy.block_size as usize;
+    let blocks = fragments.len().div_ceil(block_size);
+    let mut protected = Vec::with_capacity(fragments.len() + blocks * redundancy.parity as usize);
+
+    for (block, data) in fragments.chunks(block_size).enumerate() {
+        protected.extend_from_slice(data);
+
+        for j in 0..redundancy.parity {
+            let index = ParityIndex {
+                redundancy,
+                ordinal: block as u32 * redundancy.parity as u32 + j as u32,
+            };
+
+            let mut parity = Fragment {
+                fragment_index: index.encode(),
+                total_n_fragments: total,
+                length: 0,
+                data: [0; FRAGMENT_DSIZE],
+            };
+            let mut covered = 0;
+            for i in index.covers(total) {
+                let fragment = &fragments[i as usize];
+                xor(&mut parity.data, &fragment.data);
+                parity.length ^= fragment.length;
+                covered += 1;
+            }
+
+            if covered > 0 {
+                protected.push(parity);
+            }
+        }
+    }
+
+    protected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::AssemblerStatus;
+    use crate::assemblers_manager::AssemblersManager;
+    use crate::fragmenter::Fragmenter;
+    use crate::specialized_behavior::{AssembledResponse, RequestKind};
+
+    fn message(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn fragments(data: Vec<u8>, redundancy: Redundancy) -> Vec<Fragment> {
+        Fragmenter::new()
+            .to_fragment_vec_with_parity(
+                AssembledResponse {
+                    data,
+                    dest: 1,
+                    kind: RequestKind::Content,
+                },
+                redundancy,
+            )
+            .into_iter()
+            .map(|to_be_sent| to_be_sent.fragment)
+            .collect()
+    }
+
+    /// Inserts fragments into a fresh manager and returns the message, if it could be assembled.
+    fn assemble(fragments: Vec<Fragment>) -> Option<Vec<u8>> {
+        let mut assemblers_manager = AssemblersManager::new();
+        for fragment in fragments {
+            if let Ok(AssemblerStatus::Complete) = assemblers_manager.insert_fragment(fragment, 1) {
+                return assemblers_manager.retrieve_assembled(1).ok();
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn test_envelope() {
+        let data = b"ciao".to_vec();
+        assert_eq!(decode(encode(&data)), (data.clone(), true));
+        assert_eq!(decode(data.clone()), (data, false));
+        assert_eq!(decode(Vec::new()), (Vec::new(), false));
+    }
+
+    #[test]
+    fn test_parity_index() {
+        let index = ParityIndex {
+            redundancy: Redundancy::new(8, 3),
+            ordinal: 4,
+        };
+        assert!(is_parity(index.encode()));
+        assert_eq!(ParityIndex::decode(index.encode()), Some(index));
+        assert_eq!(ParityIndex::decode(4), None);
+
+        // Second block, second group: positions 1, 4 and 7 of the block
+        assert_eq!(index.covers(20).collect::<Vec<_>>(), vec![9, 12, 15]);
+        // The last block is shorter
+        assert_eq!(index.covers(13).collect::<Vec<_>>(), vec![9, 12]);
+    }
+
+    #[test]
+    fn test_redundancy_for_drop_rate() {
+        assert_eq!(Redundancy::for_drop_rate(0.0), Redundancy::NONE);
+        assert_eq!(Redundancy::for_drop_rate(0.1).parity, 2);
+        assert_eq!(Redundancy::for_drop_rate(0.9).parity, MAX_PARITY);
+        assert_eq!(Redundancy::new(0, 5), Redundancy::new(1, 1));
+    }
+
+    #[test]
+    fn test_rebuild_missing_fragments() {
+        let data = message(20 * FRAGMENT_DSIZE + 17);
+        let protected = fragments(data.clone(), Redundancy::new(8, 2));
+        assert_eq!(
+            protected
+                .iter()
+                .filter(|f| is_parity(f.fragment_index))
+                .count(),
+            6
+        );
+
+        // One data fragment lost in every group, including the last, shorter, one
+        let lost = [0, 3, 9, 14, 17, 20];
+        let received: Vec<Fragment> = protected
+            .into_iter()
+            .filter(|f| !lost.contains(&f.fragment_index))
+            .collect();
+        assert_eq!(assemble(received), Some(data));
+    }
+
+    #[test]
+    fn test_too_many_losses() {
+        let data = message(8 * FRAGMENT_DSIZE);
+        let protected = fragments(data.clone(), Redundancy::new(8, 2));
+
+        // Two fragments of the same group are lost
+        let received: Vec<Fragment> = protected
+            .iter()
+            .filter(|f| f.fragment_index != 0 && f.fragment_index != 2)
+            .cloned()
+            .collect();
+        assert_eq!(assemble(received), None);
+
+        // Without losses the parity fragments are not needed
+        assert_eq!(assemble(protected), Some(data));
+    }
+
+    #[test]
+    fn test_parity_never_opens_a_session() {
+        let protected = fragments(message(FRAGMENT_DSIZE), Redundancy::new(8, 1));
+        let parity = protected[1].clone();
+        assert!(is_parity(parity.fragment_index));
+
+        let mut assemblers_manager = AssemblersManager::new();
+        assert!(matches!(
+            assemblers_manager.insert_fragment(parity, 1),
+            Ok(AssemblerStatus::Incomplete)
+        ));
+        assert_eq!(assemblers_manager.pending_sessions(), 0);
+    }
+}
@@ -1,10 +1,14 @@
 //! Manages fragments to be sent over the network.
 
+use crate::error::Error;
+use crate::fec;
 use rust_roveri_api::{FragmentId, SessionId};
 use std::collections::{HashMap, VecDeque};
 use wg_2024::{network::NodeId, packet::Fragment};
 
 /// Represents a fragment that is queued to be sent to a specific destination.
+///
+/// Produced by `Fragmenter::to_fragment_vec`.
 #[derive(Clone, Debug)]
 pub struct ToBeSentFragment {
     pub dest: NodeId,
@@ -15,28 +19,44 @@ pub struct ToBeSentFragment {
 /// The `FragmentManager` struct is responsible for handling the storage and processing of
 /// fragments that are queued to be sent. It includes a caching mechanism for retrieval
 /// and a buffer for processing fragments in order.
+///
+/// Fragments that have been sent but not acknowledged yet are tracked together with the
+/// neighbor they were sent through, so they can be requeued if that neighbor goes away.
+#[derive(Debug, Default)]
 pub struct FragmentManager {
     cache: HashMap<FragmentId, ToBeSentFragment>,
     buffer: VecDeque<ToBeSentFragment>,
+    in_flight: HashMap<FragmentId, NodeId>,
+    unacknowledged: HashMap<SessionId, usize>,
 }
 
 impl FragmentManager {
+    /// Creates a manager with no fragment to send.
     pub fn new() -> Self {
         Self {
             cache: HashMap::new(),
             buffer: VecDeque::new(),
+            in_flight: HashMap::new(),
+            unacknowledged: HashMap::new(),
         }
     }
 
     /// Inserts a single fragment into the manager.
     ///
     /// The fragment is added to both the cache (for quick lookup) and the buffer (for processing).
+    /// Parity fragments are only added to the buffer: they are sent once, never retransmitted,
+    /// and the session is complete without their acknowledgments.
     ///
     /// # Arguments
     ///
     /// * `to_be_sent_fragment` - The fragment to insert.
     pub fn insert_fragment(&mut self, to_be_sent_fragment: ToBeSentFragment) {
-        self.cache.insert(
+        if fec::is_parity(to_be_sent_fragment.fragment.fragment_index) {
+            self.buffer.push_back(to_be_sent_fragment);
+            return;
+        }
+
+        let replaced = self.cache.insert(
             (
                 to_be_sent_fragment.session_id,
                 to_be_sent_fragment.fragment.fragment_index,
@@ -44,6 +64,13 @@ impl FragmentManager {
             to_be_sent_fragment.clone(),
         );
 
+        if replaced.is_none() {
+            *self
+                .unacknowledged
+                .entry(to_be_sent_fragment.session_id)
+                .or_insert(0) += 1;
+        }
+
         self.buffer.push_back(to_be_sent_fragment);
     }
 
@@ -68,8 +95,23 @@ impl FragmentManager {
         }
     }
 
+    /// Inserts fragments that are sent once, without caching them.
+    ///
+    /// They are never retransmitted and their acknowledgments are not awaited, so they do not
+    /// keep the manager from being idle once sent.
+    ///
+    /// # Arguments
+    ///
+    /// * `fragments` - A vector of fragments to insert.
+    pub fn insert_uncached(&mut self, fragments: Vec<ToBeSentFragment>) {
+        self.buffer.extend(fragments);
+    }
+
     /// Re-inserts a fragment from the cache back into the buffer.
     ///
+    /// The fragment is no longer in flight until it is sent again, so removing the neighbor it
+    /// was sent through does not requeue it a second time.
+    ///
     /// # Arguments
     ///
     /// * `fragment_id` - The ID of the fragment to re-insert.
@@ -77,13 +119,14 @@ impl FragmentManager {
     /// # Returns
     ///
     /// - `Ok(())` if the fragment is found in the cache and successfully re-inserted.
-    /// - `Err(&str)` if the fragment is not found in the cache.
-    pub fn insert_from_cache(&mut self, fragment_id: FragmentId) -> Result<(), &str> {
+    /// - `Err(Error::UnknownFragment)` if the fragment is not found in the cache.
+    pub fn insert_from_cache(&mut self, fragment_id: FragmentId) -> Result<(), Error> {
         let to_be_sent_fragment = match self.cache.get(&fragment_id) {
-            None => return Err("Requested fragment is not in cache"),
+            None => return Err(Error::UnknownFragment(fragment_id)),
             Some(fragment) => fragment.clone(),
         };
 
+        self.in_flight.remove(&fragment_id);
         self.buffer.push_back(to_be_sent_fragment);
 
         Ok(())
@@ -94,7 +137,100 @@ impl FragmentManager {
     /// # Arguments
     ///
     /// * `fragment_id` - The ID of the fragment to remove.
-    pub fn remove_from_cache(&mut self, fragment_id: FragmentId) {
-        self.cache.remove(&fragment_id);
+    ///
+    /// # Returns
+    ///
+    /// - `Some(NodeId)` with the destination of the session if this was its last
+    ///   unacknowledged fragment.
+    /// - `None` otherwise, or if the fragment was not in the cache.
+    pub fn remove_from_cache(&mut self, fragment_id: FragmentId) -> Option<NodeId> {
+        let removed = self.cache.remove(&fragment_id)?;
+        self.in_flight.remove(&fragment_id);
+
+        let session_id = fragment_id.0;
+        let remaining = self.unacknowledged.get_mut(&session_id)?;
+        *remaining -= 1;
+
+        if *remaining == 0 {
+            self.unacknowledged.remove(&session_id);
+            Some(removed.dest)
+        } else {
+            None
+        }
+    }
+
+    /// Forgets every fragment of a session, whether queued or waiting for an acknowledgment.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The session whose fragments must be forgotten.
+    ///
+    /// # Returns
+    ///
+    /// The number of fragments removed from the cache.
+    pub fn evict_session(&mut self, session_id: SessionId) -> usize {
+        let cached = self.cache.len();
+        self.cache.retain(|(session, _), _| *session != session_id);
+        self.in_flight
+            .retain(|(session, _), _| *session != session_id);
+        self.buffer
+            .retain(|fragment| fragment.session_id != session_id);
+        self.unacknowledged.remove(&session_id);
+        cached - self.cache.len()
+    }
+
+    /// Records that a fragment has been sent through the given neighbor.
+    ///
+    /// # Arguments
+    ///
+    /// * `fragment_id` - The ID of the sent fragment.
+    /// * `next_hop` - The neighbor the fragment was handed to.
+    pub fn mark_in_flight(&mut self, fragment_id: FragmentId, next_hop: NodeId) {
+        if self.cache.contains_key(&fragment_id) {
+            self.in_flight.insert(fragment_id, next_hop);
+        }
+    }
+
+    /// Re-inserts into the buffer every in-flight fragment that was sent through a neighbor.
+    ///
+    /// # Arguments
+    ///
+    /// * `next_hop` - The neighbor whose in-flight fragments must be requeued.
+    ///
+    /// # Returns
+    ///
+    /// The IDs of the requeued fragments.
+    pub fn requeue_in_flight_through(&mut self, next_hop: NodeId) -> Vec<FragmentId> {
+        let mut affected: Vec<FragmentId> = self
+            .in_flight
+            .iter()
+            .filter(|(_, hop)| **hop == next_hop)
+            .map(|(fragment_id, _)| *fragment_id)
+            .collect();
+        affected.sort();
+
+        for fragment_id in affected.iter() {
+            self.in_flight.remove(fragment_id);
+            if let Some(fragment) = self.cache.get(fragment_id) {
+                self.buffer.push_back(fragment.clone());
+            }
+        }
+
+        affected
+    }
+
+    /// Returns the number of fragments that have not been acknowledged yet.
+    pub fn pending_count(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Checks whether some fragments are waiting to be sent.
+    pub fn has_queued(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    /// Checks whether every queued fragment has been sent and acknowledged.
+    pub fn is_idle(&self) -> bool {
+        self.cache.is_empty() && self.buffer.is_empty()
     }
 }
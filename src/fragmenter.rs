@@ -1,18 +1,43 @@
 //! Provides functionality to fragment large messages into smaller fragment for network transmission.
 
+use crate::fec::{self, Redundancy};
+use crate::integrity;
 use crate::{fragment_manager::ToBeSentFragment, specialized_behavior::AssembledResponse};
 use rust_roveri_api::SessionId;
 use wg_2024::packet::{Fragment, FRAGMENT_DSIZE};
 
+/// The `Fragmenter` takes an `AssembledResponse`, splits its data into smaller chunks and attach
+/// the session id that is incremented after each fragmentation
+#[derive(Debug)]
 pub struct Fragmenter {
     session_id: SessionId,
+    integrity: bool,
 }
 
-/// The `Fragmenter` takes an `AssembledResponse`, splits its data into smaller chunks and attach
-/// the session id that is incremented after each fragmentation
 impl Fragmenter {
+    /// Creates a fragmenter whose first message uses session id 1.
     pub fn new() -> Self {
-        Self { session_id: 1 }
+        Self::starting_at(1)
+    }
+
+    /// Creates a fragmenter whose first message uses the given session id.
+    ///
+    /// Nodes that share a network should start from different session ids, so that the
+    /// sessions they open do not collide.
+    pub fn starting_at(session_id: SessionId) -> Self {
+        Self {
+            session_id,
+            integrity: false,
+        }
+    }
+
+    /// Enables or disables the integrity trailer appended to every message.
+    ///
+    /// The receiver verifies the trailer when it reassembles the message, so that corrupted or
+    /// mixed-up fragments are detected instead of being deserialized. Receivers that do not
+    /// know about it would read it as part of the message, so it is disabled by default.
+    pub fn set_integrity(&mut self, enabled: bool) {
+        self.integrity = enabled;
     }
 
     /// Splits an `AssembledResponse` into a vector of fragments.
@@ -31,14 +56,32 @@ impl Fragmenter {
     /// # Behavior
     ///
     /// - If the data in the `AssembledResponse` is empty, no fragments are created.
+    /// - If the integrity trailer is enabled, it is appended to the data before splitting it.
     /// - The session ID is incremented after fragmenting the data.
     pub fn to_fragment_vec(
         &mut self,
         assembled_response: AssembledResponse,
     ) -> Vec<ToBeSentFragment> {
-        let data = assembled_response.data;
+        self.to_fragment_vec_with_parity(assembled_response, Redundancy::NONE)
+    }
+
+    /// Splits an `AssembledResponse` into a vector of fragments protected by parity fragments.
+    ///
+    /// Works like `to_fragment_vec`, but every block of `redundancy.block_size` data fragments
+    /// is followed by `redundancy.parity` parity fragments, which let the receiver rebuild lost
+    /// data fragments without a retransmission. See the `fec` module.
+    pub fn to_fragment_vec_with_parity(
+        &mut self,
+        assembled_response: AssembledResponse,
+        redundancy: Redundancy,
+    ) -> Vec<ToBeSentFragment> {
+        let mut data = assembled_response.data;
         let dest = assembled_response.dest;
 
+        if self.integrity && !data.is_empty() {
+            integrity::append_trailer(&mut data);
+        }
+
         // Number of fragments
         let total_fragments = if data.is_empty() {
             0
@@ -46,7 +89,7 @@ impl Fragmenter {
             (data.len() + FRAGMENT_DSIZE - 1) / FRAGMENT_DSIZE
         };
 
-        let mut fragments = Vec::with_capacity(total_fragments);
+        let mut data_fragments = Vec::with_capacity(total_fragments);
 
         for i in 0..total_fragments {
             let start = i * FRAGMENT_DSIZE;
@@ -56,24 +99,31 @@ impl Fragmenter {
             let mut fragment_data = [0u8; FRAGMENT_DSIZE];
             fragment_data[..slice.len()].copy_from_slice(slice);
 
-            let fragment = Fragment {
+            data_fragments.push(Fragment {
                 fragment_index: i as u64,
                 total_n_fragments: total_fragments as u64,
                 length: slice.len() as u8,
                 data: fragment_data,
-            };
+            });
+        }
 
-            let to_be_sent_fragment = ToBeSentFragment {
+        let fragments = fec::protect(data_fragments, redundancy)
+            .into_iter()
+            .map(|fragment| ToBeSentFragment {
                 dest,
                 session_id: self.session_id,
                 fragment,
-            };
-
-            fragments.push(to_be_sent_fragment);
-        }
+            })
+            .collect();
 
         self.session_id += 1;
 
         fragments
     }
 }
+
+impl Default for Fragmenter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,296 @@
+//! Keeps the message history of chat conversations.
+//!
+//! Every conversation between two users keeps its most recent messages, up to a limit. Clients
+//! read it through the history envelope, see the `envelope` module, which holds a serialized
+//! `HistoryRequest` or `HistoryResponse`. History requests are sent with `encode(&request)`
+//! where a serialized `Request` would be, and responses read with `decode`.
+
+use crate::envelope;
+use postcard::{from_bytes, to_allocvec};
+use rust_roveri_api::UserName;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// First byte of a history message.
+const MARKER: u8 = envelope::HISTORY;
+
+/// Maximum number of messages in a page or in the results of a search.
+pub const MAX_PAGE_LEN: usize = 50;
+
+/// Number of messages kept per conversation, unless set otherwise.
+const DEFAULT_LIMIT: usize = 1000;
+
+/// A message of a conversation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// Increases with every message the server delivers, so it orders and identifies them.
+    pub id: u64,
+    /// Milliseconds since the Unix epoch.
+    pub time: u64,
+    pub sender: UserName,
+    pub recipient: UserName,
+    pub message: String,
+}
+
+/// A history operation of a logged in client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryRequest {
+    /// Asks for a page of the conversation with the second user: the messages before the given
+    /// id, or the most recent ones, up to the given number.
+    History(UserName, UserName, Option<u64>, u16),
+    /// Asks for the most recent messages the user sent or received containing the given text,
+    /// ignoring case.
+    Search(UserName, String),
+}
+
+/// Why a history operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryError {
+    NotLogged,
+    EmptyQuery,
+}
+
+/// The answer to a `HistoryRequest`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HistoryResponse {
+    /// A page of the conversation with the given user, oldest first, and whether older
+    /// messages are left.
+    History(UserName, Vec<StoredMessage>, bool),
+    /// The messages found, oldest first.
+    SearchResults(UserName, Vec<StoredMessage>),
+    Failure(UserName, HistoryError),
+}
+
+/// Checks whether a payload is a history message.
+pub fn is_history(data: &[u8]) -> bool {
+    data.first() == Some(&MARKER)
+}
+
+/// Serializes a history message in its envelope.
+pub fn encode<T: Serialize>(message: &T) -> Result<Vec<u8>, postcard::Error> {
+    let mut encoded = vec![MARKER];
+    encoded.extend(to_allocvec(message)?);
+    Ok(encoded)
+}
+
+/// Deserializes a history message.
+///
+/// # Returns
+///
+/// - `Ok(Some(T))` with the message if the payload is a history message.
+/// - `Ok(None)` if it is not.
+/// - `Err(postcard::Error)` if the message is invalid.
+pub fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<Option<T>, postcard::Error> {
+    match data.split_first() {
+        Some((&MARKER, message)) => from_bytes(message).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// The conversations of a chat server.
+#[derive(Debug)]
+pub(crate) struct History {
+    /// Keyed by the two users, in order.
+    conversations: HashMap<(UserName, UserName), VecDeque<StoredMessage>>,
+    limit: usize,
+    next_id: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            conversations: HashMap::new(),
+            limit: DEFAULT_LIMIT,
+            next_id: 0,
+        }
+    }
+}
+
+fn key(first: &UserName, second: &UserName) -> (UserName, UserName) {
+    if first <= second {
+        (first.clone(), second.clone())
+    } else {
+        (second.clone(), first.clone())
+    }
+}
+
+impl History {
+    /// Sets how many messages every conversation keeps, dropping the oldest ones beyond it.
+    pub(crate) fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        for messages in self.conversations.values_mut() {
+            let excess = messages.len().saturating_sub(limit);
+            messages.drain(..excess);
+        }
+        self.conversations
+            .retain(|_, messages| !messages.is_empty());
+    }
+
+    /// Records a delivered message.
+    pub(crate) fn record(&mut self, sender: &UserName, recipient: &UserName, message: String) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.insert(StoredMessage {
+            id: self.next_id,
+            time,
+            sender: sender.clone(),
+            recipient: recipient.clone(),
+            message,
+        });
+    }
+
+    fn insert(&mut self, message: StoredMessage) {
+        if self.limit == 0 {
+            return;
+        }
+
+        self.next_id = self.next_id.max(message.id + 1);
+        let messages = self
+            .conversations
+            .entry(key(&message.sender, &message.recipient))
+            .or_default();
+        if messages.len() == self.limit {
+            messages.pop_front();
+        }
+        messages.push_back(message);
+    }
+
+    /// Returns up to `len` messages between `first` and `second` older than `before`, or the
+    /// most recent ones, oldest first, and whether older messages are left.
+    pub(crate) fn page(
+        &self,
+        first: &UserName,
+        second: &UserName,
+        before: Option<u64>,
+        len: usize,
+    ) -> (Vec<StoredMessage>, bool) {
+        let Some(messages) = self.conversations.get(&key(first, second)) else {
+            return (Vec::new(), false);
+        };
+
+        let end = match before {
+            Some(before) => messages.partition_point(|message| message.id < before),
+            None => messages.len(),
+        };
+        let start = end.saturating_sub(len);
+        (messages.range(start..end).cloned().collect(), start > 0)
+    }
+
+    /// Returns up to `len` of the most recent messages sent or received by `username` that
+    /// contain `query`, ignoring case, oldest first.
+    pub(crate) fn search(
+        &self,
+        username: &UserName,
+        query: &str,
+        len: usize,
+    ) -> Vec<StoredMessage> {
+        let query = query.to_lowercase();
+        let mut found: Vec<StoredMessage> = self
+            .conversations
+            .iter()
+            .filter(|((first, second), _)| first == username || second == username)
+            .flat_map(|(_, messages)| messages.iter())
+            .filter(|message| message.message.to_lowercase().contains(&query))
+            .cloned()
+            .collect();
+
+        found.sort_by_key(|message| message.id);
+        let excess = found.len().saturating_sub(len);
+        found.drain(..excess);
+        found
+    }
+
+    /// Forgets the conversations of a deleted account.
+    pub(crate) fn forget(&mut self, username: &UserName) {
+        self.conversations
+            .retain(|(first, second), _| first != username && second != username);
+    }
+
+    /// Serializes every message, to be loaded back with `load`.
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>, postcard::Error> {
+        let messages: Vec<&StoredMessage> = self.conversations.values().flatten().collect();
+        to_allocvec(&messages)
+    }
+
+    /// Adds the messages serialized by `to_bytes`.
+    pub(crate) fn load(&mut self, bytes: &[u8]) -> Result<(), postcard::Error> {
+        let mut messages = from_bytes::<Vec<StoredMessage>>(bytes)?;
+        messages.sort_by_key(|message| message.id);
+        for message in messages {
+            self.insert(message);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history() -> History {
+        let mut history = History::default();
+        let (ciao, cane) = ("ciao".to_string(), "cane".to_string());
+        for i in 0..10 {
+            history.record(&ciao, &cane, format!("Message {}", i));
+            history.record(&cane, &"gatto".to_string(), format!("Other {}", i));
+        }
+        history
+    }
+
+    #[test]
+    fn test_pages() {
+        let history = history();
+        let (ciao, cane) = ("ciao".to_string(), "cane".to_string());
+
+        let (page, more) = history.page(&cane, &ciao, None, 4);
+        assert!(more);
+        let texts: Vec<&str> = page.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(texts, ["Message 6", "Message 7", "Message 8", "Message 9"]);
+
+        let (page, more) = history.page(&ciao, &cane, Some(page[0].id), 10);
+        assert!(!more);
+        assert_eq!(page.len(), 6);
+        assert_eq!(page[5].message, "Message 5");
+
+        assert!(history
+            .page(&ciao, &"gatto".to_string(), None, 10)
+            .0
+            .is_empty());
+    }
+
+    #[test]
+    fn test_search_and_limit() {
+        let mut history = history();
+        let ciao = "ciao".to_string();
+
+        let found = history.search(&ciao, "MESSAGE", 3);
+        let texts: Vec<&str> = found.iter().map(|m| m.message.as_str()).collect();
+        assert_eq!(texts, ["Message 7", "Message 8", "Message 9"]);
+        assert!(history.search(&ciao, "other", 10).is_empty());
+
+        history.set_limit(2);
+        assert_eq!(history.search(&ciao, "message", 10).len(), 2);
+
+        history.forget(&ciao);
+        assert!(history.search(&ciao, "message", 10).is_empty());
+        assert_eq!(history.search(&"gatto".to_string(), "other", 10).len(), 2);
+    }
+
+    #[test]
+    fn test_load() {
+        let history = history();
+        let mut restored = History::default();
+        restored.load(&history.to_bytes().unwrap()).unwrap();
+
+        let ciao = "ciao".to_string();
+        assert_eq!(
+            restored.search(&ciao, "", MAX_PAGE_LEN),
+            history.search(&ciao, "", MAX_PAGE_LEN)
+        );
+        restored.record(&ciao, &"cane".to_string(), "New".to_string());
+        assert_eq!(restored.search(&ciao, "new", 1)[0].id, 20);
+    }
+}
@@ -0,0 +1,160 @@
+//! Implements the optional integrity trailer of assembled messages.
+//!
+//! When enabled on the `Fragmenter`, a message is followed by its length, its CRC32 and a magic
+//! number, all little-endian `u32`s.
+//!
+//! A receiver that requires the trailer rejects every message without a valid one, so any
+//! flipped bit is detected, the magic number included. Otherwise it recognizes the trailer by
+//! the magic number, so that messages sent without it, e.g. by nodes that do not support it, are
+//! still accepted: a corrupted magic number then makes the message look like one sent without a
+//! trailer, which is only caught when the message is deserialized.
+
+use std::fmt;
+
+/// Marks the end of a message followed by an integrity trailer.
+///
+/// The bytes are not valid UTF-8, so the trailer is not mistaken for the end of a string.
+const MAGIC: [u8; 4] = [0xC3, 0x5A, 0x17, 0xE9];
+
+/// Length of the trailer: payload length, checksum and magic number.
+const TRAILER_LEN: usize = 12;
+
+/// Errors that can occur while verifying the integrity trailer of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The message is shorter or longer than the length written in its trailer.
+    LengthMismatch,
+    /// The checksum of the message does not match the one written in its trailer.
+    ChecksumMismatch,
+    /// The message has no trailer, but the receiver requires one.
+    MissingTrailer,
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::LengthMismatch => write!(f, "length mismatch"),
+            IntegrityError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            IntegrityError::MissingTrailer => write!(f, "missing integrity trailer"),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Computes the CRC32 (IEEE) of some data.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+/// Appends the integrity trailer to a message.
+pub(crate) fn append_trailer(data: &mut Vec<u8>) {
+    let length = data.len() as u32;
+    let checksum = crc32(data);
+
+    data.extend_from_slice(&length.to_le_bytes());
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data.extend_from_slice(&MAGIC);
+}
+
+/// Verifies and removes the integrity trailer of a message.
+///
+/// # Arguments
+///
+/// * `data` - The message.
+/// * `required` - Whether messages without a trailer are rejected.
+///
+/// # Returns
+///
+/// - `Ok(Vec<u8>)` with the message without its trailer, or the message unchanged if it has no
+///   trailer and none is required.
+/// - `Err(IntegrityError::MissingTrailer)` if it has no trailer and one is required.
+/// - `Err(IntegrityError)` if the message does not match its trailer.
+pub(crate) fn strip_trailer(mut data: Vec<u8>, required: bool) -> Result<Vec<u8>, IntegrityError> {
+    if !data.ends_with(&MAGIC) {
+        return if required {
+            Err(IntegrityError::MissingTrailer)
+        } else {
+            Ok(data)
+        };
+    }
+    if data.len() < TRAILER_LEN {
+        return Err(IntegrityError::LengthMismatch);
+    }
+
+    let trailer = data.split_off(data.len() - TRAILER_LEN);
+    let length = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let checksum = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+
+    if length as usize != data.len() {
+        return Err(IntegrityError::LengthMismatch);
+    }
+    if crc32(&data) != checksum {
+        return Err(IntegrityError::ChecksumMismatch);
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_trailer_roundtrip() {
+        let message = b"ciao cane".to_vec();
+        let mut data = message.clone();
+        append_trailer(&mut data);
+
+        assert_eq!(data.len(), message.len() + TRAILER_LEN);
+        assert_eq!(strip_trailer(data.clone(), false), Ok(message.clone()));
+        assert_eq!(strip_trailer(data, true), Ok(message.clone()));
+        // Messages without a trailer are accepted as they are, unless one is required
+        assert_eq!(strip_trailer(message.clone(), false), Ok(message.clone()));
+        assert_eq!(
+            strip_trailer(message, true),
+            Err(IntegrityError::MissingTrailer)
+        );
+    }
+
+    #[test]
+    fn test_flipped_bits() {
+        let mut data = b"ciao cane".to_vec();
+        append_trailer(&mut data);
+
+        for bit in 0..data.len() * 8 {
+            let mut corrupted = data.clone();
+            corrupted[bit / 8] ^= 1 << (bit % 8);
+            assert!(
+                strip_trailer(corrupted, true).is_err(),
+                "Bit {} not detected",
+                bit
+            );
+        }
+
+        // Without requiring the trailer, a flipped bit of the magic number goes unnoticed
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(strip_trailer(corrupted.clone(), false), Ok(corrupted));
+
+        let mut truncated = data.clone();
+        truncated.drain(..2);
+        assert_eq!(
+            strip_trailer(truncated, false),
+            Err(IntegrityError::LengthMismatch)
+        );
+    }
+}
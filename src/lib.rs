@@ -1,12 +1,110 @@
+//! A server node of the drone network, together with the building blocks it is made of.
+//!
+//! Besides `Server`, the crate exports the pieces every node of the network needs, so that
+//! clients and other node types can share the same logic:
+//!
+//! - `Fragmenter` splits serialized messages into fragments, and `FragmentManager` keeps them
+//!   until they are acknowledged.
+//! - `Assembler` and `AssemblersManager` put the fragments of incoming messages back together,
+//!   rebuilding lost ones from the parity fragments a `fec::Redundancy` adds, if any.
+//! - `Topology` learns the network from flood responses and source routing headers, and
+//!   computes the routes.
+//! - `ChatBehavior`, `TextBehavior` and `MediaBehavior` implement `SpecializedBehavior`, the
+//!   request handling of each server type.
+//!
+//! The envelopes clients wrap their messages in, and the server features with their own
+//! vocabulary, are reached through their modules instead: `account`, `history`, `compression`,
+//! `fec` and `token` for the envelopes, whose markers and order `envelope` describes, `capture`
+//! and `metrics` for the rest.
+//!
+//! ```
+//! use rust_roveri_api::{ChatRequest, Request};
+//! use server::{AssembledResponse, AssemblerStatus, AssemblersManager, Fragmenter, RequestKind};
+//!
+//! let request = Request::Chat(ChatRequest::Register("ciao".to_string(), "cane".to_string()));
+//! let data = postcard::to_allocvec(&request).unwrap();
+//!
+//! let mut fragmenter = Fragmenter::starting_at(100);
+//! let fragments = fragmenter.to_fragment_vec(AssembledResponse {
+//!     data: data.clone(),
+//!     dest: 30,
+//!     kind: RequestKind::from(&request),
+//! });
+//!
+//! let mut assemblers_manager = AssemblersManager::new();
+//! let mut status = AssemblerStatus::Incomplete;
+//! for to_be_sent in fragments {
+//!     status = assemblers_manager
+//!         .insert_fragment(to_be_sent.fragment, to_be_sent.session_id)
+//!         .unwrap();
+//! }
+//! assert_eq!(status, AssemblerStatus::Complete);
+//! assert_eq!(assemblers_manager.retrieve_assembled(100).unwrap(), data);
+//! ```
+
 mod assemblers_manager;
+pub mod account;
+#[cfg(feature = "async")]
+mod async_server;
 mod chat_behavior;
+mod clock;
+pub mod compression;
+mod control;
+pub mod envelope;
+mod error;
+pub mod fec;
+pub mod history;
 mod assembler;
+mod audit;
+pub mod capture;
 mod fragment_manager;
+mod integrity;
+mod login_guard;
 mod media_behavior;
+mod password;
+pub mod metrics;
+mod rate_limiter;
+mod replay_cache;
 mod server;
+#[cfg(test)]
+mod simulator;
 mod specialized_behavior;
 mod text_behavior;
+pub mod token;
 mod topology;
+mod transport;
 mod fragmenter;
 
-pub use server::Server;
+pub use assembler::{Assembler, AssemblerStatus, InsertFragmentError, RetrieveError};
+pub use assemblers_manager::AssemblersManager;
+#[cfg(feature = "async")]
+pub use async_server::AsyncServer;
+pub use audit::{AuditRecord, AuthEvent};
+pub use chat_behavior::ChatBehavior;
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use control::{
+    ControlCommand, ControlEvent, EventFilter, EventKind, RetransmissionReason, ShutdownSummary,
+};
+pub use error::Error;
+pub use fragment_manager::{FragmentManager, ToBeSentFragment};
+pub use fragmenter::Fragmenter;
+pub use integrity::IntegrityError;
+pub use login_guard::LoginPolicy;
+pub use media_behavior::MediaBehavior;
+pub use rate_limiter::{RateLimit, RateLimits};
+pub use server::{Server, StepOutcome, Wake};
+pub use specialized_behavior::{
+    AssembledResponse, ProcessError, RequestKind, SetPathError, SpecializedBehavior,
+};
+pub use text_behavior::TextBehavior;
+pub use topology::{NodeSnapshot, RoutingError, Topology, TopologySnapshot};
+pub use transport::{
+    read_packet, write_packet, Endpoint, PacketListener, SocketTransport, Transport,
+    TransportError, MAX_FRAME_LEN,
+};
+
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench {
+    pub use crate::topology::{dense::DenseTopology, Topology};
+}
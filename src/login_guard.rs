@@ -0,0 +1,252 @@
+//! Protects the chat accounts against password guessing.
+//!
+//! Failed logins are counted both per username and per node. Once one of them fails
+//! `LoginPolicy::max_failures` times within `LoginPolicy::window`, it is locked out: its
+//! following attempts are rejected without checking the password. Every new lockout of the
+//! same username or node lasts twice as long as the previous one, up to
+//! `LoginPolicy::max_lockout`.
+
+use rust_roveri_api::UserName;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+
+/// When failed logins lock a username or a node out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoginPolicy {
+    /// Failed attempts that trigger a lockout.
+    pub max_failures: u32,
+    /// Period over which failed attempts are counted.
+    pub window: Duration,
+    /// Duration of the first lockout.
+    pub lockout: Duration,
+    /// Upper bound to the duration of a lockout.
+    pub max_lockout: Duration,
+}
+
+impl Default for LoginPolicy {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window: Duration::from_secs(5 * 60),
+            lockout: Duration::from_secs(30),
+            max_lockout: Duration::from_secs(15 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    User(UserName),
+    Node(NodeId),
+}
+
+#[derive(Debug)]
+struct Attempts {
+    failures: u32,
+    window_start: Instant,
+    locked_until: Option<Instant>,
+    lockouts: u32,
+}
+
+impl Attempts {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            window_start: now,
+            locked_until: None,
+            lockouts: 0,
+        }
+    }
+
+    fn remaining_lockout(&self, now: Instant) -> Option<Duration> {
+        self.locked_until
+            .and_then(|until| until.checked_duration_since(now))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Checks whether the entry can be forgotten: it is not locked out and its failures are
+    /// outside the window. Lockouts are remembered for a window after they end, so that the
+    /// backoff keeps growing for attackers that wait for them to expire.
+    fn is_stale(&self, policy: &LoginPolicy, now: Instant) -> bool {
+        let last_activity = self.locked_until.unwrap_or(self.window_start);
+        now.saturating_duration_since(last_activity) > policy.window
+    }
+}
+
+/// Tracks failed logins and the resulting lockouts.
+#[derive(Debug, Default)]
+pub(crate) struct LoginGuard {
+    policy: LoginPolicy,
+    attempts: HashMap<Key, Attempts>,
+}
+
+impl LoginGuard {
+    pub(crate) fn set_policy(&mut self, policy: LoginPolicy) {
+        self.policy = policy;
+    }
+
+    /// Checks whether `username` may try to log in from `node_id` at `now`.
+    ///
+    /// # Returns
+    ///
+    /// - `Ok(())` if neither of them is locked out.
+    /// - `Err(Duration)` with the time left before the longest of their lockouts expires.
+    pub(crate) fn check(
+        &self,
+        username: &UserName,
+        node_id: NodeId,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let remaining = [Key::User(username.clone()), Key::Node(node_id)]
+            .iter()
+            .filter_map(|key| self.attempts.get(key))
+            .filter_map(|attempts| attempts.remaining_lockout(now))
+            .max();
+
+        match remaining {
+            Some(remaining) => Err(remaining),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a failed login from `node_id` at `now`, against `username` too if it is a
+    /// registered account.
+    ///
+    /// # Returns
+    ///
+    /// The duration of the lockout the failure started, if any.
+    pub(crate) fn record_failure(
+        &mut self,
+        username: Option<&UserName>,
+        node_id: NodeId,
+        now: Instant,
+    ) -> Option<Duration> {
+        self.remove_stale(now);
+
+        let mut keys = vec![Key::Node(node_id)];
+        keys.extend(username.map(|username| Key::User(username.clone())));
+
+        let policy = self.policy;
+        let mut started = None;
+        for key in keys {
+            let attempts = self
+                .attempts
+                .entry(key)
+                .or_insert_with(|| Attempts::new(now));
+
+            if now.saturating_duration_since(attempts.window_start) > policy.window {
+                attempts.failures = 0;
+                attempts.window_start = now;
+            }
+            attempts.failures += 1;
+
+            if attempts.failures >= policy.max_failures {
+                let lockout = policy
+                    .lockout
+                    .saturating_mul(2u32.saturating_pow(attempts.lockouts))
+                    .min(policy.max_lockout);
+
+                attempts.failures = 0;
+                attempts.window_start = now;
+                attempts.locked_until = Some(now + lockout);
+                attempts.lockouts += 1;
+                started = started.max(Some(lockout));
+            }
+        }
+
+        started
+    }
+
+    /// Records a successful login, which clears the failures of the username.
+    ///
+    /// The failures of the node are kept, so that logging in to an account does not give more
+    /// attempts against the others.
+    pub(crate) fn record_success(&mut self, username: &UserName) {
+        self.attempts.remove(&Key::User(username.clone()));
+    }
+
+    /// Forgets the failures and the lockouts of a deleted account.
+    pub(crate) fn forget(&mut self, username: &UserName) {
+        self.attempts.remove(&Key::User(username.clone()));
+    }
+
+    fn remove_stale(&mut self, now: Instant) {
+        let policy = self.policy;
+        self.attempts
+            .retain(|_, attempts| !attempts.is_stale(&policy, now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> LoginPolicy {
+        LoginPolicy {
+            max_failures: 3,
+            window: Duration::from_secs(60),
+            lockout: Duration::from_millis(50),
+            max_lockout: Duration::from_millis(150),
+        }
+    }
+
+    #[test]
+    fn test_lockout_and_backoff() {
+        let mut guard = LoginGuard::default();
+        guard.set_policy(policy());
+        let username = "ciao".to_string();
+        let mut now = Instant::now();
+
+        assert_eq!(guard.record_failure(Some(&username), 1, now), None);
+        assert_eq!(guard.record_failure(Some(&username), 2, now), None);
+        assert!(guard.check(&username, 3, now).is_ok());
+        assert_eq!(
+            guard.record_failure(Some(&username), 3, now),
+            Some(Duration::from_millis(50))
+        );
+
+        // The username is locked out from every node, while the nodes are not
+        assert!(guard.check(&username, 4, now).is_err());
+        assert!(guard.check(&"cane".to_string(), 1, now).is_ok());
+
+        now += Duration::from_millis(80);
+        assert!(guard.check(&username, 4, now).is_ok());
+
+        // The next lockouts last longer, up to the maximum
+        for _ in 0..2 {
+            guard.record_failure(Some(&username), 5, now);
+        }
+        assert_eq!(
+            guard.record_failure(Some(&username), 5, now),
+            Some(Duration::from_millis(100))
+        );
+        now += Duration::from_millis(120);
+        for _ in 0..2 {
+            guard.record_failure(Some(&username), 6, now);
+        }
+        assert_eq!(
+            guard.record_failure(Some(&username), 6, now),
+            Some(Duration::from_millis(150))
+        );
+    }
+
+    #[test]
+    fn test_node_lockout() {
+        let mut guard = LoginGuard::default();
+        guard.set_policy(policy());
+        let now = Instant::now();
+
+        // A node trying many accounts is locked out, whatever the username
+        guard.record_failure(None, 1, now);
+        guard.record_failure(Some(&"ciao".to_string()), 1, now);
+        assert!(guard.record_failure(None, 1, now).is_some());
+        assert!(guard.check(&"cane".to_string(), 1, now).is_err());
+        assert!(guard.check(&"cane".to_string(), 2, now).is_ok());
+
+        // A successful login clears the username, but not the node
+        guard.record_success(&"ciao".to_string());
+        assert!(guard.check(&"ciao".to_string(), 2, now).is_ok());
+        assert!(guard.check(&"ciao".to_string(), 1, now).is_err());
+    }
+}
@@ -11,11 +11,13 @@ use wg_2024::network::NodeId;
 ///
 /// The `MediaBehavior` struct implements the `SpecializedBehavior` trait to handle
 /// requests for media content stored in a specified directory.
+#[derive(Debug, Default)]
 pub struct MediaBehavior {
     path: PathBuf,
 }
 
 impl MediaBehavior {
+    /// Creates a behavior with no content directory, set it with `set_path`.
     pub fn new() -> Self {
         Self {
             path: PathBuf::new(),
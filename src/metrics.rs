@@ -0,0 +1,249 @@
+//! Implements the `Metrics` registry of counters and latency histograms.
+
+use std::{collections::BTreeMap, fmt::Write, fs, io, path::Path, time::Duration};
+
+pub const FRAGMENTS_RECEIVED: &str = "server_fragments_received_total";
+pub const FRAGMENTS_SENT: &str = "server_fragments_sent_total";
+pub const FRAGMENTS_RETRANSMITTED: &str = "server_fragments_retransmitted_total";
+pub const PARITY_FRAGMENTS_SENT: &str = "server_parity_fragments_sent_total";
+pub const ACKS_RECEIVED: &str = "server_acks_received_total";
+pub const NACKS_RECEIVED: &str = "server_nacks_received_total";
+pub const ROUTES_NOT_FOUND: &str = "server_routes_not_found_total";
+pub const REQUESTS: &str = "server_requests_total";
+pub const REQUEST_ERRORS: &str = "server_request_errors_total";
+pub const REQUESTS_REPLAYED: &str = "server_requests_replayed_total";
+pub const REQUESTS_THROTTLED: &str = "server_requests_throttled_total";
+pub const RESPONSES_ACKNOWLEDGED: &str = "server_responses_acknowledged_total";
+pub const RESPONSES_COMPRESSED: &str = "server_responses_compressed_total";
+pub const NOTIFICATIONS_SENT: &str = "server_notifications_sent_total";
+pub const REQUEST_LATENCY: &str = "server_request_latency_seconds";
+pub const END_TO_END_LATENCY: &str = "server_end_to_end_latency_seconds";
+pub const ERRORS: &str = "server_errors_total";
+
+/// Upper bounds, in seconds, of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Identifies a metric by name and an optional label.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MetricKey {
+    pub name: &'static str,
+    pub label: Option<(&'static str, String)>,
+}
+
+impl MetricKey {
+    pub fn new(name: &'static str) -> Self {
+        Self { name, label: None }
+    }
+
+    pub fn labeled(name: &'static str, label: &'static str, value: impl Into<String>) -> Self {
+        Self {
+            name,
+            label: Some((label, value.into())),
+        }
+    }
+
+    /// Formats the label set, adding an extra label if given.
+    fn labels(&self, extra: Option<(&str, &str)>) -> String {
+        let labels: Vec<String> = self
+            .label
+            .iter()
+            .map(|(name, value)| (*name, value.as_str()))
+            .chain(extra)
+            .map(|(name, value)| format!("{}=\"{}\"", name, value))
+            .collect();
+
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+}
+
+/// A latency histogram with fixed buckets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Self {
+            buckets: [0; LATENCY_BUCKETS.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    fn observe(&mut self, value: Duration) {
+        let seconds = value.as_secs_f64();
+
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    /// Returns the number of observed values.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sum of the observed values, in seconds.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+}
+
+/// Registry of the counters and latency histograms of a server.
+///
+/// Cloning the registry takes a snapshot of its current values.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    counters: BTreeMap<MetricKey, u64>,
+    histograms: BTreeMap<MetricKey, Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Increments a counter by one.
+    pub fn increment(&mut self, key: MetricKey) {
+        *self.counters.entry(key).or_insert(0) += 1;
+    }
+
+    /// Records a latency in a histogram.
+    pub fn observe(&mut self, key: MetricKey, value: Duration) {
+        self.histograms
+            .entry(key)
+            .or_insert_with(Histogram::new)
+            .observe(value);
+    }
+
+    /// Returns the value of a counter, `0` if it was never incremented.
+    pub fn counter(&self, key: &MetricKey) -> u64 {
+        self.counters.get(key).copied().unwrap_or(0)
+    }
+
+    /// Returns the sum of a counter over all of its labels.
+    pub fn counter_total(&self, name: &str) -> u64 {
+        self.counters
+            .iter()
+            .filter(|(key, _)| key.name == name)
+            .map(|(_, value)| value)
+            .sum()
+    }
+
+    /// Returns a histogram, if any value was recorded in it.
+    pub fn histogram(&self, key: &MetricKey) -> Option<&Histogram> {
+        self.histograms.get(key)
+    }
+
+    /// Formats the registry in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut res = String::new();
+        let mut last_name = None;
+
+        for (key, value) in self.counters.iter() {
+            if last_name != Some(key.name) {
+                let _ = writeln!(res, "# TYPE {} counter", key.name);
+                last_name = Some(key.name);
+            }
+            let _ = writeln!(res, "{}{} {}", key.name, key.labels(None), value);
+        }
+
+        for (key, histogram) in self.histograms.iter() {
+            if last_name != Some(key.name) {
+                let _ = writeln!(res, "# TYPE {} histogram", key.name);
+                last_name = Some(key.name);
+            }
+
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count;
+                let le = bound.to_string();
+                let labels = key.labels(Some(("le", &le)));
+                let _ = writeln!(res, "{}_bucket{} {}", key.name, labels, cumulative);
+            }
+            let labels = key.labels(Some(("le", "+Inf")));
+            let _ = writeln!(res, "{}_bucket{} {}", key.name, labels, histogram.count);
+            let _ = writeln!(
+                res,
+                "{}_sum{} {}",
+                key.name,
+                key.labels(None),
+                histogram.sum
+            );
+            let _ = writeln!(
+                res,
+                "{}_count{} {}",
+                key.name,
+                key.labels(None),
+                histogram.count
+            );
+        }
+
+        res
+    }
+
+    /// Writes the registry to a file in the Prometheus text exposition format.
+    pub fn export_prometheus(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_prometheus())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counters() {
+        let mut metrics = Metrics::new();
+        metrics.increment(MetricKey::new(FRAGMENTS_SENT));
+        metrics.increment(MetricKey::new(FRAGMENTS_SENT));
+        metrics.increment(MetricKey::labeled(REQUESTS, "kind", "login"));
+        metrics.increment(MetricKey::labeled(REQUESTS, "kind", "logout"));
+
+        assert_eq!(metrics.counter(&MetricKey::new(FRAGMENTS_SENT)), 2);
+        assert_eq!(metrics.counter(&MetricKey::new(ACKS_RECEIVED)), 0);
+        assert_eq!(
+            metrics.counter(&MetricKey::labeled(REQUESTS, "kind", "login")),
+            1
+        );
+        assert_eq!(metrics.counter_total(REQUESTS), 2);
+    }
+
+    #[test]
+    fn test_prometheus_format() {
+        let mut metrics = Metrics::new();
+        metrics.increment(MetricKey::new(FRAGMENTS_SENT));
+        metrics.increment(MetricKey::labeled(REQUESTS, "kind", "login"));
+        metrics.observe(
+            MetricKey::labeled(REQUEST_LATENCY, "kind", "login"),
+            Duration::from_millis(20),
+        );
+
+        let text = metrics.to_prometheus();
+        assert!(text.contains("# TYPE server_fragments_sent_total counter\n"));
+        assert!(text.contains("server_fragments_sent_total 1\n"));
+        assert!(text.contains("server_requests_total{kind=\"login\"} 1\n"));
+        assert!(text.contains("# TYPE server_request_latency_seconds histogram\n"));
+        assert!(
+            text.contains("server_request_latency_seconds_bucket{kind=\"login\",le=\"0.01\"} 0\n")
+        );
+        assert!(
+            text.contains("server_request_latency_seconds_bucket{kind=\"login\",le=\"0.025\"} 1\n")
+        );
+        assert!(
+            text.contains("server_request_latency_seconds_bucket{kind=\"login\",le=\"+Inf\"} 1\n")
+        );
+        assert!(text.contains("server_request_latency_seconds_count{kind=\"login\"} 1\n"));
+    }
+}
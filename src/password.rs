@@ -0,0 +1,86 @@
+//! Hashes the passwords of the chat accounts, so that they are never kept or saved in clear.
+//!
+//! Every password is hashed with PBKDF2-HMAC-SHA256 and its own random salt, so that equal
+//! passwords have different hashes and a leaked accounts file cannot be checked against a
+//! precomputed table.
+
+use pbkdf2::pbkdf2_hmac;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fmt;
+use std::io;
+
+/// Length of a salt, in bytes.
+const SALT_LEN: usize = 16;
+
+/// Length of a hash, in bytes.
+const HASH_LEN: usize = 32;
+
+/// PBKDF2 iterations. Tests use fewer, since they hash many passwords in debug builds.
+#[cfg(not(test))]
+const ROUNDS: u32 = 100_000;
+#[cfg(test)]
+const ROUNDS: u32 = 1_000;
+
+/// The salted hash of a password.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct PasswordHash {
+    salt: [u8; SALT_LEN],
+    hash: [u8; HASH_LEN],
+}
+
+impl PasswordHash {
+    /// Hashes a password with a new salt from the random number generator of the operating
+    /// system.
+    pub(crate) fn new(password: &str) -> io::Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt)?;
+        Ok(Self {
+            salt,
+            hash: hash(password, &salt),
+        })
+    }
+
+    /// Checks whether `password` is the hashed one.
+    ///
+    /// The hashes are compared in constant time, so that the time taken to reject a password
+    /// does not tell how much of its hash is right.
+    pub(crate) fn verify(&self, password: &str) -> bool {
+        hash(password, &self.salt)
+            .iter()
+            .zip(self.hash.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswordHash(..)")
+    }
+}
+
+fn hash(password: &str, salt: &[u8; SALT_LEN]) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, ROUNDS, &mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let hash = PasswordHash::new("cane").unwrap();
+        assert!(hash.verify("cane"));
+        assert!(!hash.verify("Cane"));
+        assert!(!hash.verify(""));
+
+        // The same password gets another salt, and so another hash
+        let other = PasswordHash::new("cane").unwrap();
+        assert_ne!(hash.salt, other.salt);
+        assert_ne!(hash.hash, other.hash);
+        assert!(other.verify("cane"));
+    }
+}
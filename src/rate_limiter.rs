@@ -0,0 +1,174 @@
+//! Limits how many requests each client can make and how many bytes it is served.
+//!
+//! Every initiator has two token buckets: one is refilled with requests per second, the other
+//! with bytes per second. A request is accepted only if a request token is available and the
+//! byte bucket is not empty. The size of a response is only known once it has been processed, so
+//! it is charged afterwards and may leave the byte bucket in debt, which blocks the following
+//! requests until it is paid back.
+
+use std::collections::HashMap;
+use std::time::Instant;
+use wg_2024::network::NodeId;
+
+/// A token bucket limit: `rate` tokens are added every second, up to `burst` tokens.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    /// Creates a limit of `rate` tokens per second, up to `burst` tokens.
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self { rate, burst }
+    }
+}
+
+/// The limits applied to every client. `None` means unlimited, which is the default.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RateLimits {
+    /// Requests per second.
+    pub requests: Option<RateLimit>,
+    /// Bytes of responses per second.
+    pub bytes: Option<RateLimit>,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = f64::min(self.tokens + elapsed * limit.rate, limit.burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    requests: TokenBucket,
+    bytes: TokenBucket,
+}
+
+/// Keeps the token buckets of every initiator.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    buckets: HashMap<NodeId, Buckets>,
+}
+
+impl RateLimiter {
+    /// Replaces the limits. Every client starts again with full buckets.
+    pub(crate) fn set_limits(&mut self, limits: RateLimits) {
+        self.limits = limits;
+        self.buckets.clear();
+    }
+
+    /// Checks whether a request of `initiator_id` received at `now` can be processed, taking a
+    /// request token if it can.
+    pub(crate) fn allow_request(&mut self, initiator_id: NodeId, now: Instant) -> bool {
+        if !self.allow_bytes(initiator_id, now) {
+            return false;
+        }
+
+        let limits = self.limits;
+        let buckets = self.buckets(initiator_id, now);
+        if let Some(limit) = limits.requests {
+            buckets.requests.refill(&limit, now);
+            if buckets.requests.tokens < 1.0 {
+                return false;
+            }
+            buckets.requests.tokens -= 1.0;
+        }
+
+        true
+    }
+
+    /// Checks whether `initiator_id` can be served more bytes at `now`, i.e. its byte bucket is
+    /// not empty, without taking a request token.
+    pub(crate) fn allow_bytes(&mut self, initiator_id: NodeId, now: Instant) -> bool {
+        let Some(limit) = self.limits.bytes else {
+            return true;
+        };
+
+        let buckets = self.buckets(initiator_id, now);
+        buckets.bytes.refill(&limit, now);
+        buckets.bytes.tokens > 0.0
+    }
+
+    /// Charges the bytes of a response served to `initiator_id` at `now`.
+    pub(crate) fn charge_bytes(&mut self, initiator_id: NodeId, bytes: usize, now: Instant) {
+        let Some(limit) = self.limits.bytes else {
+            return;
+        };
+
+        let buckets = self.buckets(initiator_id, now);
+        buckets.bytes.refill(&limit, now);
+        buckets.bytes.tokens -= bytes as f64;
+    }
+
+    fn buckets(&mut self, initiator_id: NodeId, now: Instant) -> &mut Buckets {
+        let limits = self.limits;
+        let unlimited = RateLimit::new(0.0, 0.0);
+
+        self.buckets.entry(initiator_id).or_insert_with(|| Buckets {
+            requests: TokenBucket::new(&limits.requests.unwrap_or(unlimited), now),
+            bytes: TokenBucket::new(&limits.bytes.unwrap_or(unlimited), now),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_request_limit() {
+        let mut rate_limiter = RateLimiter::default();
+        let now = Instant::now();
+        assert!((0..100).all(|_| rate_limiter.allow_request(1, now)));
+
+        rate_limiter.set_limits(RateLimits {
+            requests: Some(RateLimit::new(20.0, 2.0)),
+            bytes: None,
+        });
+        assert!(rate_limiter.allow_request(1, now));
+        assert!(rate_limiter.allow_request(1, now));
+        assert!(!rate_limiter.allow_request(1, now));
+        // Every client has its own buckets
+        assert!(rate_limiter.allow_request(2, now));
+
+        assert!(rate_limiter.allow_request(1, now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let mut rate_limiter = RateLimiter::default();
+        rate_limiter.set_limits(RateLimits {
+            requests: None,
+            bytes: Some(RateLimit::new(1000.0, 500.0)),
+        });
+
+        // A large response is served, but the client has to wait for the debt to be paid back
+        let now = Instant::now();
+        assert!(rate_limiter.allow_request(1, now));
+        rate_limiter.charge_bytes(1, 600, now);
+        assert!(!rate_limiter.allow_request(1, now + Duration::from_millis(50)));
+        assert!(!rate_limiter.allow_bytes(1, now + Duration::from_millis(50)));
+        assert!(rate_limiter.allow_request(2, now));
+
+        assert!(rate_limiter.allow_request(1, now + Duration::from_millis(200)));
+    }
+}
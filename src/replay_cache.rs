@@ -0,0 +1,257 @@
+//! Remembers the responses to recent requests, so that a request sent again by a client that
+//! never received the response is answered without being processed twice.
+
+use crate::integrity;
+use crate::specialized_behavior::{AssembledResponse, RequestKind};
+use rust_roveri_api::SessionId;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use wg_2024::network::NodeId;
+
+/// Number of responses remembered by default.
+pub(crate) const DEFAULT_MAX_ENTRIES: usize = 256;
+
+/// Total size, in bytes, of the responses remembered by default.
+pub(crate) const DEFAULT_MAX_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long a response is remembered by default.
+pub(crate) const DEFAULT_TTL: Duration = Duration::from_secs(60);
+
+/// How a request already answered is handled when it is sent again.
+#[derive(Debug, Clone)]
+pub(crate) enum Replay {
+    /// The response the initiator received the first time, to be sent again.
+    Response(AssembledResponse),
+    /// The response went to another node, e.g. a chat message to its recipient, so nothing is
+    /// sent again.
+    Forwarded(RequestKind),
+}
+
+impl Replay {
+    /// Returns the kind of the replayed request.
+    pub(crate) fn kind(&self) -> RequestKind {
+        match self {
+            Replay::Response(response) => response.kind,
+            Replay::Forwarded(kind) => *kind,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Replay::Response(response) => response.data.len(),
+            Replay::Forwarded(_) => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    digest: u32,
+    replay: Replay,
+    stored: Instant,
+}
+
+/// Caches the responses to recent requests, identified by their initiator and session id.
+///
+/// A request is replayed only if its content matches the one of the cached request, so that a
+/// client reusing a session id, e.g. after a restart, is not answered with a stale response.
+/// The cache keeps at most `max_entries` responses, `max_bytes` bytes in total, each for at
+/// most `ttl`: the oldest responses are forgotten first.
+///
+/// Responses sent to another node than the initiator are not kept, since sending them again
+/// would deliver them twice: only their request is remembered, so that it is not processed
+/// again either.
+#[derive(Debug)]
+pub(crate) struct ReplayCache {
+    entries: HashMap<(NodeId, SessionId), Entry>,
+    order: VecDeque<(NodeId, SessionId)>,
+    bytes: usize,
+    max_entries: usize,
+    max_bytes: usize,
+    ttl: Duration,
+}
+
+impl ReplayCache {
+    /// Creates an empty cache with the given limits. A cache with no entries is disabled.
+    pub(crate) fn new(max_entries: usize, max_bytes: usize, ttl: Duration) -> Self {
+        Self {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            bytes: 0,
+            max_entries,
+            max_bytes,
+            ttl,
+        }
+    }
+
+    /// Computes the digest a request is recognized by.
+    pub(crate) fn digest(request: &[u8]) -> u32 {
+        integrity::crc32(request)
+    }
+
+    /// Returns how to answer a request again, if the same request was answered within the
+    /// time to live before `now`.
+    pub(crate) fn get(
+        &mut self,
+        initiator_id: NodeId,
+        session_id: SessionId,
+        digest: u32,
+        now: Instant,
+    ) -> Option<Replay> {
+        self.remove_expired(now);
+
+        self.entries
+            .get(&(initiator_id, session_id))
+            .filter(|entry| entry.digest == digest)
+            .map(|entry| entry.replay.clone())
+    }
+
+    /// Remembers the response to a request, answered at `now`.
+    ///
+    /// Responses larger than the whole cache are not remembered.
+    pub(crate) fn insert(
+        &mut self,
+        initiator_id: NodeId,
+        session_id: SessionId,
+        digest: u32,
+        response: &AssembledResponse,
+        now: Instant,
+    ) {
+        let replay = if response.dest == initiator_id {
+            Replay::Response(response.clone())
+        } else {
+            Replay::Forwarded(response.kind)
+        };
+        if self.max_entries == 0 || replay.len() > self.max_bytes {
+            return;
+        }
+
+        let key = (initiator_id, session_id);
+        self.remove(&key);
+
+        while self.entries.len() >= self.max_entries || self.bytes + replay.len() > self.max_bytes {
+            match self.order.front().copied() {
+                Some(oldest) => self.remove(&oldest),
+                None => break,
+            }
+        }
+
+        self.bytes += replay.len();
+        self.order.push_back(key);
+        self.entries.insert(
+            key,
+            Entry {
+                digest,
+                replay,
+                stored: now,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &(NodeId, SessionId)) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.replay.len();
+            self.order.retain(|other| other != key);
+        }
+    }
+
+    /// Forgets the responses older than the time to live. They are in insertion order, so only
+    /// the front of the queue needs to be checked.
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some(oldest) = self.order.front().copied() {
+            match self.entries.get(&oldest) {
+                Some(entry) if now.saturating_duration_since(entry.stored) < self.ttl => break,
+                _ => self.remove(&oldest),
+            }
+        }
+    }
+}
+
+impl Default for ReplayCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ENTRIES, DEFAULT_MAX_BYTES, DEFAULT_TTL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(size: usize) -> AssembledResponse {
+        AssembledResponse {
+            data: vec![7; size],
+            dest: 1,
+            kind: RequestKind::Message,
+        }
+    }
+
+    #[test]
+    fn test_replay_matching_requests() {
+        let mut cache = ReplayCache::default();
+        let now = Instant::now();
+        let digest = ReplayCache::digest(b"ciao");
+        cache.insert(1, 10, digest, &response(16), now);
+
+        let Some(Replay::Response(replayed)) = cache.get(1, 10, digest, now) else {
+            panic!("Response not cached");
+        };
+        assert_eq!(replayed.data, vec![7; 16]);
+        assert_eq!(replayed.kind, RequestKind::Message);
+
+        // Another initiator, session or request content is not a retransmission
+        assert!(cache.get(2, 10, digest, now).is_none());
+        assert!(cache.get(1, 11, digest, now).is_none());
+        assert!(cache
+            .get(1, 10, ReplayCache::digest(b"cane"), now)
+            .is_none());
+    }
+
+    #[test]
+    fn test_forwarded_responses() {
+        let mut cache = ReplayCache::new(8, 10, DEFAULT_TTL);
+        let now = Instant::now();
+        // The response went to node 1, so the request of node 2 is remembered without it
+        cache.insert(2, 10, 0, &response(100), now);
+        assert!(matches!(
+            cache.get(2, 10, 0, now),
+            Some(Replay::Forwarded(RequestKind::Message))
+        ));
+        assert_eq!(cache.bytes, 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut cache = ReplayCache::new(2, 100, DEFAULT_TTL);
+        let now = Instant::now();
+        cache.insert(1, 1, 0, &response(10), now);
+        cache.insert(1, 2, 0, &response(10), now);
+        cache.insert(1, 3, 0, &response(10), now);
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.get(1, 1, 0, now).is_none());
+
+        // Making room for a large response forgets the oldest ones
+        cache.insert(1, 4, 0, &response(95), now);
+        assert_eq!(cache.entries.len(), 1);
+        assert!(cache.get(1, 4, 0, now).is_some());
+
+        cache.insert(1, 5, 0, &response(101), now);
+        assert!(cache.get(1, 5, 0, now).is_none());
+
+        let mut disabled = ReplayCache::new(0, 100, DEFAULT_TTL);
+        disabled.insert(1, 1, 0, &response(10), now);
+        assert_eq!(disabled.entries.len(), 0);
+    }
+
+    #[test]
+    fn test_expiration() {
+        let mut cache = ReplayCache::new(8, 100, Duration::from_millis(50));
+        let start = Instant::now();
+        cache.insert(1, 1, 0, &response(10), start);
+        let later = start + Duration::from_millis(100);
+        cache.insert(1, 2, 0, &response(10), later);
+
+        assert!(cache.get(1, 1, 0, later).is_none());
+        assert!(cache.get(1, 2, 0, later).is_some());
+        assert_eq!(cache.entries.len(), 1);
+    }
+}
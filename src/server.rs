@@ -1,19 +1,37 @@
 //! Implements the `Server` struct for managing server's operations.
 
-use crate::assembler::{AssemblerStatus, InsertFragmentError, RetrieveError};
+use crate::assembler::{AssemblerStatus, RetrieveError};
 use crate::assemblers_manager::AssemblersManager;
+use crate::capture::{Direction, Recorder};
 use crate::chat_behavior::ChatBehavior;
+use crate::clock::{Clock, SystemClock};
+use crate::compression;
+use crate::control::{
+    ControlCommand, ControlEvent, EventFilter, EventKind, RetransmissionReason, ShutdownSummary,
+};
+use crate::error::Error;
+use crate::fec::{self, Redundancy};
 use crate::fragment_manager::{FragmentManager, ToBeSentFragment};
 use crate::fragmenter::Fragmenter;
 use crate::media_behavior::MediaBehavior;
-use crate::specialized_behavior::{SetPathError, SpecializedBehavior};
+use crate::metrics::{self, MetricKey, Metrics};
+use crate::rate_limiter::{RateLimiter, RateLimits};
+use crate::replay_cache::{Replay, ReplayCache};
+use crate::specialized_behavior::{
+    AssembledResponse, ProcessError, RequestKind, SetPathError, SpecializedBehavior,
+};
 use crate::text_behavior::TextBehavior;
+use crate::token::{self, Presented};
 use crate::topology::{RoutingError, Topology};
-use crossbeam_channel::{select_biased, Receiver, Sender};
-use log::{error, info, warn};
+use crate::transport::Transport;
+use crossbeam_channel::{never, Receiver, Select, Sender};
+use log::{debug, error, info, warn};
 use rust_roveri_api::{FloodId, ServerCommand, ServerEvent, ServerType, SessionId};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use wg_2024::network::SourceRoutingHeader;
 use wg_2024::packet::{Ack, FloodRequest, FloodResponse, Nack, NackType, NodeType};
 use wg_2024::{
@@ -21,19 +39,57 @@ use wg_2024::{
     packet::{Fragment, Packet, PacketType},
 };
 
+/// When `Server::step` must be called again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Wake {
+    /// There is more work ready: the server must be stepped again right away.
+    Immediately,
+    /// Nothing to do until an input arrives or the given time is reached.
+    At(Instant),
+    /// Nothing to do until an input arrives.
+    #[default]
+    OnInput,
+}
+
+/// What a call to `Server::step` did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StepOutcome {
+    pub command_handled: bool,
+    pub control_command_handled: bool,
+    pub packet_handled: bool,
+    /// An outbound fragment was taken from the queue, either sent or requeued if no route
+    /// to its destination is known.
+    pub fragment_processed: bool,
+    pub terminated: bool,
+    pub wake: Wake,
+}
+
 pub struct Server {
     id: NodeId,
     command_recv: Receiver<ServerCommand>,
     packet_recv: Receiver<Packet>,
     controller_send: Sender<ServerEvent>,
-    packet_send: HashMap<NodeId, Sender<Packet>>,
+    control_recv: Receiver<ControlCommand>,
+    control_send: Option<Sender<ControlEvent>>,
+    event_filter: EventFilter,
+    packet_send: HashMap<NodeId, Box<dyn Transport>>,
     fragmenter: Fragmenter,
     assemblers_manager: AssemblersManager,
     fragment_manager: FragmentManager,
     topology: Topology,
     specialized: Box<dyn SpecializedBehavior + Send>,
     should_terminate: bool,
+    stop_deadline: Option<Instant>,
+    refused_requests: usize,
+    metrics: Metrics,
+    response_started: HashMap<SessionId, (RequestKind, Instant)>,
+    recorder: Option<Recorder>,
+    compression_threshold: usize,
+    fec: bool,
+    replay_cache: ReplayCache,
+    rate_limiter: RateLimiter,
     flood_id: FloodId,
+    clock: Arc<dyn Clock>,
 }
 
 impl Server {
@@ -49,6 +105,9 @@ impl Server {
             command_recv,
             packet_recv,
             controller_send,
+            control_recv: never(),
+            control_send: None,
+            event_filter: EventFilter::none(),
             fragmenter: Fragmenter::new(),
             assemblers_manager: AssemblersManager::new(),
             topology: Topology::new(id),
@@ -60,31 +119,230 @@ impl Server {
             },
             fragment_manager: FragmentManager::new(),
             should_terminate: false,
+            stop_deadline: None,
+            refused_requests: 0,
+            metrics: Metrics::new(),
+            response_started: HashMap::new(),
+            recorder: None,
+            compression_threshold: compression::DEFAULT_THRESHOLD,
+            fec: false,
+            replay_cache: ReplayCache::default(),
+            rate_limiter: RateLimiter::default(),
             flood_id: 0,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Installs the channels of the server-specific controller interface.
+    ///
+    /// Until this is called the server ignores every `ControlCommand` and emits no `ControlEvent`.
+    pub fn set_control_channels(
+        &mut self,
+        control_recv: Receiver<ControlCommand>,
+        control_send: Sender<ControlEvent>,
+    ) {
+        self.control_recv = control_recv;
+        self.control_send = Some(control_send);
+    }
+
+    /// Records every packet the server receives or sends to a capture file.
+    ///
+    /// The capture can be read back with `capture::read_capture` and replayed with
+    /// `capture::replay`.
+    pub fn set_capture(&mut self, path: &Path) -> io::Result<()> {
+        self.recorder = Some(Recorder::create(path)?);
+        Ok(())
+    }
+
+    /// Enables or disables the integrity trailer on the responses the server sends, and
+    /// requires it on the requests it receives.
+    ///
+    /// When disabled, requests carrying the trailer are still verified, but those without one
+    /// are accepted too. It must only be enabled if every client of the network sends and
+    /// verifies the trailer.
+    pub fn set_integrity(&mut self, enabled: bool) {
+        self.fragmenter.set_integrity(enabled);
+        self.assemblers_manager.set_integrity_required(enabled);
+    }
+
+    /// Sets the size, in bytes, above which responses are compressed.
+    ///
+    /// Only clients that sent their request in a compression envelope receive compressed
+    /// responses, see the `compression` module.
+    pub fn set_compression_threshold(&mut self, threshold: usize) {
+        self.compression_threshold = threshold;
+    }
+
+    /// Enables or disables forward error correction on the responses the server sends.
+    ///
+    /// When enabled, responses to clients that ask for them are followed by parity fragments,
+    /// as many as the drop rate observed on the path to their destination requires, so that
+    /// clients can rebuild lost fragments without waiting for a retransmission. Lost fragments
+    /// are still retransmitted when a `Nack` reports them. See the `fec` module.
+    pub fn set_fec(&mut self, enabled: bool) {
+        self.fec = enabled;
+    }
+
+    /// Sets how many responses, how many bytes of them in total and for how long the server
+    /// remembers them, to replay them when a client sends the same request again.
+    ///
+    /// By default the last 256 responses are remembered, up to 4 MiB, for one minute. Setting
+    /// `max_entries` to 0 disables the replay, so that retransmitted requests are processed again.
+    pub fn set_replay_limits(&mut self, max_entries: usize, max_bytes: usize, ttl: Duration) {
+        self.replay_cache = ReplayCache::new(max_entries, max_bytes, ttl);
+    }
+
+    /// Limits the requests each client can make and the bytes it is served per second.
+    ///
+    /// Requests over the limits are not processed: the client receives an error response
+    /// instead, and they are counted per client in the `server_requests_throttled_total` metric.
+    /// There are no limits by default.
+    pub fn set_rate_limits(&mut self, limits: RateLimits) {
+        self.rate_limiter.set_limits(limits);
+    }
+
+    /// Sets the clock the server and its specialized behavior measure their timeouts on, e.g.
+    /// the graceful stop deadline, the rate limits and the topology update window.
+    ///
+    /// `run` blocks in wall-clock time, so a clock other than the system one is meant for
+    /// servers driven by `step`, like the simulator does.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.specialized.set_clock(Arc::clone(&clock));
+        self.clock = clock;
+    }
+
+    /// Sets whether requests sent without a session token envelope are rejected, see the
+    /// `token` module.
+    ///
+    /// Only chat servers have sessions. Disabled by default, as a compatibility mode for
+    /// clients that do not support tokens, whose sessions are only bound to their `NodeId`.
+    pub fn set_tokens_required(&mut self, required: bool) {
+        self.specialized.set_tokens_required(required);
+    }
+
+    /// Sets the directory where the server persists its state across restarts.
+    ///
+    /// Only chat servers keep persistent state: the accounts saved in the directory are loaded
+    /// right away, the accounts are saved whenever they change, and the rest of the state is saved
+    /// when the server stops gracefully.
+    pub fn set_storage_dir(&mut self, dir: PathBuf) -> io::Result<()> {
+        match self.specialized.set_storage_dir(dir) {
+            Ok(()) => Ok(()),
+            Err(SetPathError::FileSystem(err)) => Err(err),
+            Err(SetPathError::WrongServerType) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only chat servers have persistent storage",
+            )),
         }
     }
 
     /// Runs the main server loop.
+    ///
+    /// Repeatedly calls `step`, blocking between steps until an input arrives or the wake-up
+    /// time it asks for is reached.
     pub fn run(&mut self) {
-        while !self.should_terminate {
-            select_biased!(
-                recv(self.command_recv) -> command => {
-                    if let Ok(command) = command {
-                        self.handle_command(command);
-                    }
-                }
-                recv(self.packet_recv) -> packet => {
-                    if let Ok(packet) = packet {
-                        self.handle_packet(packet);
-                    }
-                },
-                default => {
-                    if let Some(to_be_sent_fragment) = self.fragment_manager.get_next() {
-                        self.send_fragment(to_be_sent_fragment);
-                    }
-                }
-            );
+        loop {
+            let outcome = self.step();
+            if outcome.terminated {
+                break;
+            }
+
+            match outcome.wake {
+                Wake::Immediately => {}
+                Wake::At(deadline) => self.wait_for_input(Some(deadline)),
+                Wake::OnInput => self.wait_for_input(None),
+            }
+        }
+    }
+
+    /// Processes at most one command, one control command and one packet, then sends at most
+    /// one outbound fragment, without blocking.
+    ///
+    /// This lets the server be driven by an external event loop: the returned `StepOutcome`
+    /// tells what was done and when the server must be stepped again. Once it reports that
+    /// the server terminated, further calls do nothing.
+    pub fn step(&mut self) -> StepOutcome {
+        let mut outcome = StepOutcome::default();
+
+        if self.should_terminate {
+            outcome.terminated = true;
+            return outcome;
+        }
+
+        if let Ok(command) = self.command_recv.try_recv() {
+            self.handle_command(command);
+            outcome.command_handled = true;
+
+            if self.should_terminate {
+                self.flush_capture();
+                outcome.terminated = true;
+                return outcome;
+            }
+        }
+        if let Ok(command) = self.control_recv.try_recv() {
+            self.handle_control_command(command);
+            outcome.control_command_handled = true;
+        }
+        if let Ok(packet) = self.packet_recv.try_recv() {
+            self.handle_packet(packet);
+            outcome.packet_handled = true;
+        }
+        if let Some(to_be_sent_fragment) = self.fragment_manager.get_next() {
+            if let Err(error) = self.send_fragment(to_be_sent_fragment) {
+                self.report_error(error);
+            }
+            outcome.fragment_processed = true;
+        }
+
+        if self.stop_deadline.is_some() {
+            self.check_graceful_stop();
         }
+
+        if self.should_terminate {
+            self.flush_capture();
+        }
+        outcome.terminated = self.should_terminate;
+        outcome.wake = self.next_wake();
+        outcome
+    }
+
+    /// Computes when the server must be stepped again.
+    fn next_wake(&self) -> Wake {
+        let has_input = !self.command_recv.is_empty()
+            || !self.control_recv.is_empty()
+            || !self.packet_recv.is_empty();
+
+        if has_input || self.fragment_manager.has_queued() {
+            Wake::Immediately
+        } else if let Some(deadline) = self.stop_deadline {
+            Wake::At(deadline)
+        } else {
+            Wake::OnInput
+        }
+    }
+
+    /// Blocks until one of the input channels is ready or the deadline, if any, is reached.
+    fn wait_for_input(&self, deadline: Option<Instant>) {
+        let mut select = Select::new();
+        select.recv(&self.command_recv);
+        select.recv(&self.control_recv);
+        select.recv(&self.packet_recv);
+
+        match deadline {
+            Some(deadline) => {
+                let _ = select.ready_deadline(deadline);
+            }
+            None => {
+                select.ready();
+            }
+        }
+    }
+
+    /// Checks whether the server has no queued input and no unacknowledged output.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.command_recv.is_empty()
+            && self.packet_recv.is_empty()
+            && self.fragment_manager.is_idle()
     }
 
     /// Handles a received command from the controller.
@@ -97,57 +355,252 @@ impl Server {
         }
     }
 
+    /// Handles a received command from the server-specific control channel.
+    fn handle_control_command(&mut self, command: ControlCommand) {
+        match command {
+            ControlCommand::GetTopology => {
+                info!(
+                    "{} Topology requested:\n{}",
+                    self.get_prefix(),
+                    self.topology
+                );
+                self.send_control_event(ControlEvent::Topology(self.topology.snapshot()));
+            }
+            ControlCommand::GracefulStop(timeout) => self.begin_graceful_stop(timeout),
+            ControlCommand::Subscribe(filter) => self.event_filter = filter,
+            ControlCommand::GetMetrics => {
+                self.send_control_event(ControlEvent::Metrics(self.metrics.clone()))
+            }
+            ControlCommand::ExportMetrics(path) => {
+                let result = self.metrics.export_prometheus(&path);
+                if let Err(err) = &result {
+                    error!(
+                        "{} Could not export metrics to {:?}: {}",
+                        self.get_prefix(),
+                        path,
+                        err
+                    );
+                }
+                self.send_control_event(ControlEvent::MetricsExported(path, result));
+            }
+            ControlCommand::AddNeighbor(id, transport) => self.add_neighbor(id, transport),
+            ControlCommand::SetRateLimits(limits) => self.set_rate_limits(limits),
+        }
+    }
+
+    /// Enters the graceful stop mode, refusing new requests from now on.
+    fn begin_graceful_stop(&mut self, timeout: Duration) {
+        if self.stop_deadline.is_some() {
+            warn!("{} Graceful stop already in progress", self.get_prefix());
+            return;
+        }
+
+        info!(
+            "{} Stopping, waiting up to {:?} for {} unacknowledged fragments",
+            self.get_prefix(),
+            timeout,
+            self.fragment_manager.pending_count()
+        );
+        self.stop_deadline = Some(self.clock.now() + timeout);
+    }
+
+    /// Terminates the server if the graceful stop is complete.
+    ///
+    /// The stop is complete when every outstanding fragment has been acknowledged or the
+    /// deadline has passed. Only responses to requests accepted before the stop count:
+    /// refusals are not awaited, and requests still being received are not waited for, since
+    /// they would be refused anyway. They are reported as abandoned sessions. Before
+    /// terminating, the specialized behavior is flushed and a summary is sent to the
+    /// controller.
+    fn check_graceful_stop(&mut self) {
+        let drained = self.fragment_manager.is_idle();
+        let timed_out = self
+            .stop_deadline
+            .is_some_and(|deadline| self.clock.now() >= deadline);
+
+        if !drained && !timed_out {
+            return;
+        }
+
+        let flushed = match self.specialized.flush() {
+            Ok(()) => true,
+            Err(err) => {
+                self.report_error(Error::Behavior(err));
+                false
+            }
+        };
+
+        let summary = ShutdownSummary {
+            drained,
+            unacknowledged_fragments: self.fragment_manager.pending_count(),
+            abandoned_sessions: self.assemblers_manager.pending_sessions(),
+            refused_requests: self.refused_requests,
+            flushed,
+        };
+        info!("{} Stopped: {:?}", self.get_prefix(), summary);
+
+        self.send_control_event(ControlEvent::ShutdownSummary(summary));
+        self.should_terminate = true;
+    }
+
     /// Adds a drone to the server's topology and packet senders.
     fn add_drone(&mut self, drone_id: NodeId, sender: Sender<Packet>) {
+        self.add_neighbor(drone_id, Box::new(sender));
+    }
+
+    /// Adds a neighbor reached through any transport to the server's topology and packet
+    /// senders.
+    fn add_neighbor(&mut self, drone_id: NodeId, transport: Box<dyn Transport>) {
         self.topology
             .insert_edge((self.id, NodeType::Server), (drone_id, NodeType::Drone));
 
-        match self.packet_send.insert(drone_id, sender) {
+        match self.packet_send.insert(drone_id, transport) {
             Some(_) => info!("Neighbor {} updated", drone_id),
             None => info!("Neighbor {} added", drone_id),
         }
     }
 
-    /// Removes a drone from the server's topology.
+    /// Removes a drone from the server's topology and packet senders.
+    ///
+    /// Routes are computed for every fragment, so removing the drone's edges is enough to stop
+    /// using paths through it, while the rest of the topology is kept. Fragments already sent
+    /// through it are requeued right away instead of waiting for a Nack that will never come,
+    /// and the neighbors are flooded to find alternative paths.
     fn remove_drone(&mut self, drone_id: NodeId) {
-        self.topology.remove_edge(self.id, drone_id);
+        self.topology.remove_node(drone_id);
+
+        match self.packet_send.remove(&drone_id) {
+            Some(_) => info!("{} Neighbor {} removed", self.get_prefix(), drone_id),
+            None => warn!(
+                "{} Tried to remove {} which is not a neighbor",
+                self.get_prefix(),
+                drone_id
+            ),
+        }
+
+        let requeued = self.fragment_manager.requeue_in_flight_through(drone_id);
+        if !requeued.is_empty() {
+            info!(
+                "{} Requeued {} in-flight fragments sent through {}",
+                self.get_prefix(),
+                requeued.len(),
+                drone_id
+            );
+        }
+        for (session_id, fragment_index) in requeued {
+            self.metrics
+                .increment(MetricKey::new(metrics::FRAGMENTS_RETRANSMITTED));
+            self.send_telemetry(ControlEvent::Retransmission {
+                session_id,
+                fragment_index,
+                reason: RetransmissionReason::NeighborRemoved(drone_id),
+            });
+        }
+
+        // The paths through the other neighbors are still valid: only look for new ones.
+        self.flood_neighbors();
     }
 
     /// Set the content server path if its possible, otherwise send an error to the controller
     fn set_path(&mut self, path: PathBuf) {
         match self.specialized.set_path(path) {
             Ok(()) => info!("{} Updated path", self.get_prefix()),
-            Err(SetPathError::FileSystem(err)) => {
-                error!(
-                    "{} Cant update his path due to file system error on specified path",
-                    self.get_prefix()
-                );
-                self.send_server_event(ServerEvent::MediaPathError(err));
-            }
-            Err(SetPathError::WrongServerType) => {
-                error!(
-                    "{} Cant update his path due to his type (not a content server)",
-                    self.get_prefix()
-                );
-                self.send_server_event(ServerEvent::UnexpectedCommand);
-            }
+            Err(err) => self.report_error(Error::Path(err)),
         }
     }
 
     /// Handles a packet based on its type.
     fn handle_packet(&mut self, packet: Packet) {
-        match packet.pack_type {
-            PacketType::Ack(ack) => self.handle_ack(ack, packet.session_id, packet.routing_header),
+        self.capture(Direction::Inbound, &packet);
+
+        if self.event_filter.allows(EventKind::PacketReceived) {
+            self.send_telemetry(ControlEvent::PacketReceived(packet.clone()));
+        }
+
+        let result = match packet.pack_type {
+            PacketType::Ack(ack) => {
+                self.learn_from_header(&packet.routing_header, NodeType::Client);
+                self.handle_ack(ack, packet.session_id, packet.routing_header);
+                Ok(())
+            }
             PacketType::Nack(nack) => {
+                self.learn_from_header(&packet.routing_header, NodeType::Drone);
                 self.handle_nack(nack, packet.session_id, packet.routing_header)
             }
             PacketType::MsgFragment(fragment) => {
+                self.learn_from_header(&packet.routing_header, NodeType::Client);
                 self.handle_fragment(fragment, packet.session_id, packet.routing_header)
             }
             PacketType::FloodRequest(flood_req) => {
                 self.handle_flood_request(flood_req, packet.session_id)
             }
-            PacketType::FloodResponse(flood_res) => self.handle_flood_response(flood_res),
+            PacketType::FloodResponse(flood_res) => {
+                self.handle_flood_response(flood_res);
+                Ok(())
+            }
+        };
+
+        if let Err(error) = result {
+            self.report_error(error);
+        }
+    }
+
+    /// Learns the links traversed by an incoming packet from its source routing header.
+    ///
+    /// Every hop between the origin and this server must be a drone, while the type of the
+    /// origin depends on the packet type. Links are bidirectional, so the reverse of the
+    /// traversed path is a valid route back to the origin even if no flood ever reached it.
+    ///
+    /// Headers are not verified, so nothing is learned from one whose last hop before this
+    /// server is not a neighbor, and the types of the nodes already known are kept.
+    fn learn_from_header(&mut self, header: &SourceRoutingHeader, origin_type: NodeType) {
+        let position = match header.hops.iter().position(|id| *id == self.id) {
+            Some(position) => position,
+            None => {
+                warn!(
+                    "{} Received a packet whose route does not contain this server",
+                    self.get_prefix()
+                );
+                return;
+            }
+        };
+        if position == 0 {
+            return;
+        }
+
+        let previous = header.hops[position - 1];
+        if !self.packet_send.contains_key(&previous) {
+            warn!(
+                "{} Received a packet whose previous hop {} is not a neighbor",
+                self.get_prefix(),
+                previous
+            );
+            return;
+        }
+
+        let topology = &self.topology;
+        let node_type = |index: usize| {
+            if index == position {
+                NodeType::Server
+            } else if let Some(known) = topology.known_type(header.hops[index]) {
+                known
+            } else if index == 0 {
+                origin_type
+            } else {
+                NodeType::Drone
+            }
+        };
+        let edges: Vec<_> = (0..position)
+            .map(|index| {
+                (
+                    (header.hops[index], node_type(index)),
+                    (header.hops[index + 1], node_type(index + 1)),
+                )
+            })
+            .collect();
+
+        for (node1, node2) in edges {
+            self.topology.insert_edge(node1, node2);
         }
     }
 
@@ -158,120 +611,381 @@ impl Server {
         if let Some(sender) = header.hops.get(0) {
             self.topology.observe_success(*sender);
         };
+        self.metrics
+            .increment(MetricKey::new(metrics::ACKS_RECEIVED));
 
-        self.fragment_manager
-            .remove_from_cache((session_id, ack.fragment_index));
+        if let Some(dest) = self
+            .fragment_manager
+            .remove_from_cache((session_id, ack.fragment_index))
+        {
+            self.metrics
+                .increment(MetricKey::new(metrics::RESPONSES_ACKNOWLEDGED));
+            if let Some((kind, started)) = self.response_started.remove(&session_id) {
+                self.metrics.observe(
+                    MetricKey::labeled(metrics::END_TO_END_LATENCY, "kind", kind.as_str()),
+                    started.elapsed(),
+                );
+            }
+
+            self.send_telemetry(ControlEvent::ResponseAcknowledged { session_id, dest });
+        }
     }
 
     /// Handles a negative acknowledgment packet.
     ///
     /// Based on the NACK type, it either reinserts the fragment into the fragment manager's buffer
     /// or initiates network discovery.
-    fn handle_nack(&mut self, nack: Nack, session_id: SessionId, header: SourceRoutingHeader) {
+    fn handle_nack(
+        &mut self,
+        nack: Nack,
+        session_id: SessionId,
+        header: SourceRoutingHeader,
+    ) -> Result<(), Error> {
         if let Some(sender) = header.hops.get(0) {
             self.topology.observe_failure(*sender);
         };
+        let nack_type = match nack.nack_type {
+            NackType::Dropped => "dropped",
+            NackType::DestinationIsDrone => "destination_is_drone",
+            NackType::ErrorInRouting(_) => "error_in_routing",
+            NackType::UnexpectedRecipient(_) => "unexpected_recipient",
+        };
+        self.metrics.increment(MetricKey::labeled(
+            metrics::NACKS_RECEIVED,
+            "type",
+            nack_type,
+        ));
 
         match nack.nack_type {
             NackType::Dropped => {
                 info!("Nack with NackType::Dropped received");
-                let _ = self
-                    .fragment_manager
-                    .insert_from_cache((session_id, nack.fragment_index));
+                self.retransmit(session_id, nack)
             }
             NackType::DestinationIsDrone => {
                 //self.topology.reset();
                 self.start_network_discovery();
-                let _ = self
-                    .fragment_manager
-                    .insert_from_cache((session_id, nack.fragment_index));
+                self.retransmit(session_id, nack)
             }
             NackType::ErrorInRouting(_) => {
                 //self.topology.reset();
                 self.start_network_discovery();
-                let _ = self
-                    .fragment_manager
-                    .insert_from_cache((session_id, nack.fragment_index));
+                self.retransmit(session_id, nack)
             }
             NackType::UnexpectedRecipient(_) => {
-                warn!("Nack with NackType::UnexpectedRecipient received")
+                warn!("Nack with NackType::UnexpectedRecipient received");
+                Ok(())
             }
         }
     }
 
+    /// Reinserts the fragment a Nack refers to into the fragment manager's buffer.
+    ///
+    /// Parity fragments are never retransmitted: the client rebuilds the message from the data
+    /// fragments alone.
+    fn retransmit(&mut self, session_id: SessionId, nack: Nack) -> Result<(), Error> {
+        if fec::is_parity(nack.fragment_index) {
+            return Ok(());
+        }
+
+        self.fragment_manager
+            .insert_from_cache((session_id, nack.fragment_index))?;
+
+        self.metrics
+            .increment(MetricKey::new(metrics::FRAGMENTS_RETRANSMITTED));
+        self.send_telemetry(ControlEvent::Retransmission {
+            session_id,
+            fragment_index: nack.fragment_index,
+            reason: RetransmissionReason::Nack(nack.nack_type),
+        });
+        Ok(())
+    }
+
     /// Handles a fragment packet.
     ///
     /// Attempts to insert the fragment into the assembler manager. If the message assembly is
-    /// complete, it retrieves the assembled data and processes it.
+    /// complete, it retrieves the assembled data and processes it. If the message fails its
+    /// integrity check, the initiator is asked to send it again.
     fn handle_fragment(
         &mut self,
         fragment: Fragment,
         session_id: SessionId,
         header: SourceRoutingHeader,
-    ) {
-        println!("{} Fragment received", self.get_prefix());
+    ) -> Result<(), Error> {
+        debug!("{} Fragment received", self.get_prefix());
+        self.metrics
+            .increment(MetricKey::new(metrics::FRAGMENTS_RECEIVED));
 
-        match self
+        let total_n_fragments = fragment.total_n_fragments;
+        let status = self
             .assemblers_manager
             .insert_fragment(fragment, session_id)
-        {
-            Ok(AssemblerStatus::Complete) => {
-                match self.assemblers_manager.retrieve_assembled(session_id) {
-                    Ok(assembled) => {
-                        match header.hops.first() {
-                            Some(id) => self.handle_assembled(assembled, *id),
-                            None => error!(
-                                "{} Received a packet with empty hops vec",
-                                self.get_prefix()
-                            ),
-                        };
-                    }
-                    Err(RetrieveError::Incomplete) => warn!(
-                        "{} Tryed to assemble an incomplete message",
-                        self.get_prefix()
-                    ),
-                    Err(RetrieveError::UnknownSessionId) => warn!(
-                        "{} Tryed to assemble a message with an uknown session id",
-                        self.get_prefix()
-                    ),
+            .map_err(|source| Error::Assembly { session_id, source })?;
+        if let AssemblerStatus::Incomplete = status {
+            return Ok(());
+        }
+
+        let elapsed = self
+            .assemblers_manager
+            .elapsed(session_id)
+            .unwrap_or_default();
+        let assembled = match self.assemblers_manager.retrieve_assembled(session_id) {
+            Ok(assembled) => assembled,
+            Err(source) => {
+                if let RetrieveError::Corrupted(_) = source {
+                    self.request_retransmission(session_id, total_n_fragments, &header)?;
                 }
+                return Err(Error::Retrieve { session_id, source });
             }
-            Err(InsertFragmentError::IndexOutOfBounds) => {
-                warn!(
-                    "{} Tryed to insert a fragment into buffer with an out of bounds index",
-                    self.get_prefix()
-                );
-            }
-            Err(InsertFragmentError::CapacityDoesNotMatch) => {
-                warn!(
-                    "{} Tryed to insert a fragment into buffer with a non matching capacity",
-                    self.get_prefix()
-                );
-            }
-            _ => {}
+        };
+        let (assembled, parity) = fec::decode(assembled);
+        let (assembled, compress) = compression::decode(assembled)
+            .map_err(|source| Error::Compression { session_id, source })?;
+        let initiator_id = *header.hops.first().ok_or(Error::MalformedHeader)?;
+
+        self.send_telemetry(ControlEvent::MessageAssembled {
+            session_id,
+            initiator_id,
+            size: assembled.len(),
+        });
+        self.handle_assembled(
+            assembled,
+            initiator_id,
+            session_id,
+            elapsed,
+            compress,
+            parity,
+        )
+    }
+
+    /// Asks the initiator of a corrupted message to send the whole session again.
+    ///
+    /// The protocol has no message for this, so a `NackType::Dropped` is sent back for every
+    /// fragment, which senders already handle by sending the fragment again.
+    fn request_retransmission(
+        &self,
+        session_id: SessionId,
+        total_n_fragments: u64,
+        header: &SourceRoutingHeader,
+    ) -> Result<(), Error> {
+        let hops: Vec<NodeId> = header
+            .hops
+            .get(..=header.hop_index)
+            .ok_or(Error::MalformedHeader)?
+            .iter()
+            .rev()
+            .copied()
+            .collect();
+
+        for fragment_index in 0..total_n_fragments {
+            self.send_packet(Packet {
+                routing_header: SourceRoutingHeader {
+                    hop_index: 1,
+                    hops: hops.clone(),
+                },
+                session_id,
+                pack_type: PacketType::Nack(Nack {
+                    fragment_index,
+                    nack_type: NackType::Dropped,
+                }),
+            })?;
         }
+        Ok(())
     }
 
     /// Handles an assembled message.
     ///
     /// Converts the assembled response into fragments and inserts them into the fragment manager.
-    fn handle_assembled(&mut self, assembled: Vec<u8>, initiator_id: NodeId) {
-        let response = self.specialized.handle_assembled(assembled, initiator_id);
-        let fragments = self.fragmenter.to_fragment_vec(response);
+    /// While the server is stopping, the request is not processed and an error response is
+    /// sent instead. A request the initiator already sent in the same session is not processed
+    /// again either: the response it received the first time is sent again, or nothing if the
+    /// response went to another node, e.g. a chat message to its recipient. Requests of an
+    /// initiator over its rate limits are answered with an error response.
+    ///
+    /// If the request is in a session token envelope, the token is handed to the specialized
+    /// behavior, and the processed response is wrapped in an envelope with the token it issued,
+    /// if any. Responses sent to another node, e.g. chat messages, are never wrapped.
+    ///
+    /// A request already answered is replayed before the request rate limit is checked, so that
+    /// a client retrying it is not throttled for a response the server already produced. The
+    /// byte limit still applies: once it is exceeded, replays are throttled too.
+    ///
+    /// `elapsed` is the time spent receiving the request, used to report the request latency.
+    /// If `compress` is set, the initiator can decode compressed responses, so the response to
+    /// it is wrapped in a compression envelope. If `parity` is set, the initiator asked for parity
+    /// fragments, so if forward error correction is enabled the response to it is protected by
+    /// as many as the drop rate of the path to the initiator requires.
+    fn handle_assembled(
+        &mut self,
+        assembled: Vec<u8>,
+        initiator_id: NodeId,
+        session_id: SessionId,
+        elapsed: Duration,
+        compress: bool,
+        parity: bool,
+    ) -> Result<(), Error> {
+        let started = Instant::now();
+        let now = self.clock.now();
+        let digest = ReplayCache::digest(&assembled);
+        let (assembled, presented) =
+            token::decode(assembled).map_err(|source| Error::Token { session_id, source })?;
+
+        let mut throttled = false;
+        let refused = self.stop_deadline.is_some();
+        let mut response = if refused {
+            self.refused_requests += 1;
+            self.specialized
+                .handle_error(ProcessError::Stopping, initiator_id)
+        } else if let Some(replay) = self
+            .replay_cache
+            .get(initiator_id, session_id, digest, now)
+            .filter(|_| self.rate_limiter.allow_bytes(initiator_id, now))
+        {
+            info!(
+                "{} Replaying the response to session {} of {}",
+                self.get_prefix(),
+                session_id,
+                initiator_id
+            );
+            self.metrics.increment(MetricKey::labeled(
+                metrics::REQUESTS_REPLAYED,
+                "kind",
+                replay.kind().as_str(),
+            ));
+            match replay {
+                Replay::Response(response) => response,
+                // Sending it again would deliver it twice
+                Replay::Forwarded(_) => return Ok(()),
+            }
+        } else if !self.rate_limiter.allow_request(initiator_id, now) {
+            warn!(
+                "{} Throttling the requests of {}",
+                self.get_prefix(),
+                initiator_id
+            );
+            throttled = true;
+            self.metrics.increment(MetricKey::labeled(
+                metrics::REQUESTS_THROTTLED,
+                "initiator",
+                initiator_id.to_string(),
+            ));
+            self.specialized
+                .handle_error(ProcessError::Throttled, initiator_id)
+        } else {
+            let (mut response, issued) = self.specialized.handle_authenticated(
+                assembled,
+                initiator_id,
+                presented,
+                &mut self.metrics,
+            );
+            if let Presented::Enveloped(_) = presented {
+                if response.dest == initiator_id {
+                    response.data = token::encode(issued.as_ref(), &response.data);
+                }
+            }
+            for notification in self.specialized.take_notifications() {
+                self.send_notification(notification);
+            }
+            self.replay_cache
+                .insert(initiator_id, session_id, digest, &response, now);
+            let latency = elapsed + started.elapsed();
+
+            self.metrics.observe(
+                MetricKey::labeled(metrics::REQUEST_LATENCY, "kind", response.kind.as_str()),
+                latency,
+            );
+            self.send_telemetry(ControlEvent::RequestProcessed {
+                kind: response.kind,
+                initiator_id,
+                latency,
+            });
+            response
+        };
+
+        if compress && response.dest == initiator_id {
+            let size = response.data.len();
+            response.data = compression::encode(&response.data, self.compression_threshold);
+            if response.data.len() < size {
+                self.metrics
+                    .increment(MetricKey::new(metrics::RESPONSES_COMPRESSED));
+            }
+        }
+        if !throttled {
+            self.rate_limiter
+                .charge_bytes(initiator_id, response.data.len(), now);
+        }
+
+        let kind = response.kind;
+        let redundancy = if parity && response.dest == initiator_id {
+            self.redundancy_to(response.dest)
+        } else {
+            Redundancy::NONE
+        };
+        let fragments = self
+            .fragmenter
+            .to_fragment_vec_with_parity(response, redundancy);
+        let first = fragments
+            .first()
+            .ok_or(Error::Fragmentation { dest: initiator_id })?;
+
+        // Refusals are sent once: waiting for their acknowledgments would keep the server from
+        // stopping, and a client that misses one sees the same as if the server had stopped
+        if refused {
+            self.fragment_manager.insert_uncached(fragments);
+            return Ok(());
+        }
+
+        self.response_started.insert(
+            first.session_id,
+            (kind, started.checked_sub(elapsed).unwrap_or(started)),
+        );
         self.fragment_manager.insert_bulk(fragments);
+        Ok(())
+    }
+
+    /// Sends a message the specialized behavior produced on its own, e.g. a presence
+    /// notification. Notifications are never compressed nor protected by parity fragments,
+    /// since the destination did not negotiate either.
+    fn send_notification(&mut self, notification: AssembledResponse) {
+        self.metrics.increment(MetricKey::labeled(
+            metrics::NOTIFICATIONS_SENT,
+            "kind",
+            notification.kind.as_str(),
+        ));
+        let fragments = self.fragmenter.to_fragment_vec(notification);
+        self.fragment_manager.insert_bulk(fragments);
+    }
+
+    /// Chooses how many parity fragments protect a response to `dest`.
+    ///
+    /// The redundancy depends on the drop rate observed on the path the fragments will most
+    /// likely take. If forward error correction is disabled or no path is known yet, no parity
+    /// fragment is sent.
+    fn redundancy_to(&self, dest: NodeId) -> Redundancy {
+        if !self.fec {
+            return Redundancy::NONE;
+        }
+
+        match self.topology.dijkstra(self.id, dest) {
+            Ok(path) => Redundancy::for_drop_rate(self.topology.path_drop_rate(&path)),
+            Err(_) => Redundancy::NONE,
+        }
     }
 
     /// Handles a flood request packet.
     ///
     /// Starts the flood response process.
-    fn handle_flood_request(&mut self, flood_req: FloodRequest, session_id: SessionId) {
-        self.begin_flood_response(flood_req, session_id);
+    fn handle_flood_request(
+        &mut self,
+        flood_req: FloodRequest,
+        session_id: SessionId,
+    ) -> Result<(), Error> {
+        self.begin_flood_response(flood_req, session_id)
     }
 
     /// Begins the flood response process.
     ///
     /// Constructs a flood response packet and sends it back along the discovered path.
-    fn begin_flood_response(&self, req: FloodRequest, session_id: u64) {
+    fn begin_flood_response(&self, req: FloodRequest, session_id: u64) -> Result<(), Error> {
         let flood_res = FloodResponse {
             flood_id: req.flood_id,
             path_trace: {
@@ -300,7 +1014,7 @@ impl Server {
             session_id,
         };
 
-        self.send_packet(flood_res_packet);
+        self.send_packet(flood_res_packet)
     }
 
     /// Handles a flood response packet.
@@ -318,11 +1032,23 @@ impl Server {
 
     /// Starts the network discovery process.
     ///
-    /// Sends flood request packets to all neighbors to explore the network topology.
+    /// Forgets the known topology and sends flood request packets to all neighbors to explore it
+    /// again.
     fn start_network_discovery(&mut self) {
-        self.topology.reset();
+        self.topology.reset(self.clock.now());
+        self.flood_neighbors();
+    }
+
+    /// Sends flood request packets to all neighbors, adding the paths they report to the known
+    /// topology. Neighbors that cannot be reached are reported, while the discovery goes on
+    /// through the others.
+    fn flood_neighbors(&mut self) {
+        self.send_telemetry(ControlEvent::DiscoveryStarted {
+            neighbors: self.packet_send.len(),
+        });
 
-        for (_, sender) in self.packet_send.iter() {
+        let mut errors = Vec::new();
+        for (neighbor, sender) in self.packet_send.iter() {
             let packet = Packet {
                 routing_header: SourceRoutingHeader {
                     hop_index: 0,
@@ -338,7 +1064,13 @@ impl Server {
 
             self.flood_id += 1;
 
-            self.send_packet_to_sender(packet, sender);
+            if let Err(error) = self.send_packet_to_sender(packet, *neighbor, sender.as_ref()) {
+                errors.push(error);
+            }
+        }
+
+        for error in errors {
+            self.report_error(error);
         }
     }
 
@@ -350,109 +1082,1693 @@ impl Server {
     /// - If the topology is still updating or no path is found, the fragment is reinserted into the
     ///   fragment manager's buffer.
     /// - If the topology is not updating but no path is found, the network discovery process is started.
-    /// - If the fragment is being sent to the server itself just ignore it and log an error.
-    fn send_fragment(&mut self, to_be_sent_fragment: ToBeSentFragment) {
+    /// - If the fragment is being sent to the server itself, or cannot be handed to the next hop,
+    ///   the whole response is abandoned and an error is returned.
+    /// - Parity fragments are not cached, so they are dropped instead of being reinserted.
+    fn send_fragment(&mut self, to_be_sent_fragment: ToBeSentFragment) -> Result<(), Error> {
         //let path = self.topology.bfs(self.id, to_be_sent_fragment.dest);
         let path = self.topology.dijkstra(self.id, to_be_sent_fragment.dest);
 
-        match path {
-            Ok(path) => {
-                let header = SourceRoutingHeader {
-                    hop_index: 1,
-                    hops: path,
-                };
+        match path {
+            Ok(path) => {
+                let header = SourceRoutingHeader {
+                    hop_index: 1,
+                    hops: path,
+                };
+
+                if let Some(next_hop) = header.hops.get(1) {
+                    self.fragment_manager.mark_in_flight(
+                        (
+                            to_be_sent_fragment.session_id,
+                            to_be_sent_fragment.fragment.fragment_index,
+                        ),
+                        *next_hop,
+                    );
+                }
+
+                let packet = Packet {
+                    pack_type: PacketType::MsgFragment(to_be_sent_fragment.fragment),
+                    routing_header: header,
+                    session_id: to_be_sent_fragment.session_id,
+                };
+                if fec::is_parity(to_be_sent_fragment.fragment.fragment_index) {
+                    self.metrics
+                        .increment(MetricKey::new(metrics::PARITY_FRAGMENTS_SENT));
+                } else {
+                    self.metrics
+                        .increment(MetricKey::new(metrics::FRAGMENTS_SENT));
+                }
+                let sent = self.send_packet(packet);
+                if sent.is_err() {
+                    self.abandon_response(to_be_sent_fragment.session_id);
+                }
+                sent
+            }
+            Err(source @ RoutingError::SourceIsDest) => {
+                self.abandon_response(to_be_sent_fragment.session_id);
+                Err(Error::Routing {
+                    dest: to_be_sent_fragment.dest,
+                    source,
+                })
+            }
+            Err(RoutingError::NoPathFound) => {
+                //if the topology is still updating its ok to not find the path
+                //=> reinsert the packet in the buffer
+                let requeued = if fec::is_parity(to_be_sent_fragment.fragment.fragment_index) {
+                    Ok(())
+                } else {
+                    self.fragment_manager.insert_from_cache((
+                        to_be_sent_fragment.session_id,
+                        to_be_sent_fragment.fragment.fragment_index,
+                    ))
+                };
+                if !self.topology.is_updating(self.clock.now()) {
+                    self.metrics
+                        .increment(MetricKey::new(metrics::ROUTES_NOT_FOUND));
+                    self.send_telemetry(ControlEvent::RouteNotFound {
+                        dest: to_be_sent_fragment.dest,
+                    });
+                    //self.topology.reset();
+                    self.start_network_discovery();
+                }
+                requeued
+            }
+        }
+    }
+
+    /// Gives up on a response one of whose fragments cannot be sent.
+    ///
+    /// The client cannot assemble the response without that fragment, so the rest of it is
+    /// dropped too and no acknowledgment is awaited: otherwise its fragments would stay cached,
+    /// and keep a graceful stop waiting, forever.
+    fn abandon_response(&mut self, session_id: SessionId) {
+        let evicted = self.fragment_manager.evict_session(session_id);
+        self.response_started.remove(&session_id);
+        if evicted > 0 {
+            warn!(
+                "{} Abandoned {} fragments of session {}",
+                self.get_prefix(),
+                evicted,
+                session_id
+            );
+        }
+    }
+
+    /// Retrieves the id and the sender of the next hop in the packet's routing path.
+    fn get_sender(&self, packet: &Packet) -> Result<(NodeId, &dyn Transport), Error> {
+        let next_index = packet.routing_header.hop_index;
+        let next_id = *packet
+            .routing_header
+            .hops
+            .get(next_index)
+            .ok_or(Error::MalformedHeader)?;
+        let sender = self
+            .packet_send
+            .get(&next_id)
+            .ok_or(Error::UnknownNeighbor(next_id))?;
+        Ok((next_id, sender.as_ref()))
+    }
+
+    /// Sends a packet to the next hop in its routing path.
+    fn send_packet(&self, packet: Packet) -> Result<(), Error> {
+        let (neighbor, sender) = self.get_sender(&packet)?;
+        self.send_packet_to_sender(packet, neighbor, sender)
+    }
+
+    /// Sends a packet to a specific sender.
+    fn send_packet_to_sender(
+        &self,
+        packet: Packet,
+        neighbor: NodeId,
+        sender: &dyn Transport,
+    ) -> Result<(), Error> {
+        self.capture(Direction::Outbound, &packet);
+
+        sender
+            .send(packet.clone())
+            .map_err(|source| Error::Transport { neighbor, source })?;
+        self.send_server_event(ServerEvent::PacketSent(packet));
+        Ok(())
+    }
+
+    /// Reports an error: the single place where failures are logged, counted and sent to the
+    /// controller.
+    ///
+    /// Errors the network API has a dedicated event for are sent as that `ServerEvent`, every
+    /// other one as a `ControlEvent::Error` to controllers subscribed to `EventKind::Error`.
+    fn report_error(&mut self, error: Error) {
+        error!("{} {}", self.get_prefix(), error);
+        self.metrics
+            .increment(MetricKey::labeled(metrics::ERRORS, "kind", error.as_str()));
+
+        match error {
+            Error::Path(SetPathError::FileSystem(err)) => {
+                self.send_server_event(ServerEvent::MediaPathError(err))
+            }
+            Error::Path(SetPathError::WrongServerType) => {
+                self.send_server_event(ServerEvent::UnexpectedCommand)
+            }
+            error => self.send_telemetry(ControlEvent::Error(error)),
+        }
+    }
+
+    /// Writes a packet to the capture file, if capture is enabled.
+    fn capture(&self, direction: Direction, packet: &Packet) {
+        if let Some(recorder) = &self.recorder {
+            if let Err(err) = recorder.record(direction, packet) {
+                error!("{} Could not record packet: {}", self.get_prefix(), err);
+            }
+        }
+    }
+
+    /// Writes the buffered packets to the capture file, if capture is enabled.
+    ///
+    /// The recorder also flushes when the server is dropped, but errors can only be reported
+    /// here.
+    fn flush_capture(&self) {
+        if let Some(recorder) = &self.recorder {
+            if let Err(err) = recorder.flush() {
+                error!("{} Could not flush the capture: {}", self.get_prefix(), err);
+            }
+        }
+    }
+
+    /// Sends an event to the server controller.
+    fn send_server_event(&self, server_event: ServerEvent) {
+        if self.controller_send.send(server_event).is_err() {
+            let message = format!("{} The controller is disconnected", self.get_prefix());
+            error!("{}", message);
+        }
+    }
+
+    /// Sends an event on the server-specific control channel, if one is installed.
+    fn send_control_event(&self, control_event: ControlEvent) {
+        if let Some(control_send) = &self.control_send {
+            if control_send.send(control_event).is_err() {
+                let message = format!("{} The control channel is disconnected", self.get_prefix());
+                error!("{}", message);
+            }
+        }
+    }
+
+    /// Sends a telemetry event on the control channel if the controller subscribed to its kind.
+    fn send_telemetry(&self, control_event: ControlEvent) {
+        if control_event
+            .kind()
+            .is_some_and(|kind| self.event_filter.allows(kind))
+        {
+            self.send_control_event(control_event);
+        }
+    }
+
+    /// Retrieves the server's logging prefix.
+    fn get_prefix(&self) -> String {
+        format!("[SERVER {}]", self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::assembler::AssemblerStatus;
+    use crate::assemblers_manager::AssemblersManager;
+    use crate::capture::{self, ReplayOptions};
+    use crate::compression;
+    use crate::fec::{self, is_parity};
+    use crate::fragmenter::Fragmenter;
+    use crate::metrics::{self, MetricKey};
+    use crate::specialized_behavior::AssembledResponse;
+    use crate::token::{self, Presented, SessionToken};
+    use crate::{ControlCommand, ControlEvent, EventFilter, EventKind};
+    use crate::{Error, InsertFragmentError, IntegrityError, RetrieveError};
+    use crate::{RateLimit, RateLimits};
+    use crate::{RequestKind, RetransmissionReason};
+    use crate::{RoutingError, TransportError};
+    use crate::{Server, StepOutcome, Wake};
+    use client::client::Client;
+    use crossbeam_channel::{unbounded, Receiver};
+    use postcard::{from_bytes, to_allocvec};
+    use rust_roveri::RustRoveri;
+    use rust_roveri_api::ChatResponse;
+    use rust_roveri_api::{ChatRequest, ClientGuiMessage, ContentResponse, GuiClientMessage};
+    use rust_roveri_api::{ClientCommand, ContentRequest};
+    use rust_roveri_api::{ClientEvent, ServerCommand};
+    use rust_roveri_api::{Request, ServerType};
+    use rust_roveri_api::{Response, ServerEvent};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+    use wg_2024::controller::DroneCommand;
+    use wg_2024::controller::DroneEvent;
+    use wg_2024::drone::Drone;
+    use wg_2024::network::{NodeId, SourceRoutingHeader};
+    use wg_2024::packet::FRAGMENT_DSIZE;
+    use wg_2024::packet::{Ack, Fragment, Nack, NackType, NodeType, Packet, PacketType};
+
+    /// Serializes and fragments a request into the packets a client would send along `hops`.
+    fn request_packets(request: &Request, session_id: u64, hops: Vec<NodeId>) -> Vec<Packet> {
+        let data = to_allocvec(request).expect("Could not convert Request to bytes");
+        data_packets(data, RequestKind::from(request), session_id, hops)
+    }
+
+    /// Splits already encoded request bytes in packets, like `request_packets`.
+    fn data_packets(
+        data: Vec<u8>,
+        kind: RequestKind,
+        session_id: u64,
+        hops: Vec<NodeId>,
+    ) -> Vec<Packet> {
+        let server_id = *hops.last().expect("Hops should not be empty");
+
+        Fragmenter::new()
+            .to_fragment_vec(AssembledResponse {
+                data,
+                dest: server_id,
+                kind,
+            })
+            .into_iter()
+            .map(|to_be_sent| Packet {
+                routing_header: SourceRoutingHeader {
+                    hop_index: hops.len() - 1,
+                    hops: hops.clone(),
+                },
+                session_id,
+                pack_type: PacketType::MsgFragment(to_be_sent.fragment),
+            })
+            .collect()
+    }
+
+    /// Reads fragments from a neighbor channel until a whole response has been assembled.
+    ///
+    /// Returns the response together with the route the server chose for it.
+    fn receive_response(packet_recv: &Receiver<Packet>) -> (Response, Vec<NodeId>) {
+        let (data, hops) = receive_data(packet_recv);
+        let response = from_bytes::<Response>(&data).expect("Invalid response");
+        (response, hops)
+    }
+
+    /// Reads fragments like `receive_response`, returning the assembled bytes as they are.
+    fn receive_data(packet_recv: &Receiver<Packet>) -> (Vec<u8>, Vec<NodeId>) {
+        let mut assemblers_manager = AssemblersManager::new();
+
+        loop {
+            let packet = packet_recv
+                .recv_timeout(Duration::from_secs(1))
+                .expect("Server did not send a response");
+
+            let fragment = match packet.pack_type {
+                PacketType::MsgFragment(fragment) => fragment,
+                other => panic!("Expected a MsgFragment, got {:?}", other),
+            };
+
+            if let Ok(AssemblerStatus::Complete) =
+                assemblers_manager.insert_fragment(fragment, packet.session_id)
+            {
+                let data = match assemblers_manager.retrieve_assembled(packet.session_id) {
+                    Ok(data) => data,
+                    Err(_) => panic!("Could not retrieve the assembled response"),
+                };
+                return (data, packet.routing_header.hops);
+            }
+        }
+    }
+
+    #[test]
+    fn reply_without_flood_test() {
+        const CLIENT_ID: NodeId = 70;
+        const DRONE_1_ID: NodeId = 71;
+        const SERVER_ID: NodeId = 72;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        // The server knows nothing but its neighbor: the route back to the client can only be
+        // learned from the routing header of the request
+        let username = "ciao".to_string();
+        let request = Request::Chat(ChatRequest::Register(username.clone(), "cane".to_string()));
+        for packet in request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+
+        let (response, hops) = receive_response(&packet_recv_rx_1);
+        assert_eq!(hops, vec![SERVER_ID, DRONE_1_ID, CLIENT_ID]);
+        match response {
+            Response::Chat(ChatResponse::ClientList(name, usernames)) => {
+                assert_eq!(name, username);
+                assert_eq!(usernames, vec![username]);
+            }
+            other => panic!("Expected a ClientList, got {:?}", other),
+        }
+
+        let _ = s00.send(ServerCommand::Crash);
+        assert!(server_handle.join().is_ok(), "Server panicked");
+    }
+
+    #[test]
+    fn learn_from_header_test() {
+        const CLIENT_ID: NodeId = 115;
+        const DRONE_1_ID: NodeId = 116;
+        const SERVER_ID: NodeId = 117;
+        const FORGED_ID: NodeId = 118;
+        const FORGED_DRONE_ID: NodeId = 119;
+
+        let (packet_recv_tx_1, _packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        while server.step().wake != Wake::OnInput {}
+
+        let packet = |hops: Vec<NodeId>, pack_type: PacketType| Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: hops.len() - 1,
+                hops,
+            },
+            session_id: 1,
+            pack_type,
+        };
+
+        // A Nack from the client does not turn it into a drone
+        let ack = PacketType::Ack(Ack { fragment_index: 0 });
+        let nack = PacketType::Nack(Nack {
+            fragment_index: 0,
+            nack_type: NackType::Dropped,
+        });
+        for pack_type in [ack.clone(), nack] {
+            let _ = packet_recv_tx_server
+                .send(packet(vec![CLIENT_ID, DRONE_1_ID, SERVER_ID], pack_type));
+            while server.step().wake != Wake::OnInput {}
+        }
+        assert!(server.topology.has_edge(DRONE_1_ID, SERVER_ID));
+        assert_eq!(server.topology.node_type(CLIENT_ID), NodeType::Client);
+
+        // A header whose last hop is not a neighbor teaches nothing
+        let _ =
+            packet_recv_tx_server.send(packet(vec![FORGED_ID, FORGED_DRONE_ID, SERVER_ID], ack));
+        while server.step().wake != Wake::OnInput {}
+        assert!(!server.topology.has_edge(FORGED_DRONE_ID, SERVER_ID));
+        assert!(!server.topology.has_edge(FORGED_ID, FORGED_DRONE_ID));
+        assert_eq!(server.topology.known_type(FORGED_ID), None);
+    }
+
+    #[test]
+    fn capture_replay_test() {
+        const CLIENT_ID: NodeId = 73;
+        const DRONE_1_ID: NodeId = 74;
+        const SERVER_ID: NodeId = 75;
+
+        let capture_path = std::env::temp_dir().join(format!(
+            "server_capture_replay_test_{}.cap",
+            std::process::id()
+        ));
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server
+            .set_capture(&capture_path)
+            .expect("Could not create capture");
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        let request = Request::Chat(ChatRequest::Register(
+            "ciao".to_string(),
+            "cane".to_string(),
+        ));
+        for packet in request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        let _ = receive_response(&packet_recv_rx_1);
+
+        let _ = s00.send(ServerCommand::Crash);
+        assert!(server_handle.join().is_ok(), "Server panicked");
+
+        let records = capture::read_capture(&capture_path).expect("Could not read capture");
+        assert!(records
+            .iter()
+            .any(|record| record.direction == capture::Direction::Inbound));
+        assert!(records
+            .iter()
+            .any(|record| record.direction == capture::Direction::Outbound));
+
+        let report = capture::replay(
+            &records,
+            ReplayOptions {
+                server_id: SERVER_ID,
+                server_type: ServerType::Chat,
+                media_path: None,
+                max_gap: Duration::from_millis(10),
+                quiet_period: Duration::from_millis(200),
+            },
+        );
+        assert!(report.is_match(), "Replay diverged: {:?}", report);
+        assert_eq!(report.actual.len(), report.expected.len());
+
+        let _ = std::fs::remove_file(&capture_path);
+    }
+
+    #[test]
+    fn step_test() {
+        const CLIENT_ID: NodeId = 76;
+        const DRONE_1_ID: NodeId = 77;
+        const SERVER_ID: NodeId = 78;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (event_tx, _event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, event_tx);
+
+        assert_eq!(server.step(), StepOutcome::default());
+
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        let request = Request::Chat(ChatRequest::Register(
+            "ciao".to_string(),
+            "cane".to_string(),
+        ));
+        for packet in request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+
+        // The command, the request and the response are all handled in a single step
+        let outcome = server.step();
+        assert!(outcome.command_handled);
+        assert!(outcome.packet_handled);
+        assert!(outcome.fragment_processed);
+        assert!(!outcome.terminated);
+        assert_eq!(outcome.wake, Wake::OnInput);
+
+        let response = packet_recv_rx_1
+            .try_recv()
+            .expect("Server did not send the response");
+
+        // While stopping, the server must wake up at the deadline even without input
+        let _ = control_tx.send(ControlCommand::GracefulStop(Duration::from_secs(5)));
+        let outcome = server.step();
+        assert!(outcome.control_command_handled);
+        assert!(matches!(outcome.wake, Wake::At(_)));
+
+        let _ = packet_recv_tx_server.send(ack_packet(&response));
+        let outcome = server.step();
+        assert!(outcome.packet_handled);
+        assert!(outcome.terminated);
+
+        assert!(server.step().terminated);
+    }
+
+    /// Builds the acknowledgment a client would send back for a response fragment.
+    fn ack_packet(packet: &Packet) -> Packet {
+        let fragment_index = match &packet.pack_type {
+            PacketType::MsgFragment(fragment) => fragment.fragment_index,
+            other => panic!("Expected a MsgFragment, got {:?}", other),
+        };
+        let hops: Vec<NodeId> = packet.routing_header.hops.iter().rev().copied().collect();
+
+        Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: hops.len() - 1,
+                hops,
+            },
+            session_id: packet.session_id,
+            pack_type: PacketType::Ack(Ack { fragment_index }),
+        }
+    }
+
+    #[test]
+    fn error_report_test() {
+        const CLIENT_1_ID: NodeId = 90;
+        const CLIENT_2_ID: NodeId = 91;
+        const CLIENT_3_ID: NodeId = 92;
+        const DRONE_1_ID: NodeId = 93;
+        const DRONE_2_ID: NodeId = 94;
+        const UNKNOWN_DRONE_ID: NodeId = 95;
+        const SERVER_ID: NodeId = 96;
+
+        let (packet_recv_tx_1, _packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_2, packet_recv_rx_2) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (event_tx, event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, event_tx);
+
+        let _ = control_tx.send(ControlCommand::Subscribe(
+            EventFilter::none().with(EventKind::Error),
+        ));
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        server.step();
+        // The second drone is already gone when the server tries to reach it
+        drop(packet_recv_rx_2);
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_2_ID, packet_recv_tx_2));
+        server.step();
+
+        let mut next_error = |packets: Vec<Packet>| {
+            for packet in packets {
+                let _ = packet_recv_tx_server.send(packet);
+            }
+            while server.step().wake != Wake::OnInput {}
+
+            match event_rx.try_recv() {
+                Ok(ControlEvent::Error(error)) => error,
+                other => panic!("Expected an Error event, got {:?}", other),
+            }
+        };
+        let request = Request::Chat(ChatRequest::Register(
+            "ciao".to_string(),
+            "cane".to_string(),
+        ));
+
+        // A Nack for a fragment that was never sent
+        let nack = Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: vec![DRONE_1_ID, SERVER_ID],
+            },
+            session_id: 7,
+            pack_type: PacketType::Nack(Nack {
+                fragment_index: 0,
+                nack_type: NackType::Dropped,
+            }),
+        };
+        assert!(matches!(
+            next_error(vec![nack]),
+            Error::UnknownFragment((7, 0))
+        ));
+
+        // Two fragments of the same message disagreeing on the number of fragments
+        let fragments = [2, 3].map(|total_n_fragments| Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 2,
+                hops: vec![CLIENT_1_ID, DRONE_1_ID, SERVER_ID],
+            },
+            session_id: 8,
+            pack_type: PacketType::MsgFragment(Fragment {
+                fragment_index: 0,
+                total_n_fragments,
+                length: 0,
+                data: [0; FRAGMENT_DSIZE],
+            }),
+        });
+        assert!(matches!(
+            next_error(fragments.to_vec()),
+            Error::Assembly {
+                session_id: 8,
+                source: InsertFragmentError::CapacityDoesNotMatch
+            }
+        ));
+
+        // The only route back goes through a drone that was never added
+        let packets = request_packets(&request, 9, vec![CLIENT_2_ID, UNKNOWN_DRONE_ID, SERVER_ID]);
+        assert!(matches!(
+            next_error(packets),
+            Error::UnknownNeighbor(UNKNOWN_DRONE_ID)
+        ));
+
+        // The only route back goes through the disconnected drone
+        let packets = request_packets(&request, 10, vec![CLIENT_3_ID, DRONE_2_ID, SERVER_ID]);
+        assert!(matches!(
+            next_error(packets),
+            Error::Transport {
+                neighbor: DRONE_2_ID,
+                source: TransportError::Disconnected
+            }
+        ));
+
+        // A request whose route starts at the server itself
+        let packets = request_packets(&request, 11, vec![SERVER_ID]);
+        assert!(matches!(
+            next_error(packets),
+            Error::Routing {
+                dest: SERVER_ID,
+                source: RoutingError::SourceIsDest
+            }
+        ));
+
+        assert!(event_rx.try_recv().is_err());
+        // The responses that could not be sent are not waiting for acknowledgments
+        assert!(server.fragment_manager.is_idle());
+
+        let _ = control_tx.send(ControlCommand::GetMetrics);
+        server.step();
+        let metrics = match event_rx.try_recv() {
+            Ok(ControlEvent::Metrics(metrics)) => metrics,
+            other => panic!("Expected Metrics, got {:?}", other),
+        };
+        assert_eq!(metrics.counter_total(metrics::ERRORS), 5);
+        assert_eq!(
+            metrics.counter(&MetricKey::labeled(metrics::ERRORS, "kind", "transport")),
+            1
+        );
+    }
+
+    #[test]
+    fn integrity_test() {
+        const CLIENT_ID: NodeId = 97;
+        const DRONE_1_ID: NodeId = 98;
+        const SERVER_ID: NodeId = 99;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (event_tx, event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, event_tx);
+        server.set_integrity(true);
+
+        let _ = control_tx.send(ControlCommand::Subscribe(
+            EventFilter::none().with(EventKind::Error),
+        ));
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        server.step();
+
+        let request = Request::Chat(ChatRequest::Register("ciao".repeat(50), "cane".to_string()));
+        let mut fragmenter = Fragmenter::new();
+        fragmenter.set_integrity(true);
+        let packets: Vec<Packet> = fragmenter
+            .to_fragment_vec(AssembledResponse {
+                data: to_allocvec(&request).expect("Could not convert Request to bytes"),
+                dest: SERVER_ID,
+                kind: RequestKind::from(&request),
+            })
+            .into_iter()
+            .map(|to_be_sent| Packet {
+                routing_header: SourceRoutingHeader {
+                    hop_index: 2,
+                    hops: vec![CLIENT_ID, DRONE_1_ID, SERVER_ID],
+                },
+                session_id: 1,
+                pack_type: PacketType::MsgFragment(to_be_sent.fragment),
+            })
+            .collect();
+        assert!(packets.len() > 1);
+
+        // Flip a bit in the first fragment
+        let mut corrupted = packets.clone();
+        if let PacketType::MsgFragment(fragment) = &mut corrupted[0].pack_type {
+            fragment.data[3] ^= 0x10;
+        }
+        for packet in corrupted {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        while server.step().wake != Wake::OnInput {}
+
+        match event_rx.try_recv() {
+            Ok(ControlEvent::Error(Error::Retrieve {
+                session_id: 1,
+                source: RetrieveError::Corrupted(IntegrityError::ChecksumMismatch),
+            })) => {}
+            other => panic!("Expected a corrupted message error, got {:?}", other),
+        }
+
+        // The client is asked to send every fragment of the session again
+        let nacked: Vec<u64> = packet_recv_rx_1
+            .try_iter()
+            .map(|packet| {
+                assert_eq!(
+                    packet.routing_header.hops,
+                    vec![SERVER_ID, DRONE_1_ID, CLIENT_ID]
+                );
+                match packet.pack_type {
+                    PacketType::Nack(Nack {
+                        fragment_index,
+                        nack_type: NackType::Dropped,
+                    }) => fragment_index,
+                    other => panic!("Expected a Nack, got {:?}", other),
+                }
+            })
+            .collect();
+        assert_eq!(nacked, (0..packets.len() as u64).collect::<Vec<_>>());
+
+        // The retransmitted session is accepted, and the response carries a trailer too
+        for packet in packets {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        while server.step().wake != Wake::OnInput {}
+
+        let (response, _) = receive_response(&packet_recv_rx_1);
+        assert!(matches!(response, Response::Chat(_)));
+        assert!(event_rx.try_recv().is_err());
+
+        // Requests without a trailer are rejected
+        let request = Request::Chat(ChatRequest::Logout("ciao".repeat(50)));
+        for packet in request_packets(&request, 2, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        while server.step().wake != Wake::OnInput {}
+
+        match event_rx.try_recv() {
+            Ok(ControlEvent::Error(Error::Retrieve {
+                session_id: 2,
+                source: RetrieveError::Corrupted(IntegrityError::MissingTrailer),
+            })) => {}
+            other => panic!("Expected a missing trailer error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compression_test() {
+        const CLIENT_ID: NodeId = 100;
+        const DRONE_1_ID: NodeId = 101;
+        const SERVER_ID: NodeId = 102;
+
+        let dir =
+            std::env::temp_dir().join(format!("server_compression_test_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let content = "Il cane abbaia alla luna. ".repeat(400).into_bytes();
+        std::fs::write(dir.join("cane.txt"), &content).expect("Could not write the content");
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(
+            SERVER_ID,
+            r01,
+            packet_recv_rx_server,
+            s10,
+            ServerType::ContentText,
+        );
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        let _ = s00.send(ServerCommand::SetMediaPath(dir.clone()));
+        while server.step().wake != Wake::OnInput {}
+
+        let request = Request::Content(ContentRequest::Content("cane.txt".to_string()));
+        let hops = vec![CLIENT_ID, DRONE_1_ID, SERVER_ID];
+
+        // A client that does not know about compression receives the plain content
+        for packet in request_packets(&request, 1, hops.clone()) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        while server.step().wake != Wake::OnInput {}
+        let plain: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+        let (response, _) = receive_response(&channel_of(plain.clone()));
+        assert!(matches!(
+            response,
+            Response::Content(ContentResponse::Content(_, _, data)) if data == content
+        ));
+
+        // A client that sends its request in an envelope receives it compressed
+        let data = compression::encode(
+            &to_allocvec(&request).expect("Could not convert Request to bytes"),
+            usize::MAX,
+        );
+        for to_be_sent in Fragmenter::starting_at(2).to_fragment_vec(AssembledResponse {
+            data,
+            dest: SERVER_ID,
+            kind: RequestKind::from(&request),
+        }) {
+            let _ = packet_recv_tx_server.send(Packet {
+                routing_header: SourceRoutingHeader {
+                    hop_index: 2,
+                    hops: hops.clone(),
+                },
+                session_id: to_be_sent.session_id,
+                pack_type: PacketType::MsgFragment(to_be_sent.fragment),
+            });
+        }
+        while server.step().wake != Wake::OnInput {}
+        let compressed: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+        assert!(compressed.len() < plain.len() / 4);
+
+        let mut assemblers_manager = AssemblersManager::new();
+        let mut data = None;
+        for packet in compressed {
+            if let PacketType::MsgFragment(fragment) = packet.pack_type {
+                if let Ok(AssemblerStatus::Complete) =
+                    assemblers_manager.insert_fragment(fragment, packet.session_id)
+                {
+                    data = assemblers_manager
+                        .retrieve_assembled(packet.session_id)
+                        .ok();
+                }
+            }
+        }
+        let data = data.expect("Server did not send a whole response");
+        let (data, enveloped) = compression::decode(data).expect("Invalid envelope");
+        assert!(enveloped);
+        assert!(matches!(
+            from_bytes::<Response>(&data),
+            Ok(Response::Content(ContentResponse::Content(_, _, data))) if data == content
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn compression_forward_test() {
+        const CLIENT_1_ID: NodeId = 120;
+        const CLIENT_2_ID: NodeId = 121;
+        const DRONE_1_ID: NodeId = 122;
+        const SERVER_ID: NodeId = 123;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        let mut send_request = |request: ChatRequest, client_id, session_id, compress| {
+            let request = Request::Chat(request);
+            let mut data = to_allocvec(&request).expect("Could not convert Request to bytes");
+            if compress {
+                data = compression::encode(&data, usize::MAX);
+            }
+            let hops = vec![client_id, DRONE_1_ID, SERVER_ID];
+            for packet in data_packets(data, RequestKind::from(&request), session_id, hops) {
+                let _ = packet_recv_tx_server.send(packet);
+            }
+            while server.step().wake != Wake::OnInput {}
+            let packets: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+            receive_data(&channel_of(packets))
+        };
+
+        let (ciao, cane) = ("ciao".to_string(), "cane".to_string());
+        send_request(
+            ChatRequest::Register(cane.clone(), "pass".to_string()),
+            CLIENT_2_ID,
+            1,
+            false,
+        );
+        send_request(
+            ChatRequest::Register(ciao.clone(), "pass".to_string()),
+            CLIENT_1_ID,
+            2,
+            true,
+        );
+
+        // The message reaches a recipient that never negotiated compression without an envelope
+        let (data, hops) = send_request(
+            ChatRequest::Message(ciao.clone(), cane, "Ciao".to_string()),
+            CLIENT_1_ID,
+            3,
+            true,
+        );
+        assert_eq!(hops.last(), Some(&CLIENT_2_ID));
+        assert!(matches!(
+            from_bytes::<Response>(&data),
+            Ok(Response::Chat(ChatResponse::Message(sender, _))) if sender == ciao
+        ));
+    }
+
+    #[test]
+    fn fec_test() {
+        const CLIENT_ID: NodeId = 103;
+        const DRONE_1_ID: NodeId = 104;
+        const SERVER_ID: NodeId = 105;
+
+        let dir = std::env::temp_dir().join(format!("server_fec_test_{}", std::process::id()));
+        let _ = std::fs::create_dir_all(&dir);
+        let content: Vec<u8> = (0..2000).map(|i| b'a' + (i % 26) as u8).collect();
+        std::fs::write(dir.join("cane.txt"), &content).expect("Could not write the content");
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(
+            SERVER_ID,
+            r01,
+            packet_recv_rx_server,
+            s10,
+            ServerType::ContentText,
+        );
+        server.set_fec(true);
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        let _ = s00.send(ServerCommand::SetMediaPath(dir.clone()));
+        while server.step().wake != Wake::OnInput {}
+
+        // The drone is seen dropping a packet
+        let _ = packet_recv_tx_server.send(Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: vec![DRONE_1_ID, SERVER_ID],
+            },
+            session_id: 0,
+            pack_type: PacketType::Nack(Nack {
+                fragment_index: 0,
+                nack_type: NackType::Dropped,
+            }),
+        });
+
+        // A client that does not ask for parity fragments receives none
+        let request = Request::Content(ContentRequest::Content("cane.txt".to_string()));
+        for packet in request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        while server.step().wake != Wake::OnInput {}
+        assert!(packet_recv_rx_1.try_iter().all(|packet| {
+            !matches!(&packet.pack_type, PacketType::MsgFragment(fragment)
+                if is_parity(fragment.fragment_index))
+        }));
+
+        let data = fec::encode(&to_allocvec(&request).expect("Could not serialize the request"));
+        let packets = data_packets(
+            data,
+            RequestKind::from(&request),
+            2,
+            vec![CLIENT_ID, DRONE_1_ID, SERVER_ID],
+        );
+        for packet in packets {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        while server.step().wake != Wake::OnInput {}
+
+        let fragments: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+        let parity = fragments
+            .iter()
+            .filter(|packet| {
+                matches!(&packet.pack_type, PacketType::MsgFragment(fragment)
+                    if is_parity(fragment.fragment_index))
+            })
+            .count();
+        assert!(parity > 0);
+        assert_eq!(
+            server
+                .metrics
+                .counter(&MetricKey::new(metrics::PARITY_FRAGMENTS_SENT)),
+            parity as u64
+        );
+
+        // A data fragment is lost, and rebuilt by the client without a retransmission
+        let mut assemblers_manager = AssemblersManager::new();
+        let mut data = None;
+        for packet in fragments {
+            if let PacketType::MsgFragment(fragment) = packet.pack_type {
+                if fragment.fragment_index == 3 {
+                    continue;
+                }
+                if let Ok(AssemblerStatus::Complete) =
+                    assemblers_manager.insert_fragment(fragment, packet.session_id)
+                {
+                    data = assemblers_manager
+                        .retrieve_assembled(packet.session_id)
+                        .ok();
+                }
+            }
+        }
+        let data = data.expect("Client could not rebuild the response");
+        assert!(matches!(
+            from_bytes::<Response>(&data),
+            Ok(Response::Content(ContentResponse::Content(_, _, data))) if data == content
+        ));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replay_test() {
+        const CLIENT_ID: NodeId = 106;
+        const DRONE_1_ID: NodeId = 107;
+        const SERVER_ID: NodeId = 108;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        let username = "ciao".to_string();
+        let request = Request::Chat(ChatRequest::Register(username.clone(), "cane".to_string()));
+        let hops = vec![CLIENT_ID, DRONE_1_ID, SERVER_ID];
+        let mut send_request = |session_id| {
+            for packet in request_packets(&request, session_id, hops.clone()) {
+                let _ = packet_recv_tx_server.send(packet);
+            }
+            while server.step().wake != Wake::OnInput {}
+            let packets: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+            receive_response(&channel_of(packets)).0
+        };
+
+        let first = send_request(1);
+        assert!(matches!(
+            &first,
+            Response::Chat(ChatResponse::ClientList(..))
+        ));
+
+        // The client never saw the response and sends the same request again
+        let replayed = send_request(1);
+        assert_eq!(
+            to_allocvec(&replayed).expect("Could not serialize the response"),
+            to_allocvec(&first).expect("Could not serialize the response")
+        );
+
+        // A new request in another session is processed
+        assert!(matches!(
+            send_request(2),
+            Response::Chat(ChatResponse::RegisterFailure(name, _)) if name == username
+        ));
+
+        assert_eq!(
+            server.metrics.counter(&MetricKey::labeled(
+                metrics::REQUESTS_REPLAYED,
+                "kind",
+                "register"
+            )),
+            1
+        );
+        assert_eq!(
+            server
+                .metrics
+                .counter(&MetricKey::labeled(metrics::REQUESTS, "kind", "register")),
+            2
+        );
+
+        // A retransmitted message is not delivered to its recipient twice
+        const CLIENT_2_ID: NodeId = 124;
+        let request = Request::Chat(ChatRequest::Register(
+            "cane".to_string(),
+            "ciao".to_string(),
+        ));
+        for packet in request_packets(&request, 1, vec![CLIENT_2_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        while server.step().wake != Wake::OnInput {}
+        let _ = packet_recv_rx_1.try_iter().count();
+
+        let message = Request::Chat(ChatRequest::Message(
+            username.clone(),
+            "cane".to_string(),
+            "Ciao".to_string(),
+        ));
+        let mut copies = 0;
+        for _ in 0..2 {
+            for packet in request_packets(&message, 3, hops.clone()) {
+                let _ = packet_recv_tx_server.send(packet);
+            }
+            while server.step().wake != Wake::OnInput {}
+            let packets: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+            if !packets.is_empty() {
+                let (response, hops) = receive_response(&channel_of(packets));
+                assert_eq!(hops.last(), Some(&CLIENT_2_ID));
+                assert!(matches!(
+                    response,
+                    Response::Chat(ChatResponse::Message(..))
+                ));
+                copies += 1;
+            }
+        }
+        assert_eq!(copies, 1);
+        assert_eq!(
+            server.metrics.counter(&MetricKey::labeled(
+                metrics::REQUESTS_REPLAYED,
+                "kind",
+                "message"
+            )),
+            1
+        );
+    }
+
+    #[test]
+    fn rate_limit_test() {
+        const CLIENT_ID: NodeId = 109;
+        const DRONE_1_ID: NodeId = 110;
+        const SERVER_ID: NodeId = 111;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (event_tx, event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, event_tx);
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        let _ = control_tx.send(ControlCommand::SetRateLimits(RateLimits {
+            requests: Some(RateLimit::new(0.01, 2.0)),
+            bytes: None,
+        }));
+        while server.step().wake != Wake::OnInput {}
+
+        let hops = vec![CLIENT_ID, DRONE_1_ID, SERVER_ID];
+        let mut send_request = |session_id| {
+            let request = Request::Chat(ChatRequest::Register(
+                format!("ciao{}", session_id),
+                "cane".to_string(),
+            ));
+            for packet in request_packets(&request, session_id, hops.clone()) {
+                let _ = packet_recv_tx_server.send(packet);
+            }
+            while server.step().wake != Wake::OnInput {}
+            let packets: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+            receive_response(&channel_of(packets)).0
+        };
+
+        for session_id in 1..=2 {
+            assert!(matches!(
+                send_request(session_id),
+                Response::Chat(ChatResponse::ClientList(..))
+            ));
+        }
+        assert!(matches!(
+            send_request(3),
+            Response::Content(ContentResponse::InternalServerError(_))
+        ));
+        // A retried request is replayed even while throttled
+        assert!(matches!(
+            send_request(2),
+            Response::Chat(ChatResponse::ClientList(..))
+        ));
+
+        let _ = control_tx.send(ControlCommand::GetMetrics);
+        server.step();
+        let metrics = match event_rx.try_recv() {
+            Ok(ControlEvent::Metrics(metrics)) => metrics,
+            other => panic!("Expected Metrics, got {:?}", other),
+        };
+        assert_eq!(
+            metrics.counter(&MetricKey::labeled(
+                metrics::REQUESTS_THROTTLED,
+                "initiator",
+                CLIENT_ID.to_string()
+            )),
+            1
+        );
+        assert_eq!(
+            metrics.counter(&MetricKey::labeled(metrics::REQUESTS, "kind", "register")),
+            2
+        );
+    }
+
+    #[test]
+    fn replay_byte_limit_test() {
+        const CLIENT_ID: NodeId = 125;
+        const DRONE_1_ID: NodeId = 126;
+        const SERVER_ID: NodeId = 127;
+
+        let dir = std::env::temp_dir().join(format!(
+            "server_replay_byte_limit_test_{}",
+            std::process::id()
+        ));
+        let _ = std::fs::create_dir_all(&dir);
+        let content: Vec<u8> = (0..4000).map(|i| b'a' + (i % 26) as u8).collect();
+        std::fs::write(dir.join("cane.txt"), &content).expect("Could not write the content");
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(
+            SERVER_ID,
+            r01,
+            packet_recv_rx_server,
+            s10,
+            ServerType::ContentText,
+        );
+        server.set_rate_limits(RateLimits {
+            requests: None,
+            bytes: Some(RateLimit::new(1.0, 1000.0)),
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+        let _ = s00.send(ServerCommand::SetMediaPath(dir.clone()));
+        while server.step().wake != Wake::OnInput {}
+
+        let request = Request::Content(ContentRequest::Content("cane.txt".to_string()));
+        let hops = vec![CLIENT_ID, DRONE_1_ID, SERVER_ID];
+        let mut send_request = || {
+            for packet in request_packets(&request, 1, hops.clone()) {
+                let _ = packet_recv_tx_server.send(packet);
+            }
+            while server.step().wake != Wake::OnInput {}
+            let packets: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+            receive_response(&channel_of(packets)).0
+        };
+
+        assert!(matches!(
+            send_request(),
+            Response::Content(ContentResponse::Content(_, _, data)) if data == content
+        ));
+        // The content left the byte bucket in debt, so resending the request is not a way
+        // around the limit
+        for _ in 0..5 {
+            assert!(matches!(
+                send_request(),
+                Response::Content(ContentResponse::InternalServerError(_))
+            ));
+        }
+
+        assert_eq!(
+            server.metrics.counter(&MetricKey::labeled(
+                metrics::REQUESTS_REPLAYED,
+                "kind",
+                "content"
+            )),
+            0
+        );
+        assert_eq!(
+            server.metrics.counter(&MetricKey::labeled(
+                metrics::REQUESTS_THROTTLED,
+                "initiator",
+                CLIENT_ID.to_string()
+            )),
+            5
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn token_test() {
+        const CLIENT_ID: NodeId = 112;
+        const DRONE_1_ID: NodeId = 113;
+        const SERVER_ID: NodeId = 114;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        let username = "ciao".to_string();
+        let hops = vec![CLIENT_ID, DRONE_1_ID, SERVER_ID];
+        let mut send_request = |request: ChatRequest, token: Option<&SessionToken>, session_id| {
+            let request = Request::Chat(request);
+            let data = token::encode(
+                token,
+                &to_allocvec(&request).expect("Could not convert Request to bytes"),
+            );
+            for packet in data_packets(data, RequestKind::from(&request), session_id, hops.clone())
+            {
+                let _ = packet_recv_tx_server.send(packet);
+            }
+            while server.step().wake != Wake::OnInput {}
+            let packets: Vec<Packet> = packet_recv_rx_1.try_iter().collect();
+            let (data, _) = receive_data(&channel_of(packets));
+            let (data, presented) = token::decode(data).expect("Invalid token envelope");
+            let response = from_bytes::<Response>(&data).expect("Invalid response");
+            (response, presented)
+        };
+
+        let (response, presented) = send_request(
+            ChatRequest::Register(username.clone(), "cane".to_string()),
+            None,
+            1,
+        );
+        assert!(matches!(
+            response,
+            Response::Chat(ChatResponse::ClientList(..))
+        ));
+        let token = presented.token().expect("No token was issued");
+
+        // The token is required from then on
+        let (response, _) = send_request(ChatRequest::ClientList(username.clone()), None, 2);
+        assert!(matches!(
+            response,
+            Response::Chat(ChatResponse::ClientListFailure(..))
+        ));
+        let (response, presented) =
+            send_request(ChatRequest::ClientList(username.clone()), Some(&token), 3);
+        assert!(matches!(
+            response,
+            Response::Chat(ChatResponse::ClientList(..))
+        ));
+        assert_eq!(presented, Presented::Enveloped(None));
+    }
+
+    /// Puts already received packets back on a channel, so they can be read with
+    /// `receive_response`.
+    fn channel_of(packets: Vec<Packet>) -> Receiver<Packet> {
+        let (packet_send, packet_recv) = unbounded();
+        for packet in packets {
+            let _ = packet_send.send(packet);
+        }
+        packet_recv
+    }
+
+    #[test]
+    fn graceful_stop_test() {
+        const CLIENT_ID: NodeId = 70;
+        const DRONE_1_ID: NodeId = 71;
+        const SERVER_ID: NodeId = 72;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (control_event_tx, control_event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, control_event_tx);
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        // Get a response but do not acknowledge it yet
+        let request = Request::Chat(ChatRequest::Register("ciao".repeat(100), "cane".into()));
+        for packet in request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        let mut response_packets = Vec::new();
+        while let Ok(packet) = packet_recv_rx_1.recv_timeout(Duration::from_millis(200)) {
+            if let PacketType::MsgFragment(_) = packet.pack_type {
+                response_packets.push(packet);
+            }
+        }
+        assert!(
+            !response_packets.is_empty(),
+            "Server did not send a response"
+        );
+
+        let _ = control_tx.send(ControlCommand::GracefulStop(Duration::from_secs(10)));
+
+        // New requests are refused, and the refusal is not waited for
+        let request = Request::Chat(ChatRequest::Register("come".into(), "va".into()));
+        for packet in request_packets(&request, 2, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        let (response, _) = receive_response(&packet_recv_rx_1);
+        assert!(matches!(
+            response,
+            Response::Content(ContentResponse::InternalServerError(_))
+        ));
+        assert!(
+            control_event_rx.try_recv().is_err(),
+            "Server stopped too early"
+        );
+
+        // Acknowledging the fragments of the first response lets the server stop
+        for packet in response_packets.iter() {
+            let _ = packet_recv_tx_server.send(ack_packet(packet));
+        }
+
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::ShutdownSummary(summary)) => {
+                assert!(summary.drained);
+                assert_eq!(summary.refused_requests, 1);
+                assert_eq!(summary.abandoned_sessions, 0);
+                assert!(summary.flushed);
+            }
+            other => panic!("Expected a shutdown summary, got {:?}", other),
+        }
+        assert!(server_handle.join().is_ok(), "Server panicked");
+    }
+
+    #[test]
+    fn graceful_stop_timeout_test() {
+        const CLIENT_ID: NodeId = 70;
+        const DRONE_1_ID: NodeId = 71;
+        const SERVER_ID: NodeId = 72;
+
+        let (packet_recv_tx_1, _packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (control_event_tx, control_event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, control_event_tx);
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        // The response is never acknowledged
+        let request = Request::Chat(ChatRequest::Register("ciao".into(), "cane".into()));
+        for packet in request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let _ = control_tx.send(ControlCommand::GracefulStop(Duration::from_millis(200)));
+
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::ShutdownSummary(summary)) => {
+                assert!(!summary.drained);
+                assert!(summary.unacknowledged_fragments > 0);
+                assert_eq!(summary.refused_requests, 0);
+            }
+            other => panic!("Expected a shutdown summary, got {:?}", other),
+        }
+        assert!(server_handle.join().is_ok(), "Server panicked");
+    }
+
+    #[test]
+    fn telemetry_test() {
+        const CLIENT_ID: NodeId = 70;
+        const DRONE_1_ID: NodeId = 71;
+        const SERVER_ID: NodeId = 72;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (control_event_tx, control_event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, control_event_tx);
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        // Everything but the received packets
+        let filter = EventFilter::none()
+            .with(EventKind::MessageAssembled)
+            .with(EventKind::RequestProcessed)
+            .with(EventKind::ResponseAcknowledged)
+            .with(EventKind::Retransmission);
+        let _ = control_tx.send(ControlCommand::Subscribe(filter));
+
+        let request = Request::Chat(ChatRequest::Register("ciao".into(), "cane".into()));
+        for packet in request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
 
-                let packet = Packet {
-                    pack_type: PacketType::MsgFragment(to_be_sent_fragment.fragment),
-                    routing_header: header,
-                    session_id: to_be_sent_fragment.session_id,
-                };
-                self.send_packet(packet);
-            }
-            Err(RoutingError::SourceIsDest) => {
-                error!("{} Cant send a packet to myself", self.get_prefix())
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::MessageAssembled {
+                session_id,
+                initiator_id,
+                ..
+            }) => {
+                assert_eq!(session_id, 1);
+                assert_eq!(initiator_id, CLIENT_ID);
             }
-            Err(RoutingError::NoPathFound) => {
-                //if the topology is still updating its ok to not find the path
-                //=> reinsert the packet in the buffer
-                let _ = self.fragment_manager.insert_from_cache((
-                    to_be_sent_fragment.session_id,
-                    to_be_sent_fragment.fragment.fragment_index,
-                ));
-                if !self.topology.is_updating() {
-                    //self.topology.reset();
-                    self.start_network_discovery();
-                }
+            other => panic!("Expected MessageAssembled, got {:?}", other),
+        }
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::RequestProcessed {
+                kind, initiator_id, ..
+            }) => {
+                assert_eq!(kind, RequestKind::Register);
+                assert_eq!(initiator_id, CLIENT_ID);
             }
+            other => panic!("Expected RequestProcessed, got {:?}", other),
         }
-    }
 
-    /// Retrieves the sender for the next hop in the packet's routing path.
-    fn get_sender(&self, packet: &Packet) -> Option<&Sender<Packet>> {
-        let next_index = packet.routing_header.hop_index;
-        let next_id = packet.routing_header.hops.get(next_index)?;
-        self.packet_send.get(next_id)
-    }
+        let response = packet_recv_rx_1
+            .recv_timeout(Duration::from_secs(1))
+            .expect("Server did not send a response");
 
-    /// Sends a packet to the next hop in its routing path.
-    fn send_packet(&self, packet: Packet) {
-        match self.get_sender(&packet) {
-            Some(sender) => {
-                self.send_packet_to_sender(packet, sender);
-            }
-            None => {
-                let message = format!("{} Could not reach neighbor", self.get_prefix());
-                error!("{}", message);
+        // A dropped fragment is retransmitted
+        let nack = Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: vec![DRONE_1_ID, SERVER_ID],
+            },
+            session_id: response.session_id,
+            pack_type: PacketType::Nack(Nack {
+                fragment_index: 0,
+                nack_type: NackType::Dropped,
+            }),
+        };
+        let _ = packet_recv_tx_server.send(nack);
+
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::Retransmission {
+                fragment_index,
+                reason: RetransmissionReason::Nack(NackType::Dropped),
+                ..
+            }) => assert_eq!(fragment_index, 0),
+            other => panic!("Expected Retransmission, got {:?}", other),
+        }
+
+        // Acknowledging the only fragment completes the response
+        let retransmitted = packet_recv_rx_1
+            .recv_timeout(Duration::from_secs(1))
+            .expect("Server did not retransmit the fragment");
+        let _ = packet_recv_tx_server.send(ack_packet(&retransmitted));
+
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::ResponseAcknowledged { session_id, dest }) => {
+                assert_eq!(session_id, response.session_id);
+                assert_eq!(dest, CLIENT_ID);
             }
+            other => panic!("Expected ResponseAcknowledged, got {:?}", other),
         }
+
+        let _ = s00.send(ServerCommand::Crash);
+        assert!(server_handle.join().is_ok(), "Server panicked");
     }
 
-    /// Sends a packet to a specific sender.
-    fn send_packet_to_sender(&self, packet: Packet, sender: &Sender<Packet>) {
-        if sender.send(packet.clone()).is_ok() {
-            self.send_server_event(ServerEvent::PacketSent(packet));
-        } else {
-            let message = format!("{} The drone is disconnected", self.get_prefix());
-            error!("{}", message);
+    #[test]
+    fn metrics_test() {
+        const CLIENT_ID: NodeId = 70;
+        const DRONE_1_ID: NodeId = 71;
+        const SERVER_ID: NodeId = 72;
+
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (control_event_tx, control_event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, control_event_tx);
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        // Register, the response spans several fragments
+        let request = Request::Chat(ChatRequest::Register("ciao".repeat(100), "cane".into()));
+        let request_packets = request_packets(&request, 1, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]);
+        let request_fragments = request_packets.len() as u64;
+        for packet in request_packets {
+            let _ = packet_recv_tx_server.send(packet);
         }
-    }
+        let mut response_packets = Vec::new();
+        while let Ok(packet) = packet_recv_rx_1.recv_timeout(Duration::from_millis(200)) {
+            response_packets.push(packet);
+        }
+        let response_fragments = response_packets.len() as u64;
 
-    /// Sends an event to the server controller.
-    fn send_server_event(&self, server_event: ServerEvent) {
-        if self.controller_send.send(server_event).is_err() {
-            let message = format!("{} The controller is disconnected", self.get_prefix());
-            error!("{}", message);
+        // The first fragment is dropped once, then everything is acknowledged
+        let nack = Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: vec![DRONE_1_ID, SERVER_ID],
+            },
+            session_id: response_packets[0].session_id,
+            pack_type: PacketType::Nack(Nack {
+                fragment_index: 0,
+                nack_type: NackType::Dropped,
+            }),
+        };
+        let _ = packet_recv_tx_server.send(nack);
+        let _ = packet_recv_rx_1
+            .recv_timeout(Duration::from_secs(1))
+            .expect("Server did not retransmit the fragment");
+        for packet in response_packets.iter() {
+            let _ = packet_recv_tx_server.send(ack_packet(packet));
         }
-    }
 
-    /// Retrieves the server's logging prefix.
-    fn get_prefix(&self) -> String {
-        format!("[SERVER {}]", self.id)
-    }
-}
+        // A request that is not a chat request
+        let request = Request::Content(ContentRequest::List);
+        for packet in request_packets(&request, 2, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]) {
+            let _ = packet_recv_tx_server.send(packet);
+        }
+        let _ = receive_response(&packet_recv_rx_1);
 
-#[cfg(test)]
-mod tests {
-    use crate::Server;
-    use client::client::Client;
-    use crossbeam_channel::unbounded;
-    use postcard::{from_bytes, to_allocvec};
-    use rust_roveri::RustRoveri;
-    use rust_roveri_api::ChatResponse;
-    use rust_roveri_api::{ChatRequest, ClientGuiMessage, ContentResponse, GuiClientMessage};
-    use rust_roveri_api::{ClientCommand, ContentRequest};
-    use rust_roveri_api::{ClientEvent, ServerCommand};
-    use rust_roveri_api::{Request, ServerType};
-    use rust_roveri_api::{Response, ServerEvent};
-    use std::collections::HashMap;
-    use std::path::PathBuf;
-    use std::thread;
-    use std::time::Duration;
-    use wg_2024::controller::DroneCommand;
-    use wg_2024::controller::DroneEvent;
-    use wg_2024::drone::Drone;
-    use wg_2024::network::NodeId;
-    use wg_2024::packet::Packet;
+        let _ = control_tx.send(ControlCommand::GetMetrics);
+        let snapshot = match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::Metrics(snapshot)) => snapshot,
+            other => panic!("Expected metrics, got {:?}", other),
+        };
+
+        assert_eq!(
+            snapshot.counter(&MetricKey::new(metrics::FRAGMENTS_RECEIVED)),
+            request_fragments + 1
+        );
+        assert_eq!(
+            snapshot.counter(&MetricKey::new(metrics::FRAGMENTS_SENT)),
+            response_fragments + 2
+        );
+        assert_eq!(
+            snapshot.counter(&MetricKey::new(metrics::FRAGMENTS_RETRANSMITTED)),
+            1
+        );
+        assert_eq!(
+            snapshot.counter(&MetricKey::labeled(
+                metrics::NACKS_RECEIVED,
+                "type",
+                "dropped"
+            )),
+            1
+        );
+        assert_eq!(
+            snapshot.counter(&MetricKey::new(metrics::ACKS_RECEIVED)),
+            response_fragments
+        );
+        assert_eq!(
+            snapshot.counter(&MetricKey::new(metrics::RESPONSES_ACKNOWLEDGED)),
+            1
+        );
+        assert_eq!(
+            snapshot.counter(&MetricKey::labeled(metrics::REQUESTS, "kind", "register")),
+            1
+        );
+        assert_eq!(
+            snapshot.counter(&MetricKey::labeled(
+                metrics::REQUEST_ERRORS,
+                "error",
+                "unexpected_request"
+            )),
+            1
+        );
+        assert_eq!(
+            snapshot
+                .histogram(&MetricKey::labeled(
+                    metrics::END_TO_END_LATENCY,
+                    "kind",
+                    "register"
+                ))
+                .map(|histogram| histogram.count()),
+            Some(1)
+        );
+
+        // The same values are exported in the Prometheus format
+        let path = std::env::temp_dir().join("server_metrics_test.prom");
+        let _ = control_tx.send(ControlCommand::ExportMetrics(path.clone()));
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::MetricsExported(exported, Ok(()))) => assert_eq!(exported, path),
+            other => panic!("Expected a successful export, got {:?}", other),
+        }
+        let text = std::fs::read_to_string(&path).expect("Could not read exported metrics");
+        assert!(text.contains("server_requests_total{kind=\"register\"} 1\n"));
+        let _ = std::fs::remove_file(&path);
+
+        let _ = s00.send(ServerCommand::Crash);
+        assert!(server_handle.join().is_ok(), "Server panicked");
+    }
 
     #[test]
     fn add_drone_test() {
@@ -482,6 +2798,46 @@ mod tests {
         assert!(server_handle.join().is_ok(), "Server panicked");
     }
 
+    #[test]
+    fn get_topology_test() {
+        const DRONE_1_ID: NodeId = 71;
+        let (packet_recv_tx_1, _packet_recv_rx_1) = unbounded::<Packet>();
+
+        //Create Server
+        const SERVER_ID: NodeId = 72;
+        let (_packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (s00, r01) = unbounded::<ServerCommand>();
+        let (s10, _r11) = unbounded::<ServerEvent>();
+        let (control_tx, control_rx) = unbounded::<ControlCommand>();
+        let (control_event_tx, control_event_rx) = unbounded::<ControlEvent>();
+
+        let mut server = Server::new(SERVER_ID, r01, packet_recv_rx_server, s10, ServerType::Chat);
+        server.set_control_channels(control_rx, control_event_tx);
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        let _ = s00.send(ServerCommand::AddDrone(DRONE_1_ID, packet_recv_tx_1));
+
+        thread::sleep(Duration::from_millis(100));
+        let _ = control_tx.send(ControlCommand::GetTopology);
+
+        match control_event_rx.recv_timeout(Duration::from_secs(1)) {
+            Ok(ControlEvent::Topology(snapshot)) => {
+                assert_eq!(snapshot.node_id, SERVER_ID);
+                assert_eq!(snapshot.edges, vec![(DRONE_1_ID, SERVER_ID)]);
+                // The controller can export what it received for debugging tools
+                let dot = snapshot.to_dot();
+                assert!(dot.starts_with("graph topology_72 {"));
+                assert!(dot.contains("72 [label=\"72 Server\\npdr 0.50\", shape=box, style=bold];"));
+                assert!(dot.contains("    71 -- 72;"));
+            }
+            other => panic!("Expected a topology snapshot, got {:?}", other),
+        }
+
+        let _ = s00.send(ServerCommand::Crash);
+        assert!(server_handle.join().is_ok(), "Server panicked");
+    }
+
     #[test]
     fn set_media_path() {
         //Create Server
@@ -1556,4 +3912,202 @@ mod tests {
         assert!(client_1_handle.join().is_ok());
         assert!(client_2_handle.join().is_ok());
     }
+
+    #[test]
+    fn test_remove_drone_mid_transfer() {
+        // Topology:
+        //   -- d1 --
+        //  /        \
+        // c          s
+        //  \        /
+        //   -- d2 --
+        //
+        // The server is told to remove the drone it is using while the response is being sent
+
+        // Set parameters
+        const CLIENT_ID: NodeId = 70;
+        const DRONE_1_ID: NodeId = 71;
+        const DRONE_2_ID: NodeId = 72;
+        const SERVER_ID: NodeId = 73;
+        const PDR: f32 = 0.0;
+        let username = "ciao".repeat(5000);
+        let password = "cane".to_string();
+
+        // Create browser
+        let (message_sender_tx, message_sender_rx) = unbounded();
+        let (message_receiver_tx, message_receiver_rx) = unbounded();
+
+        // Create client channels
+        let (packet_recv_tx_client, packet_recv_rx_client) = unbounded::<Packet>();
+        let (command_recv_tx_client, command_recv_rx_client) = unbounded::<ClientCommand>();
+        let (event_send_tx_client, _event_send_rx_client) = unbounded::<ClientEvent>();
+
+        // Create drone 1 channels
+        let (controller_send_tx_1, _controller_send_rx_1) = unbounded::<DroneEvent>();
+        let (controller_recv_tx_1, controller_recv_rx_1) = unbounded::<DroneCommand>();
+        let (packet_recv_tx_1, packet_recv_rx_1) = unbounded::<Packet>();
+
+        // Create drone 2 channels
+        let (controller_send_tx_2, _controller_send_rx_2) = unbounded::<DroneEvent>();
+        let (controller_recv_tx_2, controller_recv_rx_2) = unbounded::<DroneCommand>();
+        let (packet_recv_tx_2, packet_recv_rx_2) = unbounded::<Packet>();
+
+        // Create server channels
+        let (packet_recv_tx_server, packet_recv_rx_server) = unbounded::<Packet>();
+        let (command_recv_tx_server, command_recv_rx_server) = unbounded::<ServerCommand>();
+        let (event_send_tx_server, event_send_rx_server) = unbounded::<ServerEvent>();
+
+        // Create client
+        let mut client = Client::new(
+            CLIENT_ID,
+            packet_recv_rx_client,
+            command_recv_rx_client,
+            event_send_tx_client,
+            message_sender_rx,
+            message_receiver_tx,
+        );
+        command_recv_tx_client
+            .send(ClientCommand::AddDrone(
+                DRONE_1_ID,
+                packet_recv_tx_1.clone(),
+            ))
+            .expect("Cannot add drone 1 to client neighbors");
+        command_recv_tx_client
+            .send(ClientCommand::AddDrone(
+                DRONE_2_ID,
+                packet_recv_tx_2.clone(),
+            ))
+            .expect("Cannot add drone 2 to client neighbors");
+        let client_handle = thread::spawn(move || {
+            client.run();
+        });
+
+        // Create drones
+        let drones = [
+            (
+                DRONE_1_ID,
+                controller_send_tx_1,
+                controller_recv_rx_1,
+                packet_recv_rx_1,
+                &controller_recv_tx_1,
+            ),
+            (
+                DRONE_2_ID,
+                controller_send_tx_2,
+                controller_recv_rx_2,
+                packet_recv_rx_2,
+                &controller_recv_tx_2,
+            ),
+        ];
+        let mut drone_handles = Vec::new();
+        for (drone_id, controller_send, controller_recv, packet_recv, controller) in drones {
+            let mut drone = RustRoveri::new(
+                drone_id,
+                controller_send,
+                controller_recv,
+                packet_recv,
+                HashMap::new(),
+                PDR,
+            );
+            drone_handles.push(thread::spawn(move || drone.run()));
+            controller
+                .send(DroneCommand::AddSender(
+                    CLIENT_ID,
+                    packet_recv_tx_client.clone(),
+                ))
+                .expect("Cannot add client to drone neighbors");
+            controller
+                .send(DroneCommand::AddSender(
+                    SERVER_ID,
+                    packet_recv_tx_server.clone(),
+                ))
+                .expect("Cannot add server to drone neighbors");
+        }
+
+        // Create server
+        let mut server = Server::new(
+            SERVER_ID,
+            command_recv_rx_server,
+            packet_recv_rx_server,
+            event_send_tx_server,
+            ServerType::Chat,
+        );
+        let server_handle = thread::spawn(move || {
+            server.run();
+        });
+        command_recv_tx_server
+            .send(ServerCommand::AddDrone(
+                DRONE_1_ID,
+                packet_recv_tx_1.clone(),
+            ))
+            .expect("Cannot add drone 1 to server neighbors");
+        command_recv_tx_server
+            .send(ServerCommand::AddDrone(
+                DRONE_2_ID,
+                packet_recv_tx_2.clone(),
+            ))
+            .expect("Cannot add drone 2 to server neighbors");
+
+        // Send register request, the response carries the long username twice
+        let request = Request::Chat(ChatRequest::Register(username.clone(), password));
+        let _ = message_sender_tx.send(GuiClientMessage::Message {
+            dst: SERVER_ID,
+            data: to_allocvec(&request).expect("Could not convert Request to bytes"),
+        });
+
+        // Wait for the first response fragment and remove the drone it went through
+        let used_drone = loop {
+            match event_send_rx_server.recv_timeout(Duration::from_secs(5)) {
+                Ok(ServerEvent::PacketSent(packet)) => {
+                    if let PacketType::MsgFragment(_) = packet.pack_type {
+                        break packet.routing_header.hops[1];
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => panic!("Server did not start sending the response"),
+            }
+        };
+        let used_drone_controller = if used_drone == DRONE_1_ID {
+            &controller_recv_tx_1
+        } else {
+            &controller_recv_tx_2
+        };
+        used_drone_controller
+            .send(DroneCommand::RemoveSender(SERVER_ID))
+            .expect("Cannot remove server from drone neighbors");
+        command_recv_tx_server
+            .send(ServerCommand::RemoveDrone(used_drone))
+            .expect("Cannot remove drone from server neighbors");
+
+        // Receive client list over the remaining drone
+        let data = message_receiver_rx
+            .recv_timeout(Duration::from_secs(10))
+            .expect("Client did not receive a Response");
+
+        let data = match data {
+            ClientGuiMessage::Message { data, .. } => data,
+            _ => panic!("Clientguimessage is not a Message"),
+        };
+
+        match from_bytes::<Response>(&data) {
+            Ok(Response::Chat(ChatResponse::ClientList(name, usernames))) => {
+                assert_eq!(name, username);
+                assert_eq!(usernames, vec![username.clone()]);
+            }
+            _ => panic!("Response is not a ChatResponse of ClientList"),
+        }
+
+        // Crash nodes
+        thread::sleep(Duration::from_millis(100));
+        let _ = command_recv_tx_client.send(ClientCommand::Crash);
+        let _ = controller_recv_tx_1.send(DroneCommand::Crash);
+        let _ = controller_recv_tx_2.send(DroneCommand::Crash);
+        let _ = command_recv_tx_server.send(ServerCommand::Crash);
+
+        assert!(client_handle.join().is_ok());
+        for handle in drone_handles {
+            assert!(handle.join().is_ok());
+        }
+        assert!(server_handle.join().is_ok());
+    }
 }
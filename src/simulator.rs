@@ -0,0 +1,686 @@
+//! Deterministic in-process network simulator used to test the `Server`.
+//!
+//! The simulator replaces real drones and clients with simulated nodes that follow the
+//! protocol of the network, and drives the server one `step` per tick of a virtual clock.
+//! Every link delivers packets after a fixed number of ticks and every drone drops fragments
+//! with its own rate, drawn from a seeded generator, so a run only depends on its seed.
+//!
+//! The server reads the time from the same virtual clock, which moves forward by `TICK` at
+//! every tick, so its timeouts do not depend on how fast the simulation runs either.
+
+use crate::assembler::AssemblerStatus;
+use crate::assemblers_manager::AssemblersManager;
+use crate::clock::ManualClock;
+use crate::fragmenter::Fragmenter;
+use crate::server::Server;
+use crate::specialized_behavior::{AssembledResponse, RequestKind};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use postcard::{from_bytes, to_allocvec};
+use rust_roveri_api::{Request, Response, ServerCommand, ServerEvent, ServerType, SessionId};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use wg_2024::{
+    network::{NodeId, SourceRoutingHeader},
+    packet::{Ack, FloodRequest, FloodResponse, Nack, NackType, NodeType, Packet, PacketType},
+};
+
+/// Virtual time elapsed at every tick.
+pub const TICK: Duration = Duration::from_millis(10);
+
+/// SplitMix64 generator, so that runs are reproducible without external dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a number uniformly distributed in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+struct SimDrone {
+    pdr: f64,
+    seen_floods: HashSet<(u64, NodeId)>,
+}
+
+struct SimClient {
+    fragmenter: Fragmenter,
+    /// Fragments sent so far, resent when a Nack refers to them.
+    sent: HashMap<(SessionId, u64), Packet>,
+    assemblers_manager: AssemblersManager,
+    responses: Vec<(Response, Vec<NodeId>)>,
+}
+
+struct InTransit {
+    due: u64,
+    from: NodeId,
+    to: NodeId,
+    packet: Packet,
+    /// Delivered through the controller, regardless of the links.
+    shortcut: bool,
+}
+
+/// An event scheduled at a given tick.
+pub enum ScriptedEvent {
+    Crash(NodeId),
+}
+
+/// Counters of what happened in the simulated network.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SimStats {
+    /// Fragments dropped by drones.
+    pub dropped: usize,
+    /// Packets lost because their recipient crashed or the link disappeared while in transit.
+    pub lost: usize,
+    /// Ack, Nack and FloodResponse packets delivered through the controller shortcut.
+    pub shortcuts: usize,
+}
+
+/// A simulated network made of one `Server`, simulated drones and simulated clients.
+pub struct Simulation {
+    tick: u64,
+    clock: ManualClock,
+    /// Number of ticks a packet takes to cross a link.
+    latency: u64,
+    rng: Rng,
+    server_id: NodeId,
+    server: Server,
+    server_commands: Sender<ServerCommand>,
+    server_packets: Sender<Packet>,
+    server_events: Receiver<ServerEvent>,
+    server_links: BTreeMap<NodeId, Receiver<Packet>>,
+    links: BTreeMap<NodeId, BTreeSet<NodeId>>,
+    drones: BTreeMap<NodeId, SimDrone>,
+    clients: BTreeMap<NodeId, SimClient>,
+    in_transit: VecDeque<InTransit>,
+    scheduled: BTreeMap<u64, Vec<ScriptedEvent>>,
+    events: Vec<ServerEvent>,
+    stats: SimStats,
+}
+
+impl Simulation {
+    /// Creates a network containing only the server.
+    pub fn new(server_id: NodeId, server_type: ServerType, seed: u64) -> Self {
+        let (server_commands, command_recv) = unbounded();
+        let (server_packets, packet_recv) = unbounded();
+        let (controller_send, server_events) = unbounded();
+        let clock = ManualClock::new();
+        let mut server = Server::new(
+            server_id,
+            command_recv,
+            packet_recv,
+            controller_send,
+            server_type,
+        );
+        server.set_clock(Arc::new(clock.clone()));
+
+        Self {
+            tick: 0,
+            clock,
+            latency: 1,
+            rng: Rng(seed),
+            server_id,
+            server,
+            server_commands,
+            server_packets,
+            server_events,
+            server_links: BTreeMap::new(),
+            links: BTreeMap::new(),
+            drones: BTreeMap::new(),
+            clients: BTreeMap::new(),
+            in_transit: VecDeque::new(),
+            scheduled: BTreeMap::new(),
+            events: Vec::new(),
+            stats: SimStats::default(),
+        }
+    }
+
+    pub fn add_drone(&mut self, id: NodeId, pdr: f64) {
+        self.drones.insert(
+            id,
+            SimDrone {
+                pdr,
+                seen_floods: HashSet::new(),
+            },
+        );
+    }
+
+    pub fn add_client(&mut self, id: NodeId) {
+        self.clients.insert(
+            id,
+            SimClient {
+                fragmenter: Fragmenter::new(),
+                sent: HashMap::new(),
+                assemblers_manager: AssemblersManager::new(),
+                responses: Vec::new(),
+            },
+        );
+    }
+
+    /// Connects two nodes. Links with the server are announced to it with `AddDrone`.
+    pub fn connect(&mut self, node1: NodeId, node2: NodeId) {
+        self.links.entry(node1).or_default().insert(node2);
+        self.links.entry(node2).or_default().insert(node1);
+
+        for (server, neighbor) in [(node1, node2), (node2, node1)] {
+            if server == self.server_id {
+                let (sender, receiver) = unbounded();
+                self.server_links.insert(neighbor, receiver);
+                let _ = self
+                    .server_commands
+                    .send(ServerCommand::AddDrone(neighbor, sender));
+            }
+        }
+    }
+
+    /// Schedules an event at the given tick.
+    pub fn schedule(&mut self, tick: u64, event: ScriptedEvent) {
+        self.scheduled.entry(tick).or_default().push(event);
+    }
+
+    /// Crashes a drone: it is removed from the network and the server is told with `RemoveDrone`
+    /// if it was one of its neighbors.
+    pub fn crash(&mut self, id: NodeId) {
+        self.drones.remove(&id);
+
+        if let Some(neighbors) = self.links.remove(&id) {
+            for neighbor in neighbors {
+                if let Some(links) = self.links.get_mut(&neighbor) {
+                    links.remove(&id);
+                }
+                if neighbor == self.server_id {
+                    self.server_links.remove(&id);
+                    let _ = self.server_commands.send(ServerCommand::RemoveDrone(id));
+                }
+            }
+        }
+    }
+
+    /// Fragments a request and sends it from a client along the given route.
+    pub fn send_request(&mut self, client_id: NodeId, request: &Request, hops: Vec<NodeId>) {
+        let data = to_allocvec(request).expect("Could not serialize the request");
+        let dest = *hops.last().expect("Hops should not be empty");
+        let client = self.clients.get_mut(&client_id).expect("Unknown client");
+
+        let fragments = client.fragmenter.to_fragment_vec(AssembledResponse {
+            data,
+            dest,
+            kind: RequestKind::from(request),
+        });
+
+        let mut packets = Vec::with_capacity(fragments.len());
+        for to_be_sent in fragments {
+            let fragment_id = (to_be_sent.session_id, to_be_sent.fragment.fragment_index);
+            let packet = Packet {
+                routing_header: SourceRoutingHeader {
+                    hop_index: 1,
+                    hops: hops.clone(),
+                },
+                session_id: to_be_sent.session_id,
+                pack_type: PacketType::MsgFragment(to_be_sent.fragment),
+            };
+            client.sent.insert(fragment_id, packet.clone());
+            packets.push(packet);
+        }
+
+        for packet in packets {
+            self.transmit(client_id, hops[1], packet);
+        }
+    }
+
+    /// Advances the virtual clock by one tick.
+    ///
+    /// Scripted events of the tick are applied first, then the server takes one step, then every
+    /// packet due at this tick is delivered. Finally the clock of the server moves forward by
+    /// `TICK`.
+    pub fn step(&mut self) {
+        if let Some(events) = self.scheduled.remove(&self.tick) {
+            for event in events {
+                match event {
+                    ScriptedEvent::Crash(id) => self.crash(id),
+                }
+            }
+        }
+
+        self.server.step();
+        self.collect_server_output();
+
+        while self
+            .in_transit
+            .front()
+            .is_some_and(|in_transit| in_transit.due <= self.tick)
+        {
+            if let Some(in_transit) = self.in_transit.pop_front() {
+                self.deliver(in_transit);
+            }
+        }
+
+        self.tick += 1;
+        self.clock.advance(TICK);
+    }
+
+    /// Steps until the condition holds, returning `false` if it does not within `max_ticks`.
+    pub fn run_until(&mut self, max_ticks: u64, condition: impl Fn(&Self) -> bool) -> bool {
+        for _ in 0..max_ticks {
+            if condition(self) {
+                return true;
+            }
+            self.step();
+        }
+        condition(self)
+    }
+
+    /// Steps until no packet is in transit and the server has nothing left to send.
+    pub fn run_until_idle(&mut self, max_ticks: u64) -> bool {
+        // Give the server a few steps to process its queues once the network is quiet
+        let mut quiet_ticks = 0;
+        for _ in 0..max_ticks {
+            self.step();
+            if self.in_transit.is_empty() && self.server.is_idle() {
+                quiet_ticks += 1;
+                if quiet_ticks > 2 {
+                    return true;
+                }
+            } else {
+                quiet_ticks = 0;
+            }
+        }
+        false
+    }
+
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn stats(&self) -> &SimStats {
+        &self.stats
+    }
+
+    /// Returns the events the server sent to the controller so far.
+    pub fn server_events(&self) -> &[ServerEvent] {
+        &self.events
+    }
+
+    /// Returns the responses a client reassembled, with the route of their last fragment.
+    pub fn responses(&self, client_id: NodeId) -> &[(Response, Vec<NodeId>)] {
+        self.clients
+            .get(&client_id)
+            .map(|client| client.responses.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Moves what the server sent on its links into the network.
+    fn collect_server_output(&mut self) {
+        while let Ok(event) = self.server_events.try_recv() {
+            self.events.push(event);
+        }
+
+        let mut outgoing = Vec::new();
+        for (neighbor, receiver) in self.server_links.iter() {
+            while let Ok(packet) = receiver.try_recv() {
+                outgoing.push((*neighbor, packet));
+            }
+        }
+        for (neighbor, packet) in outgoing {
+            self.transmit(self.server_id, neighbor, packet);
+        }
+    }
+
+    /// Puts a packet on the link between two nodes, losing it if they are not connected.
+    fn transmit(&mut self, from: NodeId, to: NodeId, packet: Packet) {
+        if self.is_linked(from, to) {
+            self.in_transit.push_back(InTransit {
+                due: self.tick + self.latency,
+                from,
+                to,
+                packet,
+                shortcut: false,
+            });
+        } else {
+            self.stats.lost += 1;
+        }
+    }
+
+    fn is_linked(&self, node1: NodeId, node2: NodeId) -> bool {
+        self.links
+            .get(&node1)
+            .is_some_and(|neighbors| neighbors.contains(&node2))
+    }
+
+    fn deliver(&mut self, in_transit: InTransit) {
+        let InTransit {
+            from,
+            to,
+            packet,
+            shortcut,
+            ..
+        } = in_transit;
+
+        if !shortcut && !self.is_linked(from, to) {
+            self.stats.lost += 1;
+        } else if to == self.server_id {
+            let _ = self.server_packets.send(packet);
+        } else if self.drones.contains_key(&to) {
+            self.drone_receive(to, packet);
+        } else if self.clients.contains_key(&to) {
+            self.client_receive(to, packet);
+        } else {
+            self.stats.lost += 1;
+        }
+    }
+
+    /// Forwards a packet as a drone would.
+    fn drone_receive(&mut self, id: NodeId, mut packet: Packet) {
+        if let PacketType::FloodRequest(mut flood_req) = packet.pack_type {
+            let sender = flood_req.path_trace.last().map(|(node, _)| *node);
+            flood_req.path_trace.push((id, NodeType::Drone));
+
+            let drone = self.drones.get_mut(&id).expect("Unknown drone");
+            let first_time = drone
+                .seen_floods
+                .insert((flood_req.flood_id, flood_req.initiator_id));
+            let next_hops: Vec<NodeId> = self.links[&id]
+                .iter()
+                .copied()
+                .filter(|neighbor| Some(*neighbor) != sender)
+                .collect();
+
+            if !first_time || next_hops.is_empty() {
+                self.flood_respond(id, flood_req, packet.session_id);
+            } else {
+                for next_hop in next_hops {
+                    let packet = Packet {
+                        routing_header: packet.routing_header.clone(),
+                        session_id: packet.session_id,
+                        pack_type: PacketType::FloodRequest(flood_req.clone()),
+                    };
+                    self.transmit(id, next_hop, packet);
+                }
+            }
+            return;
+        }
+
+        let hop_index = packet.routing_header.hop_index;
+        if packet.routing_header.hops.get(hop_index) != Some(&id) {
+            self.reject(id, packet, NackType::UnexpectedRecipient(id));
+            return;
+        }
+
+        if matches!(packet.pack_type, PacketType::MsgFragment(_)) {
+            let pdr = self.drones[&id].pdr;
+            if self.rng.next_f64() < pdr {
+                self.stats.dropped += 1;
+                self.reject(id, packet, NackType::Dropped);
+                return;
+            }
+        }
+
+        match packet.routing_header.hops.get(hop_index + 1).copied() {
+            None => self.reject(id, packet, NackType::DestinationIsDrone),
+            Some(next_hop) if !self.is_linked(id, next_hop) => {
+                self.reject(id, packet, NackType::ErrorInRouting(next_hop))
+            }
+            Some(next_hop) => {
+                packet.routing_header.hop_index += 1;
+                self.transmit(id, next_hop, packet);
+            }
+        }
+    }
+
+    /// Handles a packet a drone can not forward.
+    ///
+    /// Fragments are answered with a Nack, while Acks, Nacks and FloodResponses are delivered
+    /// to their destination through the controller shortcut.
+    fn reject(&mut self, id: NodeId, packet: Packet, nack_type: NackType) {
+        let fragment_index = match &packet.pack_type {
+            PacketType::MsgFragment(fragment) => fragment.fragment_index,
+            PacketType::FloodRequest(_) => return,
+            _ => {
+                if let Some(dest) = packet.routing_header.hops.last().copied() {
+                    self.stats.shortcuts += 1;
+                    self.in_transit.push_back(InTransit {
+                        due: self.tick + self.latency,
+                        from: id,
+                        to: dest,
+                        packet,
+                        shortcut: true,
+                    });
+                }
+                return;
+            }
+        };
+
+        let hop_index = packet.routing_header.hop_index;
+        let mut hops = vec![id];
+        hops.extend(packet.routing_header.hops[..hop_index].iter().rev());
+
+        let nack = Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: hops.clone(),
+            },
+            session_id: packet.session_id,
+            pack_type: PacketType::Nack(Nack {
+                fragment_index,
+                nack_type,
+            }),
+        };
+        if let Some(next_hop) = hops.get(1) {
+            self.transmit(id, *next_hop, nack);
+        }
+    }
+
+    /// Sends back a FloodResponse for a flood request whose path trace ends with `id`.
+    fn flood_respond(&mut self, id: NodeId, flood_req: FloodRequest, session_id: SessionId) {
+        let mut hops: Vec<NodeId> = flood_req
+            .path_trace
+            .iter()
+            .map(|(node, _)| *node)
+            .rev()
+            .collect();
+        if hops.last() != Some(&flood_req.initiator_id) {
+            hops.push(flood_req.initiator_id);
+        }
+
+        let packet = Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: hops.clone(),
+            },
+            session_id,
+            pack_type: PacketType::FloodResponse(FloodResponse {
+                flood_id: flood_req.flood_id,
+                path_trace: flood_req.path_trace,
+            }),
+        };
+        if let Some(next_hop) = hops.get(1) {
+            self.transmit(id, *next_hop, packet);
+        }
+    }
+
+    /// Handles a packet as a client would: fragments are acknowledged and reassembled, and
+    /// flood requests are answered.
+    fn client_receive(&mut self, id: NodeId, packet: Packet) {
+        let client = self.clients.get_mut(&id).expect("Unknown client");
+
+        match packet.pack_type {
+            PacketType::FloodRequest(mut flood_req) => {
+                flood_req.path_trace.push((id, NodeType::Client));
+                self.flood_respond(id, flood_req, packet.session_id);
+            }
+            PacketType::MsgFragment(fragment) => {
+                let fragment_index = fragment.fragment_index;
+                let hop_index = packet.routing_header.hop_index;
+                let route = packet.routing_header.hops.clone();
+
+                if let Ok(AssemblerStatus::Complete) = client
+                    .assemblers_manager
+                    .insert_fragment(fragment, packet.session_id)
+                {
+                    if let Ok(data) = client
+                        .assemblers_manager
+                        .retrieve_assembled(packet.session_id)
+                    {
+                        if let Ok(response) = from_bytes::<Response>(&data) {
+                            client.responses.push((response, route.clone()));
+                        }
+                    }
+                }
+
+                let hops: Vec<NodeId> = route[..=hop_index].iter().rev().copied().collect();
+                let ack = Packet {
+                    routing_header: SourceRoutingHeader {
+                        hop_index: 1,
+                        hops: hops.clone(),
+                    },
+                    session_id: packet.session_id,
+                    pack_type: PacketType::Ack(Ack { fragment_index }),
+                };
+                if let Some(next_hop) = hops.get(1) {
+                    self.transmit(id, *next_hop, ack);
+                }
+            }
+            PacketType::Nack(nack) => {
+                let fragment_id = (packet.session_id, nack.fragment_index);
+                if let Some(resent) = client.sent.get(&fragment_id).cloned() {
+                    let next_hop = resent.routing_header.hops[1];
+                    self.transmit(id, next_hop, resent);
+                }
+            }
+            PacketType::Ack(_) | PacketType::FloodResponse(_) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_roveri_api::{ChatRequest, ChatResponse};
+
+    const CLIENT_ID: NodeId = 1;
+    const DRONE_1_ID: NodeId = 11;
+    const DRONE_2_ID: NodeId = 12;
+    const SERVER_ID: NodeId = 21;
+
+    fn register(username: &str) -> Request {
+        Request::Chat(ChatRequest::Register(
+            username.to_string(),
+            "password".to_string(),
+        ))
+    }
+
+    /// A long request, so that the response listing every client spans several fragments.
+    fn register_many(simulation: &mut Simulation, hops: Vec<NodeId>) {
+        for i in 0..20 {
+            let username = format!("user_with_a_rather_long_name_{:02}", i);
+            simulation.send_request(CLIENT_ID, &register(&username), hops.clone());
+        }
+    }
+
+    #[test]
+    fn test_line_topology() {
+        let mut simulation = Simulation::new(SERVER_ID, ServerType::Chat, 0);
+        simulation.add_client(CLIENT_ID);
+        simulation.add_drone(DRONE_1_ID, 0.0);
+        simulation.add_drone(DRONE_2_ID, 0.0);
+        simulation.connect(CLIENT_ID, DRONE_1_ID);
+        simulation.connect(DRONE_1_ID, DRONE_2_ID);
+        simulation.connect(DRONE_2_ID, SERVER_ID);
+
+        simulation.send_request(
+            CLIENT_ID,
+            &register("ciao"),
+            vec![CLIENT_ID, DRONE_1_ID, DRONE_2_ID, SERVER_ID],
+        );
+        assert!(simulation.run_until_idle(100));
+
+        let responses = simulation.responses(CLIENT_ID);
+        assert_eq!(responses.len(), 1);
+        let (response, route) = &responses[0];
+        assert_eq!(
+            route,
+            &vec![SERVER_ID, DRONE_2_ID, DRONE_1_ID, CLIENT_ID],
+            "The response should follow the reverse of the request route"
+        );
+        match response {
+            Response::Chat(ChatResponse::ClientList(username, _)) => assert_eq!(username, "ciao"),
+            other => panic!("Expected a ClientList, got {:?}", other),
+        }
+        assert_eq!(simulation.stats(), &SimStats::default());
+
+        // The response fits in a single fragment
+        let sent = simulation
+            .server_events()
+            .iter()
+            .filter(|event| matches!(event, ServerEvent::PacketSent(_)))
+            .count();
+        assert_eq!(sent, 1);
+    }
+
+    /// Runs the same lossy scenario and returns what the client saw.
+    fn lossy_run(seed: u64) -> (usize, SimStats, u64) {
+        let mut simulation = Simulation::new(SERVER_ID, ServerType::Chat, seed);
+        simulation.add_client(CLIENT_ID);
+        simulation.add_drone(DRONE_1_ID, 0.3);
+        simulation.connect(CLIENT_ID, DRONE_1_ID);
+        simulation.connect(DRONE_1_ID, SERVER_ID);
+
+        register_many(&mut simulation, vec![CLIENT_ID, DRONE_1_ID, SERVER_ID]);
+        assert!(simulation.run_until_idle(10_000));
+
+        (
+            simulation.responses(CLIENT_ID).len(),
+            simulation.stats().clone(),
+            simulation.tick(),
+        )
+    }
+
+    #[test]
+    fn test_retransmission_is_deterministic() {
+        let (responses, stats, ticks) = lossy_run(42);
+
+        // Every request reached the server and every response reached the client, even if
+        // the drone dropped some fragments in both directions
+        assert_eq!(responses, 20);
+        assert!(stats.dropped > 0);
+
+        assert_eq!(lossy_run(42), (responses, stats, ticks));
+    }
+
+    #[test]
+    fn test_scripted_crash() {
+        let mut simulation = Simulation::new(SERVER_ID, ServerType::Chat, 0);
+        simulation.add_client(CLIENT_ID);
+        simulation.add_drone(DRONE_1_ID, 0.0);
+        simulation.add_drone(DRONE_2_ID, 0.0);
+        simulation.connect(CLIENT_ID, DRONE_1_ID);
+        simulation.connect(CLIENT_ID, DRONE_2_ID);
+        simulation.connect(DRONE_1_ID, SERVER_ID);
+        simulation.connect(DRONE_2_ID, SERVER_ID);
+
+        // The server reads the request at tick 3, so the response is on its way to the first
+        // drone when it crashes
+        simulation.send_request(
+            CLIENT_ID,
+            &register("ciao"),
+            vec![CLIENT_ID, DRONE_1_ID, SERVER_ID],
+        );
+        simulation.schedule(4, ScriptedEvent::Crash(DRONE_1_ID));
+
+        assert!(simulation.run_until(1_000, |simulation| {
+            !simulation.responses(CLIENT_ID).is_empty()
+        }));
+
+        let (_, route) = &simulation.responses(CLIENT_ID)[0];
+        assert_eq!(route, &vec![SERVER_ID, DRONE_2_ID, CLIENT_ID]);
+        assert!(simulation.stats().lost > 0);
+    }
+}
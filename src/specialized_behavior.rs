@@ -1,67 +1,301 @@
 //! Defines traits and structures for processing and handling specialized server behaviors.
 
+use crate::clock::Clock;
+use crate::metrics::{self, MetricKey, Metrics};
+use crate::token::{Presented, SessionToken};
 use log::error;
 use postcard::{self, from_bytes, to_allocvec};
-use rust_roveri_api::{ContentResponse, Request, Response};
+use rust_roveri_api::{ChatRequest, ContentRequest, ContentResponse, Request, Response};
+use std::fmt;
 use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 use wg_2024::network::NodeId;
 
-#[derive(Debug)]
+/// Serialized data ready to be fragmented with `Fragmenter::to_fragment_vec` and sent to
+/// `dest`.
+///
+/// Despite the name, it also carries requests, e.g. when a client uses the `Fragmenter`.
+#[derive(Debug, Clone)]
 pub struct AssembledResponse {
     pub data: Vec<u8>,
     pub dest: NodeId,
+    pub kind: RequestKind,
+}
+
+/// The type of the request an `AssembledResponse` answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    ContentList,
+    Content,
+    ClientList,
+    Message,
+    Register,
+    Login,
+    Logout,
+    /// An account operation, see the `account` module.
+    Account,
+    /// A history request, see the `history` module.
+    History,
+    /// The request could not be deserialized.
+    Invalid,
+}
+
+impl RequestKind {
+    /// Returns the name of the kind, used as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RequestKind::ContentList => "content_list",
+            RequestKind::Content => "content",
+            RequestKind::ClientList => "client_list",
+            RequestKind::Message => "message",
+            RequestKind::Register => "register",
+            RequestKind::Login => "login",
+            RequestKind::Logout => "logout",
+            RequestKind::Account => "account",
+            RequestKind::History => "history",
+            RequestKind::Invalid => "invalid",
+        }
+    }
+}
+
+impl From<&Request> for RequestKind {
+    fn from(request: &Request) -> Self {
+        match request {
+            Request::Content(ContentRequest::List) => RequestKind::ContentList,
+            Request::Content(ContentRequest::Content(_)) => RequestKind::Content,
+            Request::Chat(ChatRequest::ClientList(_)) => RequestKind::ClientList,
+            Request::Chat(ChatRequest::Message(..)) => RequestKind::Message,
+            Request::Chat(ChatRequest::Register(..)) => RequestKind::Register,
+            Request::Chat(ChatRequest::Login(..)) => RequestKind::Login,
+            Request::Chat(ChatRequest::Logout(_)) => RequestKind::Logout,
+        }
+    }
 }
 
+/// Errors that can occur while setting the content path or the storage directory of a
+/// behavior.
+#[derive(Debug)]
 pub enum SetPathError {
     WrongServerType,
     FileSystem(io::Error),
 }
 
+impl fmt::Display for SetPathError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SetPathError::WrongServerType => write!(f, "not supported by this server type"),
+            SetPathError::FileSystem(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for SetPathError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SetPathError::WrongServerType => None,
+            SetPathError::FileSystem(err) => Some(err),
+        }
+    }
+}
+
+/// Errors that can occur while processing a request.
+#[derive(Debug)]
 pub enum ProcessError {
     UnexpectedRequest,
+    /// The server is stopping gracefully and refuses new requests.
+    Stopping,
+    /// The initiator exceeded its rate limits.
+    Throttled,
+    /// The request is not in a session token envelope, and the server requires one.
+    TokenRequired,
     Deserialize(postcard::Error),
     Serialize(postcard::Error),
     FileSystem(io::Error),
+    /// A session token could not be generated.
+    TokenGeneration(io::Error),
+    /// A password could not be hashed.
+    PasswordHashing(io::Error),
+}
+
+impl ProcessError {
+    /// Returns the name of the error, used as a metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProcessError::UnexpectedRequest => "unexpected_request",
+            ProcessError::Stopping => "stopping",
+            ProcessError::Throttled => "throttled",
+            ProcessError::TokenRequired => "token_required",
+            ProcessError::Deserialize(_) => "deserialize",
+            ProcessError::Serialize(_) => "serialize",
+            ProcessError::FileSystem(_) => "file_system",
+            ProcessError::TokenGeneration(_) => "token_generation",
+            ProcessError::PasswordHashing(_) => "password_hashing",
+        }
+    }
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessError::UnexpectedRequest => write!(f, "unexpected request"),
+            ProcessError::Stopping => write!(f, "the server is stopping"),
+            ProcessError::Throttled => write!(f, "too many requests"),
+            ProcessError::TokenRequired => write!(f, "session token envelope required"),
+            ProcessError::Deserialize(err) => write!(f, "cannot deserialize: {}", err),
+            ProcessError::Serialize(err) => write!(f, "cannot serialize: {}", err),
+            ProcessError::FileSystem(err) => write!(f, "{}", err),
+            ProcessError::TokenGeneration(err) => write!(f, "cannot generate a token: {}", err),
+            ProcessError::PasswordHashing(err) => write!(f, "cannot hash a password: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProcessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProcessError::UnexpectedRequest
+            | ProcessError::Stopping
+            | ProcessError::Throttled
+            | ProcessError::TokenRequired => None,
+            ProcessError::Deserialize(err) | ProcessError::Serialize(err) => Some(err),
+            ProcessError::FileSystem(err)
+            | ProcessError::TokenGeneration(err)
+            | ProcessError::PasswordHashing(err) => Some(err),
+        }
+    }
 }
 
+/// The request handling logic that makes a server a chat or a content server.
 pub trait SpecializedBehavior: Send {
     fn set_path(&mut self, _: PathBuf) -> Result<(), SetPathError> {
         Err(SetPathError::WrongServerType)
     }
 
+    /// Sets the directory where the behavior persists its state, loading any state saved there.
+    ///
+    /// Behaviors without persistent state keep the default implementation, which rejects it.
+    fn set_storage_dir(&mut self, _: PathBuf) -> Result<(), SetPathError> {
+        Err(SetPathError::WrongServerType)
+    }
+
+    /// Sets the clock the behavior measures its timeouts on, see the `clock` module.
+    ///
+    /// Behaviors without timeouts keep the default implementation, which ignores it.
+    fn set_clock(&mut self, _: Arc<dyn Clock>) {}
+
+    /// Sets whether requests sent without a session token envelope are rejected, see the
+    /// `token` module.
+    ///
+    /// Behaviors without sessions keep the default implementation, which ignores it.
+    fn set_tokens_required(&mut self, _: bool) {}
+
+    /// Writes any state the behavior keeps in memory to persistent storage.
+    ///
+    /// Called once when the server stops gracefully. Behaviors without persistent state keep
+    /// the default implementation, which does nothing.
+    fn flush(&mut self) -> Result<(), ProcessError> {
+        Ok(())
+    }
+
+    /// Returns the messages the behavior wants to send on its own, e.g. the presence
+    /// notifications caused by the last request, and forgets them.
+    ///
+    /// Behaviors that only answer requests keep the default implementation, which returns none.
+    fn take_notifications(&mut self) -> Vec<AssembledResponse> {
+        Vec::new()
+    }
+
     /// Handles incoming assembled data and use `process_assembled` to process requests.
     ///
     /// # Arguments
     ///
     /// * `assembled` - The assembled message (request).
     /// * `initiator_id` - The id of the message sender.
+    /// * `metrics` - The registry where handled requests and errors are counted.
     ///
     /// # Returns
     ///
     /// - `AssembledResponse` the assembled message (response).
-    fn handle_assembled(&mut self, assembled: Vec<u8>, initiator_id: NodeId) -> AssembledResponse {
+    fn handle_assembled(
+        &mut self,
+        assembled: Vec<u8>,
+        initiator_id: NodeId,
+        metrics: &mut Metrics,
+    ) -> AssembledResponse {
         let request = match from_bytes::<Request>(&assembled).map_err(ProcessError::Deserialize) {
             Ok(request) => request,
-            Err(err) => return self.handle_error(err, initiator_id),
+            Err(err) => {
+                metrics.increment(MetricKey::labeled(
+                    metrics::REQUEST_ERRORS,
+                    "error",
+                    err.as_str(),
+                ));
+                return self.handle_error(err, initiator_id);
+            }
         };
+        let kind = RequestKind::from(&request);
+        metrics.increment(MetricKey::labeled(metrics::REQUESTS, "kind", kind.as_str()));
 
         let (response, dest) = match self.process_assembled(request, initiator_id) {
             Ok((response, dest)) => (response, dest),
-            Err(err) => return self.handle_error(err, initiator_id),
+            Err(err) => {
+                metrics.increment(MetricKey::labeled(
+                    metrics::REQUEST_ERRORS,
+                    "error",
+                    err.as_str(),
+                ));
+                return AssembledResponse {
+                    kind,
+                    ..self.handle_error(err, initiator_id)
+                };
+            }
         };
 
         let assembled_response = match to_allocvec(&response).map_err(ProcessError::Serialize) {
             Ok(assembled_response) => assembled_response,
-            Err(err) => return self.handle_error(err, initiator_id),
+            Err(err) => {
+                metrics.increment(MetricKey::labeled(
+                    metrics::REQUEST_ERRORS,
+                    "error",
+                    err.as_str(),
+                ));
+                return AssembledResponse {
+                    kind,
+                    ..self.handle_error(err, initiator_id)
+                };
+            }
         };
 
         AssembledResponse {
             data: assembled_response,
             dest,
+            kind,
         }
     }
 
+    /// Handles incoming assembled data like `handle_assembled`, knowing how the initiator
+    /// presented its session token, see the `token` module.
+    ///
+    /// # Returns
+    ///
+    /// - `AssembledResponse` the assembled message (response).
+    /// - `Option<SessionToken>` the token issued to the initiator by the request, if any.
+    ///
+    /// Behaviors without sessions keep the default implementation, which ignores the token.
+    fn handle_authenticated(
+        &mut self,
+        assembled: Vec<u8>,
+        initiator_id: NodeId,
+        _presented: Presented,
+        metrics: &mut Metrics,
+    ) -> (AssembledResponse, Option<SessionToken>) {
+        (
+            self.handle_assembled(assembled, initiator_id, metrics),
+            None,
+        )
+    }
+
     /// Processes requests based on the behavior and generates appropriate responses.
     /// Errors are propagated to the `handle_assembled` method.
     ///
@@ -83,9 +317,15 @@ pub trait SpecializedBehavior: Send {
     fn handle_error(&self, err: ProcessError, dest_id: NodeId) -> AssembledResponse {
         let error_message = match err {
             ProcessError::UnexpectedRequest => format!("Unexpected request"),
+            ProcessError::Stopping => format!("Server stopping, retry later"),
+            ProcessError::Throttled => format!("Too many requests, retry later"),
+            ProcessError::TokenRequired => format!("Session tokens required"),
             ProcessError::Deserialize(_) => format!("Deserialization error"),
             ProcessError::Serialize(_) => format!("Serialization error"),
             ProcessError::FileSystem(_) => format!("Filesystem error"),
+            ProcessError::TokenGeneration(_) | ProcessError::PasswordHashing(_) => {
+                format!("Internal error")
+            }
         };
         let error_response = Response::Content(ContentResponse::InternalServerError(error_message));
 
@@ -93,12 +333,14 @@ pub trait SpecializedBehavior: Send {
             Ok(bytes) => AssembledResponse {
                 data: bytes,
                 dest: dest_id,
+                kind: RequestKind::Invalid,
             },
             Err(e) => {
                 error!("Failed to serialize error response: {:?}", e);
                 AssembledResponse {
                     data: Vec::new(),
                     dest: dest_id,
+                    kind: RequestKind::Invalid,
                 }
             }
         }
@@ -11,11 +11,13 @@ use wg_2024::network::NodeId;
 ///
 /// The `TextBehavior` struct implements the `SpecializedBehavior` trait to handle
 /// requests for text content stored in a specified directory.
+#[derive(Debug, Default)]
 pub struct TextBehavior {
     path: PathBuf,
 }
 
 impl TextBehavior {
+    /// Creates a behavior with no content directory, set it with `set_path`.
     pub fn new() -> Self {
         Self {
             path: PathBuf::new(),
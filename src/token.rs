@@ -0,0 +1,199 @@
+//! Implements the session tokens of chat clients.
+//!
+//! Requests and responses do not have room for a token, so a client that supports them wraps
+//! its payloads in the token envelope, see the `envelope` module: a flag telling whether a
+//! token follows, the token and the payload.
+//!
+//! A client logs in or registers with `encode(None, &request)`. If the login succeeds, the
+//! response envelope carries the token the client must present with `encode(Some(&token), ..)`
+//! on every following chat request, until it logs out or the token expires.
+//!
+//! By default, requests outside an envelope are still accepted, as a compatibility mode for
+//! clients that do not support tokens: their sessions are only bound to the `NodeId` in the
+//! source routing header, which any node on the path can forge. Servers whose clients all
+//! support tokens should require them with `Server::set_tokens_required`.
+
+use crate::envelope;
+use std::fmt;
+use std::io;
+
+/// First byte of a payload wrapped in an envelope.
+const MARKER: u8 = envelope::TOKEN;
+
+const NO_TOKEN: u8 = 0;
+const WITH_TOKEN: u8 = 1;
+
+/// Length of a token, in bytes.
+pub const TOKEN_LEN: usize = 16;
+
+/// An unguessable token identifying a logged in chat client.
+#[derive(Clone, Copy, Eq)]
+pub struct SessionToken([u8; TOKEN_LEN]);
+
+impl SessionToken {
+    /// Creates a token from its bytes, e.g. the ones received in an envelope.
+    pub fn from_bytes(bytes: [u8; TOKEN_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the bytes of the token.
+    pub fn as_bytes(&self) -> &[u8; TOKEN_LEN] {
+        &self.0
+    }
+
+    /// Generates a new token from the random number generator of the operating system.
+    pub(crate) fn generate() -> io::Result<Self> {
+        let mut bytes = [0; TOKEN_LEN];
+        getrandom::getrandom(&mut bytes)?;
+        Ok(Self(bytes))
+    }
+}
+
+impl PartialEq for SessionToken {
+    /// Compares the tokens in constant time, so that the time taken to reject a token does not
+    /// tell how much of it is right.
+    fn eq(&self, other: &Self) -> bool {
+        self.0
+            .iter()
+            .zip(other.0.iter())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionToken(..)")
+    }
+}
+
+/// How a payload was sent with respect to session tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Presented {
+    /// Not in an envelope: the sender does not support tokens.
+    #[default]
+    Plain,
+    /// In an envelope, with or without a token.
+    Enveloped(Option<SessionToken>),
+}
+
+impl Presented {
+    /// Returns the token, if one was presented.
+    pub fn token(&self) -> Option<SessionToken> {
+        match self {
+            Presented::Plain => None,
+            Presented::Enveloped(token) => *token,
+        }
+    }
+}
+
+/// Errors that can occur while unwrapping a payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /// The envelope ends before its token.
+    Truncated,
+    /// The envelope has an unknown flag.
+    UnknownFlag(u8),
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Truncated => write!(f, "truncated token envelope"),
+            TokenError::UnknownFlag(flag) => write!(f, "unknown token flag {}", flag),
+        }
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+/// Wraps a payload in an envelope, with a token if one is given.
+pub fn encode(token: Option<&SessionToken>, data: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(data.len() + TOKEN_LEN + 2);
+    encoded.push(MARKER);
+    match token {
+        Some(token) => {
+            encoded.push(WITH_TOKEN);
+            encoded.extend_from_slice(token.as_bytes());
+        }
+        None => encoded.push(NO_TOKEN),
+    }
+    encoded.extend_from_slice(data);
+    encoded
+}
+
+/// Unwraps a payload.
+///
+/// # Returns
+///
+/// - `Ok((Vec<u8>, Presented::Enveloped))` with the payload and the token, if any, if it was in
+///   an envelope.
+/// - `Ok((Vec<u8>, Presented::Plain))` with the payload unchanged if it was not.
+/// - `Err(TokenError)` if the envelope is invalid.
+pub fn decode(mut data: Vec<u8>) -> Result<(Vec<u8>, Presented), TokenError> {
+    if data.first() != Some(&MARKER) {
+        return Ok((data, Presented::Plain));
+    }
+
+    match data.get(1).copied() {
+        None => Err(TokenError::Truncated),
+        Some(NO_TOKEN) => {
+            data.drain(..2);
+            Ok((data, Presented::Enveloped(None)))
+        }
+        Some(WITH_TOKEN) => {
+            let bytes: [u8; TOKEN_LEN] = data
+                .get(2..2 + TOKEN_LEN)
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or(TokenError::Truncated)?;
+            data.drain(..2 + TOKEN_LEN);
+            Ok((data, Presented::Enveloped(Some(SessionToken(bytes)))))
+        }
+        Some(flag) => Err(TokenError::UnknownFlag(flag)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use postcard::to_allocvec;
+    use rust_roveri_api::{ChatRequest, Request};
+
+    #[test]
+    fn test_roundtrip() {
+        let request = to_allocvec(&Request::Chat(ChatRequest::Logout("ciao".to_string()))).unwrap();
+        assert_eq!(
+            decode(request.clone()),
+            Ok((request.clone(), Presented::Plain))
+        );
+
+        let encoded = encode(None, &request);
+        assert_eq!(
+            decode(encoded),
+            Ok((request.clone(), Presented::Enveloped(None)))
+        );
+
+        let token = SessionToken::generate().unwrap();
+        let (data, presented) = decode(encode(Some(&token), &request)).unwrap();
+        assert_eq!(data, request);
+        assert_eq!(presented.token(), Some(token));
+    }
+
+    #[test]
+    fn test_tokens_are_unique() {
+        let first = SessionToken::generate().unwrap();
+        let second = SessionToken::generate().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(format!("{:?}", first), "SessionToken(..)");
+    }
+
+    #[test]
+    fn test_invalid_envelopes() {
+        assert_eq!(decode(vec![MARKER]), Err(TokenError::Truncated));
+        assert_eq!(
+            decode(vec![MARKER, WITH_TOKEN, 1, 2, 3]),
+            Err(TokenError::Truncated)
+        );
+        assert_eq!(decode(vec![MARKER, 9]), Err(TokenError::UnknownFlag(9)));
+    }
+}
@@ -1,25 +1,43 @@
 //! Implements the `Topology` struct for managing and analyzing network connectivity.
 
-use bitvec::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::Ordering,
-    collections::{BinaryHeap, VecDeque},
-    fmt::Display,
+    collections::{BTreeSet, BinaryHeap, HashMap, HashSet, VecDeque},
+    fmt::{self, Display, Write},
     time::{Duration, Instant},
-    usize,
 };
 use wg_2024::{network::NodeId, packet::NodeType};
 
-const NETWORK_SIZE: usize = 256;
+#[cfg(any(test, feature = "bench"))]
+pub mod dense;
 
 /// Estimated duration after which the topology is considered fully updated.
 const ESTIMATED_UPDATE_TIME: Duration = Duration::from_secs(2);
 
+#[derive(Debug)]
 struct Rate {
     pub success: f64,
     pub failure: f64,
 }
 
+impl Rate {
+    const fn new() -> Self {
+        Self {
+            success: 1.0,
+            failure: 1.0,
+        }
+    }
+
+    fn pdr(&self) -> f64 {
+        if self.success + self.failure > 0.0 {
+            self.failure / (self.success + self.failure)
+        } else {
+            0.0
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 struct State {
     pdr: f64,
@@ -46,65 +64,179 @@ impl PartialOrd for State {
 /// Represents the network topology as an undirected graph.
 ///
 /// The `Topology` struct tracks connections between nodes and their types.
-/// It uses a bit matrix (`BitArray`) to represent edges between nodes.
+/// Edges are stored as sparse adjacency lists, so memory grows with the number of known
+/// nodes instead of the whole `NodeId` space. Neighbors are kept ordered so that path
+/// selection is deterministic.
+#[derive(Debug)]
 pub struct Topology {
     node_id: NodeId,
-    graph: [BitArray<[u8; 32]>; NETWORK_SIZE],
-    types: [NodeType; NETWORK_SIZE],
-    observed_trend: [Rate; NETWORK_SIZE],
-    last_reset: Instant,
+    own_type: NodeType,
+    graph: HashMap<NodeId, BTreeSet<NodeId>>,
+    types: HashMap<NodeId, NodeType>,
+    observed_trend: HashMap<NodeId, Rate>,
+    last_reset: Option<Instant>,
 }
 
-#[derive(Debug)]
+/// Serializable view of a node known to the topology.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeSnapshot {
+    pub id: NodeId,
+    pub node_type: NodeType,
+    pub observed_drop_rate: f64,
+}
+
+/// Serializable view of the whole topology, as seen by the node that owns it.
+///
+/// Nodes are sorted by id and every undirected edge is listed once, with the lower id first.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopologySnapshot {
+    pub node_id: NodeId,
+    pub nodes: Vec<NodeSnapshot>,
+    pub edges: Vec<(NodeId, NodeId)>,
+}
+
+/// Errors that can occur while looking for a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RoutingError {
     NoPathFound,
     SourceIsDest,
 }
 
+impl Display for RoutingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoutingError::NoPathFound => write!(f, "no path found"),
+            RoutingError::SourceIsDest => write!(f, "the destination is the source"),
+        }
+    }
+}
+
+impl std::error::Error for RoutingError {}
+
+impl TopologySnapshot {
+    /// Exports the snapshot as a Graphviz DOT undirected graph.
+    ///
+    /// Each node is labelled with its id, type and observed drop rate, and the node that owns
+    /// the topology is drawn in bold. Controllers can export the snapshots they receive in
+    /// `ControlEvent::Topology` this way.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::new();
+
+        let _ = writeln!(dot, "graph topology_{} {{", self.node_id);
+
+        for node in &self.nodes {
+            let shape = match node.node_type {
+                NodeType::Client => "ellipse",
+                NodeType::Drone => "circle",
+                NodeType::Server => "box",
+            };
+            let style = if node.id == self.node_id {
+                ", style=bold"
+            } else {
+                ""
+            };
+
+            let _ = writeln!(
+                dot,
+                "    {} [label=\"{} {:?}\\npdr {:.2}\", shape={}{}];",
+                node.id, node.id, node.node_type, node.observed_drop_rate, shape, style
+            );
+        }
+
+        for (node1, node2) in &self.edges {
+            let _ = writeln!(dot, "    {} -- {};", node1, node2);
+        }
+
+        dot.push('}');
+        dot
+    }
+}
+
 impl Topology {
+    /// Creates the topology seen by the server `node_id`, which knows no other node yet.
     pub fn new(node_id: NodeId) -> Self {
+        Self::with_node_type(node_id, NodeType::Server)
+    }
+
+    /// Creates the topology seen by a node of any type, which knows no other node yet.
+    ///
+    /// The owner is always the source of the paths, so its type only shows in snapshots.
+    pub fn with_node_type(node_id: NodeId, node_type: NodeType) -> Self {
         Self {
             node_id,
-            graph: [BitArray::new([0; 32]); NETWORK_SIZE],
-            types: {
-                let mut types = [NodeType::Drone; NETWORK_SIZE];
-                types[node_id as usize] = NodeType::Server;
-                types
-            },
-            observed_trend: [const {
-                Rate {
-                    success: 1.0,
-                    failure: 1.0,
-                }
-            }; NETWORK_SIZE],
-            last_reset: Instant::now() - ESTIMATED_UPDATE_TIME,
+            own_type: node_type,
+            graph: HashMap::new(),
+            types: HashMap::from([(node_id, node_type)]),
+            observed_trend: HashMap::new(),
+            last_reset: None,
         }
     }
 
     /// Inserts an edge between two nodes in the topology.
     pub fn insert_edge(&mut self, node1: (NodeId, NodeType), node2: (NodeId, NodeType)) {
-        let node1_id = node1.0 as usize;
-        let node2_id = node2.0 as usize;
-
-        self.graph[node1_id].set(node2_id, true);
-        self.graph[node2_id].set(node1_id, true);
+        self.graph.entry(node1.0).or_default().insert(node2.0);
+        self.graph.entry(node2.0).or_default().insert(node1.0);
 
         if self.node_id != node1.0 {
-            self.types[node1_id] = node1.1;
+            self.types.insert(node1.0, node1.1);
         }
 
         if self.node_id != node2.0 {
-            self.types[node2_id] = node2.1;
+            self.types.insert(node2.0, node2.1);
         }
     }
 
     /// Removes an edge between two nodes in the topology.
+    ///
+    /// Nodes left without neighbors are dropped from the adjacency lists.
     pub fn remove_edge(&mut self, node1_id: NodeId, node2_id: NodeId) {
-        let n1_id = node1_id as usize;
-        let n2_id = node2_id as usize;
+        self.remove_directed(node1_id, node2_id);
+        self.remove_directed(node2_id, node1_id);
+    }
+
+    /// Removes every edge of a node, and its type, keeping the rest of the topology.
+    pub fn remove_node(&mut self, node_id: NodeId) {
+        if node_id == self.node_id {
+            return;
+        }
+
+        for neighbor in self.graph.remove(&node_id).unwrap_or_default() {
+            self.remove_directed(neighbor, node_id);
+        }
+        self.types.remove(&node_id);
+    }
+
+    fn remove_directed(&mut self, from: NodeId, to: NodeId) {
+        if let Some(neighbors) = self.graph.get_mut(&from) {
+            neighbors.remove(&to);
+            if neighbors.is_empty() {
+                self.graph.remove(&from);
+            }
+        }
+    }
+
+    /// Checks whether an edge between two nodes is known.
+    pub fn has_edge(&self, node1_id: NodeId, node2_id: NodeId) -> bool {
+        self.graph
+            .get(&node1_id)
+            .is_some_and(|neighbors| neighbors.contains(&node2_id))
+    }
+
+    /// Returns the type of a node, if it is known.
+    pub fn known_type(&self, node_id: NodeId) -> Option<NodeType> {
+        self.types.get(&node_id).copied()
+    }
 
-        self.graph[n1_id].set(n2_id, false);
-        self.graph[n2_id].set(n1_id, false);
+    /// Returns the type of a node, defaulting to `NodeType::Drone` for unknown nodes.
+    pub fn node_type(&self, node_id: NodeId) -> NodeType {
+        self.types.get(&node_id).copied().unwrap_or(NodeType::Drone)
+    }
+
+    fn neighbors(&self, node_id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.graph
+            .get(&node_id)
+            .into_iter()
+            .flat_map(|neighbors| neighbors.iter().copied())
     }
 
     /// Finds the shortest path between two nodes using BFS.
@@ -124,40 +256,33 @@ impl Topology {
     ///
     /// Routing through a node of type `NodeType::Drone` is not allowed.
     pub fn bfs(&self, source: NodeId, dest: NodeId) -> Result<Vec<NodeId>, RoutingError> {
-        let source_id = source as usize;
-        let dest_id = dest as usize;
-
         if source == dest {
             return Err(RoutingError::SourceIsDest);
         }
 
-        let mut visited = vec![false; NETWORK_SIZE];
-        let mut parent = vec![None; NETWORK_SIZE];
-        let mut queue = VecDeque::new();
-
-        visited[source_id] = true;
-        queue.push_back(source_id);
+        let mut visited = HashSet::from([source]);
+        let mut parent = HashMap::new();
+        let mut queue = VecDeque::from([source]);
 
         while let Some(current) = queue.pop_front() {
-            for neighbor in self.graph[current].iter_ones() {
-                if !visited[neighbor] {
-                    visited[neighbor] = true;
-                    parent[neighbor] = Some(current);
+            for neighbor in self.neighbors(current) {
+                if visited.insert(neighbor) {
+                    parent.insert(neighbor, current);
 
-                    if neighbor == dest_id {
+                    if neighbor == dest {
                         let mut path = vec![dest];
-                        let mut current_node = dest_id;
+                        let mut current_node = dest;
 
-                        while let Some(p) = parent[current_node] {
-                            path.push(p as NodeId);
-                            current_node = p;
+                        while let Some(p) = parent.get(&current_node) {
+                            path.push(*p);
+                            current_node = *p;
                         }
 
                         path.reverse();
                         return Ok(path);
 
                     //Routing with a node different from drone in the middle is illegal
-                    } else if self.types[neighbor] == NodeType::Drone {
+                    } else if self.node_type(neighbor) == NodeType::Drone {
                         queue.push_back(neighbor);
                     }
                 }
@@ -167,39 +292,42 @@ impl Topology {
         Err(RoutingError::NoPathFound)
     }
 
+    /// Finds the path between two nodes with the lowest probability of being dropped, based
+    /// on the drop rates observed with `observe_success` and `observe_failure`.
+    ///
+    /// Returns the same errors as `bfs` and, like it, never routes through a node that is not a
+    /// drone.
     pub fn dijkstra(&self, source: NodeId, dest: NodeId) -> Result<Vec<NodeId>, RoutingError> {
-        let source_id = source as usize;
-        let dest_id = dest as usize;
-
         if source == dest {
             return Err(RoutingError::SourceIsDest);
         }
 
-        let mut dist: Vec<f64> = (0..NETWORK_SIZE).map(|_| f64::INFINITY).collect();
-        let mut prev: Vec<Option<usize>> = vec![None; NETWORK_SIZE];
+        let mut dist: HashMap<NodeId, f64> = HashMap::from([(source, 0.0)]);
+        let mut prev: HashMap<NodeId, NodeId> = HashMap::new();
         let mut heap = BinaryHeap::new();
 
-        dist[source_id] = 0.0;
         heap.push(State {
             pdr: 0.0,
-            position: source_id,
+            position: source as usize,
         });
 
         while let Some(State { pdr, position }) = heap.pop() {
-            if position == dest_id {
-                let mut path = Vec::new();
-                let mut current = Some(dest_id);
+            let position = position as NodeId;
 
-                while let Some(node) = current {
-                    path.push(node as NodeId);
-                    current = prev[node];
+            if position == dest {
+                let mut path = vec![dest];
+                let mut current = dest;
+
+                while let Some(node) = prev.get(&current) {
+                    path.push(*node);
+                    current = *node;
                 }
 
                 path.reverse();
                 return Ok(path);
             }
 
-            if pdr > dist[position] {
+            if pdr > dist.get(&position).copied().unwrap_or(f64::INFINITY) {
                 continue;
             }
 
@@ -208,19 +336,19 @@ impl Topology {
             // the pdr of the whole path for reaching the current node
             // + the probability that the packet will reach the next drop
             // but it will drop there
-            for neighbor in self.graph[position].iter_ones() {
+            for neighbor in self.neighbors(position) {
                 // a non-drone node cant be used in the middle of the path
-                if neighbor != dest_id && self.types[neighbor] != NodeType::Drone {
+                if neighbor != dest && self.node_type(neighbor) != NodeType::Drone {
                     continue;
                 }
 
                 let next_pdr = pdr + (1.0 - pdr) * self.get_observed_pdr(neighbor);
-                if next_pdr < dist[neighbor] {
-                    dist[neighbor] = next_pdr;
-                    prev[neighbor] = Some(position);
+                if next_pdr < dist.get(&neighbor).copied().unwrap_or(f64::INFINITY) {
+                    dist.insert(neighbor, next_pdr);
+                    prev.insert(neighbor, position);
                     heap.push(State {
                         pdr: next_pdr,
-                        position: neighbor,
+                        position: neighbor as usize,
                     });
                 }
             }
@@ -229,64 +357,136 @@ impl Topology {
         Err(RoutingError::NoPathFound)
     }
 
-    /// Resets the topology by clearing all edges and resetting node types.
-    pub fn reset(&mut self) {
-        self.graph = [BitArray::new([0; 32]); NETWORK_SIZE];
-        self.types = [NodeType::Drone; NETWORK_SIZE];
+    /// Resets the topology by clearing all edges and resetting node types. The topology is
+    /// considered updating for a while after `now`.
+    pub fn reset(&mut self, now: Instant) {
+        self.graph.clear();
+        self.types.clear();
+        self.types.insert(self.node_id, self.own_type);
         //todo!("UPDATE THE TREND?");
 
-        self.last_reset = Instant::now()
+        self.last_reset = Some(now);
     }
 
     /// Checks if the topology is currently updating.
     ///
     /// # Returns
     ///
-    /// `true` if the last reset occurred within the estimated update time; otherwise, `false`.
-    pub fn is_updating(&self) -> bool {
-        self.last_reset.elapsed() < ESTIMATED_UPDATE_TIME
+    /// `true` if the last reset occurred within the estimated update time before `now`;
+    /// otherwise, `false`.
+    pub fn is_updating(&self, now: Instant) -> bool {
+        self.last_reset.is_some_and(|last_reset| {
+            now.saturating_duration_since(last_reset) < ESTIMATED_UPDATE_TIME
+        })
     }
 
+    /// Records that a packet went through `node`, e.g. because it was acknowledged.
     pub fn observe_success(&mut self, node: NodeId) {
-        self.observed_trend[node as usize].success += 1.0;
+        self.observed_trend
+            .entry(node)
+            .or_insert(Rate::new())
+            .success += 1.0;
     }
 
+    /// Records that `node` dropped a packet.
     pub fn observe_failure(&mut self, node: NodeId) {
-        self.observed_trend[node as usize].failure += 1.0;
+        self.observed_trend
+            .entry(node)
+            .or_insert(Rate::new())
+            .failure += 1.0;
     }
 
-    fn get_observed_pdr(&self, node_id: usize) -> f64 {
-        let trend = &self.observed_trend[node_id];
-        if trend.success + trend.failure > 0.0 {
-            trend.failure / (trend.success + trend.failure)
-        } else {
-            0.0
+    /// Estimates the probability that a packet sent along `path` is dropped, based on the drop
+    /// rates observed on its intermediate nodes.
+    ///
+    /// Nodes that were never observed do not count, so a path through unknown drones has a drop
+    /// rate of 0.
+    pub fn path_drop_rate(&self, path: &[NodeId]) -> f64 {
+        let intermediate = path.get(1..path.len().saturating_sub(1)).unwrap_or(&[]);
+
+        let delivered: f64 = intermediate
+            .iter()
+            .filter_map(|node| self.observed_trend.get(node))
+            .map(|trend| 1.0 - trend.pdr())
+            .product();
+        1.0 - delivered
+    }
+
+    /// Returns the ids of every node that appears in the topology, owner included, sorted.
+    fn known_nodes(&self) -> Vec<NodeId> {
+        let mut nodes: Vec<NodeId> = self.graph.keys().copied().collect();
+        if !self.graph.contains_key(&self.node_id) {
+            nodes.push(self.node_id);
+        }
+        nodes.sort();
+        nodes
+    }
+
+    /// Takes a serializable snapshot of the graph, node types and observed drop rates.
+    pub fn snapshot(&self) -> TopologySnapshot {
+        let known_nodes = self.known_nodes();
+
+        let nodes = known_nodes
+            .iter()
+            .map(|id| NodeSnapshot {
+                id: *id,
+                node_type: self.node_type(*id),
+                observed_drop_rate: self.get_observed_pdr(*id),
+            })
+            .collect();
+
+        let edges = known_nodes
+            .iter()
+            .flat_map(|id| {
+                self.neighbors(*id)
+                    .filter(move |neighbor| id <= neighbor)
+                    .map(move |neighbor| (*id, neighbor))
+            })
+            .collect();
+
+        TopologySnapshot {
+            node_id: self.node_id,
+            nodes,
+            edges,
+        }
+    }
+
+    /// Exports the topology as a Graphviz DOT undirected graph, see `TopologySnapshot::to_dot`.
+    pub fn to_dot(&self) -> String {
+        self.snapshot().to_dot()
+    }
+
+    fn get_observed_pdr(&self, node_id: NodeId) -> f64 {
+        match self.observed_trend.get(&node_id) {
+            Some(trend) => trend.pdr(),
+            None => Rate::new().pdr(),
         }
     }
 }
 
 impl Display for Topology {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut res = String::new();
-
-        for (i, node) in self.graph.iter().enumerate() {
-            if node.count_ones() > 0 {
-                let observed_pdr = self.get_observed_pdr(i);
-
-                res.push_str(&format!(
-                    "Node {} [OBSERVED DROP RATE: {}] : ",
-                    i, observed_pdr
-                ));
-                let connections: Vec<usize> = node.iter_ones().collect();
-                res.push_str(&format!("connected to {:?} ", connections));
-            }
+        if self.graph.is_empty() {
+            return write!(f, "No connections in the topology");
         }
 
-        if res.is_empty() {
-            res = "No connections in the topology".to_string();
+        let mut nodes: Vec<&NodeId> = self.graph.keys().collect();
+        nodes.sort();
+
+        for node in nodes {
+            let connections: Vec<NodeId> = self.neighbors(*node).collect();
+
+            writeln!(
+                f,
+                "Node {} ({:?}) [OBSERVED DROP RATE: {:.2}] connected to {:?}",
+                node,
+                self.node_type(*node),
+                self.get_observed_pdr(*node),
+                connections
+            )?;
         }
 
-        write!(f, "{}", res)
+        Ok(())
     }
 }
 
@@ -298,21 +498,38 @@ mod tests {
     #[test]
     fn test_topology_initialization() {
         let topo = Topology::new(1);
-        assert_eq!(topo.graph.len(), NETWORK_SIZE);
-        assert_eq!(topo.types.len(), NETWORK_SIZE);
+        assert!(topo.graph.is_empty());
+        assert_eq!(topo.node_type(1), NodeType::Server);
+        assert_eq!(topo.node_type(2), NodeType::Drone);
     }
 
     #[test]
     fn test_insert_and_remove_edge() {
         let mut topo = Topology::new(2);
         topo.insert_edge((0, NodeType::Drone), (1, NodeType::Client));
-        assert!(topo.graph[0].get(1).unwrap());
-        assert!(topo.graph[1].get(0).unwrap());
-        assert_eq!(topo.types[1], NodeType::Client);
+        assert!(topo.has_edge(0, 1));
+        assert!(topo.has_edge(1, 0));
+        assert_eq!(topo.node_type(1), NodeType::Client);
 
         topo.remove_edge(0, 1);
-        assert!(!topo.graph[0].get(1).unwrap());
-        assert!(!topo.graph[1].get(0).unwrap());
+        assert!(!topo.has_edge(0, 1));
+        assert!(!topo.has_edge(1, 0));
+        assert!(topo.graph.is_empty());
+    }
+
+    #[test]
+    fn test_remove_node() {
+        let mut topo = Topology::new(1);
+        topo.insert_edge((1, NodeType::Server), (2, NodeType::Drone));
+        topo.insert_edge((1, NodeType::Server), (3, NodeType::Drone));
+        topo.insert_edge((2, NodeType::Drone), (4, NodeType::Client));
+        topo.insert_edge((3, NodeType::Drone), (4, NodeType::Client));
+
+        topo.remove_node(2);
+        assert!(!topo.has_edge(1, 2));
+        assert!(!topo.has_edge(4, 2));
+        assert!(!topo.graph.contains_key(&2));
+        assert_eq!(topo.dijkstra(1, 4), Ok(vec![1, 3, 4]));
     }
 
     #[test]
@@ -340,6 +557,24 @@ mod tests {
         assert_eq!(pdr_node_1, 1.0 / 2.0);
     }
 
+    #[test]
+    fn test_path_drop_rate() {
+        let mut topo = Topology::new(4);
+        assert_eq!(topo.path_drop_rate(&[4, 1, 2, 0]), 0.0);
+
+        topo.observe_failure(1);
+        topo.observe_success(1);
+        topo.observe_success(1);
+        topo.observe_failure(2);
+        // Endpoints never drop packets they send or receive
+        topo.observe_failure(0);
+
+        let drop_rate = topo.path_drop_rate(&[4, 1, 2, 0]);
+        assert!((drop_rate - (1.0 - 0.6 * 1.0 / 3.0)).abs() < 1e-9);
+        assert_eq!(topo.path_drop_rate(&[4, 0]), 0.0);
+        assert_eq!(topo.path_drop_rate(&[]), 0.0);
+    }
+
     #[test]
     fn test_valid_path_with_drones() {
         let mut topo = Topology::new(4);
@@ -374,4 +609,177 @@ mod tests {
         let path = topo.bfs(0, 3).expect("Path should exist");
         assert_eq!(path, vec![0, 1, 2, 3]);
     }
+
+    #[test]
+    fn test_reset_keeps_own_type() {
+        let mut topo = Topology::new(3);
+        topo.insert_edge((3, NodeType::Server), (1, NodeType::Drone));
+        let now = Instant::now();
+        assert!(!topo.is_updating(now));
+        topo.reset(now);
+
+        assert!(!topo.has_edge(3, 1));
+        assert_eq!(topo.node_type(3), NodeType::Server);
+        assert!(topo.is_updating(now + ESTIMATED_UPDATE_TIME / 2));
+        assert!(!topo.is_updating(now + ESTIMATED_UPDATE_TIME));
+    }
+
+    #[test]
+    fn test_client_topology() {
+        let mut topo = Topology::with_node_type(5, NodeType::Client);
+        topo.insert_edge((5, NodeType::Client), (1, NodeType::Drone));
+        topo.insert_edge((1, NodeType::Drone), (9, NodeType::Server));
+
+        assert_eq!(topo.bfs(5, 9).unwrap(), vec![5, 1, 9]);
+        assert_eq!(topo.dijkstra(5, 9).unwrap(), vec![5, 1, 9]);
+
+        topo.reset(Instant::now());
+        assert_eq!(topo.node_type(5), NodeType::Client);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut topo = Topology::new(3);
+        topo.insert_edge((3, NodeType::Server), (1, NodeType::Drone));
+        topo.insert_edge((1, NodeType::Drone), (0, NodeType::Client));
+        topo.observe_failure(1);
+
+        let snapshot = topo.snapshot();
+        assert_eq!(snapshot.node_id, 3);
+        assert_eq!(snapshot.edges, vec![(0, 1), (1, 3)]);
+        assert_eq!(
+            snapshot.nodes,
+            vec![
+                NodeSnapshot {
+                    id: 0,
+                    node_type: NodeType::Client,
+                    observed_drop_rate: 0.5,
+                },
+                NodeSnapshot {
+                    id: 1,
+                    node_type: NodeType::Drone,
+                    observed_drop_rate: 2.0 / 3.0,
+                },
+                NodeSnapshot {
+                    id: 3,
+                    node_type: NodeType::Server,
+                    observed_drop_rate: 0.5,
+                },
+            ]
+        );
+
+        let bytes = postcard::to_allocvec(&snapshot).expect("Snapshot should serialize");
+        let decoded: TopologySnapshot =
+            postcard::from_bytes(&bytes).expect("Snapshot should deserialize");
+        assert_eq!(decoded, snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_of_empty_topology() {
+        let topo = Topology::new(3);
+        let snapshot = topo.snapshot();
+
+        assert!(snapshot.edges.is_empty());
+        assert_eq!(snapshot.nodes.len(), 1);
+        assert_eq!(snapshot.nodes[0].node_type, NodeType::Server);
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let mut topo = Topology::new(3);
+        topo.insert_edge((3, NodeType::Server), (1, NodeType::Drone));
+        topo.insert_edge((1, NodeType::Drone), (0, NodeType::Client));
+
+        let dot = topo.to_dot();
+        assert!(dot.starts_with("graph topology_3 {"));
+        assert!(dot.contains("0 [label=\"0 Client\\npdr 0.50\", shape=ellipse];"));
+        assert!(dot.contains("3 [label=\"3 Server\\npdr 0.50\", shape=box, style=bold];"));
+        assert!(dot.contains("    0 -- 1;"));
+        assert!(dot.contains("    1 -- 3;"));
+        assert!(dot.ends_with('}'));
+    }
+
+    mod equivalence {
+        use super::super::dense::DenseTopology;
+        use super::super::*;
+        use proptest::prelude::*;
+
+        const NODES: NodeId = 24;
+
+        fn node_type() -> impl Strategy<Value = NodeType> {
+            prop_oneof![
+                6 => Just(NodeType::Drone),
+                1 => Just(NodeType::Client),
+                1 => Just(NodeType::Server),
+            ]
+        }
+
+        fn edge() -> impl Strategy<Value = ((NodeId, NodeType), (NodeId, NodeType))> {
+            ((0..NODES, node_type()), (0..NODES, node_type()))
+        }
+
+        fn build(
+            owner: NodeId,
+            edges: &[((NodeId, NodeType), (NodeId, NodeType))],
+            removed: &[(NodeId, NodeId)],
+            failures: &[NodeId],
+        ) -> (Topology, DenseTopology) {
+            let mut sparse = Topology::new(owner);
+            let mut dense = DenseTopology::new(owner);
+
+            for (node1, node2) in edges {
+                sparse.insert_edge(*node1, *node2);
+                dense.insert_edge(*node1, *node2);
+            }
+
+            for (node1, node2) in removed {
+                sparse.remove_edge(*node1, *node2);
+                dense.remove_edge(*node1, *node2);
+            }
+
+            for node in failures {
+                sparse.observe_failure(*node);
+                dense.observe_failure(*node);
+            }
+
+            (sparse, dense)
+        }
+
+        proptest! {
+            #[test]
+            fn bfs_paths_match(
+                owner in 0..NODES,
+                edges in prop::collection::vec(edge(), 0..80),
+                removed in prop::collection::vec((0..NODES, 0..NODES), 0..10),
+                dest in 0..NODES,
+            ) {
+                let (sparse, dense) = build(owner, &edges, &removed, &[]);
+
+                match (sparse.bfs(owner, dest), dense.bfs(owner, dest)) {
+                    (Ok(a), Ok(b)) => prop_assert_eq!(a, b),
+                    (Err(RoutingError::NoPathFound), Err(RoutingError::NoPathFound)) => {}
+                    (Err(RoutingError::SourceIsDest), Err(RoutingError::SourceIsDest)) => {}
+                    (a, b) => prop_assert!(false, "sparse {:?} != dense {:?}", a, b),
+                }
+            }
+
+            #[test]
+            fn dijkstra_paths_match(
+                owner in 0..NODES,
+                edges in prop::collection::vec(edge(), 0..80),
+                removed in prop::collection::vec((0..NODES, 0..NODES), 0..10),
+                failures in prop::collection::vec(0..NODES, 0..40),
+                dest in 0..NODES,
+            ) {
+                let (sparse, dense) = build(owner, &edges, &removed, &failures);
+
+                match (sparse.dijkstra(owner, dest), dense.dijkstra(owner, dest)) {
+                    (Ok(a), Ok(b)) => prop_assert_eq!(a, b),
+                    (Err(RoutingError::NoPathFound), Err(RoutingError::NoPathFound)) => {}
+                    (Err(RoutingError::SourceIsDest), Err(RoutingError::SourceIsDest)) => {}
+                    (a, b) => prop_assert!(false, "sparse {:?} != dense {:?}", a, b),
+                }
+            }
+        }
+    }
 }
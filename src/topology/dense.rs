@@ -0,0 +1,211 @@
+//! Fixed-size reference implementation of the topology, kept to benchmark and cross-check the
+//! sparse `Topology`.
+
+#![cfg_attr(not(feature = "bench"), allow(dead_code))]
+
+use super::{Rate, RoutingError, State, ESTIMATED_UPDATE_TIME};
+use bitvec::prelude::*;
+use std::{
+    collections::{BinaryHeap, VecDeque},
+    time::Instant,
+};
+use wg_2024::{network::NodeId, packet::NodeType};
+
+pub const NETWORK_SIZE: usize = 256;
+
+/// Represents the network topology as an undirected graph over the whole `NodeId` space.
+///
+/// It uses a bit matrix (`BitArray`) to represent edges between nodes, so every instance
+/// allocates room for `NETWORK_SIZE` nodes regardless of how many are actually known.
+pub struct DenseTopology {
+    node_id: NodeId,
+    graph: [BitArray<[u8; 32]>; NETWORK_SIZE],
+    types: [NodeType; NETWORK_SIZE],
+    observed_trend: [Rate; NETWORK_SIZE],
+    last_reset: Option<Instant>,
+}
+
+impl DenseTopology {
+    pub fn new(node_id: NodeId) -> Self {
+        Self {
+            node_id,
+            graph: [BitArray::new([0; 32]); NETWORK_SIZE],
+            types: {
+                let mut types = [NodeType::Drone; NETWORK_SIZE];
+                types[node_id as usize] = NodeType::Server;
+                types
+            },
+            observed_trend: [const { Rate::new() }; NETWORK_SIZE],
+            last_reset: None,
+        }
+    }
+
+    /// Inserts an edge between two nodes in the topology.
+    pub fn insert_edge(&mut self, node1: (NodeId, NodeType), node2: (NodeId, NodeType)) {
+        let node1_id = node1.0 as usize;
+        let node2_id = node2.0 as usize;
+
+        self.graph[node1_id].set(node2_id, true);
+        self.graph[node2_id].set(node1_id, true);
+
+        if self.node_id != node1.0 {
+            self.types[node1_id] = node1.1;
+        }
+
+        if self.node_id != node2.0 {
+            self.types[node2_id] = node2.1;
+        }
+    }
+
+    /// Removes an edge between two nodes in the topology.
+    pub fn remove_edge(&mut self, node1_id: NodeId, node2_id: NodeId) {
+        let n1_id = node1_id as usize;
+        let n2_id = node2_id as usize;
+
+        self.graph[n1_id].set(n2_id, false);
+        self.graph[n2_id].set(n1_id, false);
+    }
+
+    /// Finds the shortest path between two nodes using BFS.
+    ///
+    /// See `Topology::bfs`.
+    pub fn bfs(&self, source: NodeId, dest: NodeId) -> Result<Vec<NodeId>, RoutingError> {
+        let source_id = source as usize;
+        let dest_id = dest as usize;
+
+        if source == dest {
+            return Err(RoutingError::SourceIsDest);
+        }
+
+        let mut visited = vec![false; NETWORK_SIZE];
+        let mut parent = vec![None; NETWORK_SIZE];
+        let mut queue = VecDeque::new();
+
+        visited[source_id] = true;
+        queue.push_back(source_id);
+
+        while let Some(current) = queue.pop_front() {
+            for neighbor in self.graph[current].iter_ones() {
+                if !visited[neighbor] {
+                    visited[neighbor] = true;
+                    parent[neighbor] = Some(current);
+
+                    if neighbor == dest_id {
+                        let mut path = vec![dest];
+                        let mut current_node = dest_id;
+
+                        while let Some(p) = parent[current_node] {
+                            path.push(p as NodeId);
+                            current_node = p;
+                        }
+
+                        path.reverse();
+                        return Ok(path);
+
+                    //Routing with a node different from drone in the middle is illegal
+                    } else if self.types[neighbor] == NodeType::Drone {
+                        queue.push_back(neighbor);
+                    }
+                }
+            }
+        }
+
+        Err(RoutingError::NoPathFound)
+    }
+
+    /// Finds the path with the lowest observed drop rate between two nodes.
+    ///
+    /// See `Topology::dijkstra`.
+    pub fn dijkstra(&self, source: NodeId, dest: NodeId) -> Result<Vec<NodeId>, RoutingError> {
+        let source_id = source as usize;
+        let dest_id = dest as usize;
+
+        if source == dest {
+            return Err(RoutingError::SourceIsDest);
+        }
+
+        let mut dist: Vec<f64> = (0..NETWORK_SIZE).map(|_| f64::INFINITY).collect();
+        let mut prev: Vec<Option<usize>> = vec![None; NETWORK_SIZE];
+        let mut heap = BinaryHeap::new();
+
+        dist[source_id] = 0.0;
+        heap.push(State {
+            pdr: 0.0,
+            position: source_id,
+        });
+
+        while let Some(State { pdr, position }) = heap.pop() {
+            if position == dest_id {
+                let mut path = Vec::new();
+                let mut current = Some(dest_id);
+
+                while let Some(node) = current {
+                    path.push(node as NodeId);
+                    current = prev[node];
+                }
+
+                path.reverse();
+                return Ok(path);
+            }
+
+            if pdr > dist[position] {
+                continue;
+            }
+
+            for neighbor in self.graph[position].iter_ones() {
+                // a non-drone node cant be used in the middle of the path
+                if neighbor != dest_id && self.types[neighbor] != NodeType::Drone {
+                    continue;
+                }
+
+                let next_pdr = pdr + (1.0 - pdr) * self.observed_trend[neighbor].pdr();
+                if next_pdr < dist[neighbor] {
+                    dist[neighbor] = next_pdr;
+                    prev[neighbor] = Some(position);
+                    heap.push(State {
+                        pdr: next_pdr,
+                        position: neighbor,
+                    });
+                }
+            }
+        }
+
+        Err(RoutingError::NoPathFound)
+    }
+
+    /// Resets the topology by clearing all edges and resetting node types. The topology is
+    /// considered updating for a while after `now`.
+    pub fn reset(&mut self, now: Instant) {
+        self.graph = [BitArray::new([0; 32]); NETWORK_SIZE];
+        self.types = [NodeType::Drone; NETWORK_SIZE];
+
+        self.last_reset = Some(now);
+    }
+
+    /// Checks if the topology is currently updating.
+    pub fn is_updating(&self, now: Instant) -> bool {
+        self.last_reset.is_some_and(|last_reset| {
+            now.saturating_duration_since(last_reset) < ESTIMATED_UPDATE_TIME
+        })
+    }
+
+    pub fn observe_success(&mut self, node: NodeId) {
+        self.observed_trend[node as usize].success += 1.0;
+    }
+
+    pub fn observe_failure(&mut self, node: NodeId) {
+        self.observed_trend[node as usize].failure += 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_topology_initialization() {
+        let topo = DenseTopology::new(1);
+        assert_eq!(topo.graph.len(), NETWORK_SIZE);
+        assert_eq!(topo.types.len(), NETWORK_SIZE);
+    }
+}
@@ -0,0 +1,562 @@
+//! Defines how the server hands packets to its neighbors.
+//!
+//! Neighbors in the same process are reached through the `Sender<Packet>` of
+//! `ServerCommand::AddDrone`. Neighbors in other processes are reached through a
+//! `SocketTransport`, which carries packets over TCP or a Unix domain socket, while a
+//! `PacketListener` receives the packets they send. On a socket, every packet is sent as its
+//! postcard serialization preceded by its length as a little-endian `u32`. Frames longer than
+//! `MAX_FRAME_LEN` are rejected, so that a peer cannot make the server allocate arbitrary
+//! amounts of memory.
+
+use crossbeam_channel::{unbounded, Sender};
+use log::warn;
+use std::fmt;
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use wg_2024::packet::Packet;
+
+/// How long to wait between two attempts to connect to a neighbor.
+const RECONNECT_DELAY: Duration = Duration::from_millis(200);
+
+/// Maximum length of a frame, in bytes, without its length prefix.
+pub const MAX_FRAME_LEN: usize = 64 * 1024;
+
+/// Returns the error for a frame longer than `MAX_FRAME_LEN`.
+pub(crate) fn frame_too_long(len: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame of {} bytes exceeds {} bytes", len, MAX_FRAME_LEN),
+    )
+}
+
+/// A way of sending packets to a neighbor.
+pub trait Transport: Send + fmt::Debug {
+    /// Hands a packet over to the neighbor, without blocking.
+    fn send(&self, packet: Packet) -> Result<(), TransportError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportError {
+    /// The neighbor can not receive packets anymore.
+    Disconnected,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Disconnected => write!(f, "the neighbor is disconnected"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+impl Transport for Sender<Packet> {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        Sender::send(self, packet).map_err(|_| TransportError::Disconnected)
+    }
+}
+
+/// The address of a node in another process.
+///
+/// Written as `host:port` for TCP and `unix:<path>` for Unix domain sockets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Endpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+
+        s.parse()
+            .map(Endpoint::Tcp)
+            .map_err(|err| format!("Invalid endpoint {}: {}", s, err))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Writes a packet as a length-prefixed postcard frame.
+pub fn write_packet(writer: &mut impl Write, packet: &Packet) -> io::Result<()> {
+    let bytes = postcard::to_allocvec(packet)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    if bytes.len() > MAX_FRAME_LEN {
+        return Err(frame_too_long(bytes.len()));
+    }
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+/// Reads a packet written by `write_packet`.
+///
+/// Fails with `io::ErrorKind::InvalidData` if the frame is longer than `MAX_FRAME_LEN`, without
+/// reading it.
+pub fn read_packet(reader: &mut impl Read) -> io::Result<Packet> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(frame_too_long(len));
+    }
+    let mut bytes = vec![0u8; len];
+    reader.read_exact(&mut bytes)?;
+    postcard::from_bytes(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn connect(endpoint: &Endpoint) -> io::Result<Box<dyn Write + Send>> {
+    match endpoint {
+        Endpoint::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr)?)),
+        #[cfg(unix)]
+        Endpoint::Unix(path) => Ok(Box::new(UnixStream::connect(path)?)),
+    }
+}
+
+/// Sends packets to a node of another process over a socket.
+///
+/// Packets are queued and written by a background thread, which connects to the endpoint and
+/// reconnects whenever the connection is lost, so `send` never blocks the server. Once the
+/// transport is dropped, the thread writes the packets still queued while the connection
+/// lasts, and stops at the first failed attempt to connect.
+#[derive(Debug)]
+pub struct SocketTransport {
+    endpoint: Endpoint,
+    queue: Sender<Packet>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl SocketTransport {
+    pub fn connect(endpoint: Endpoint) -> Self {
+        let (queue, packet_recv) = unbounded::<Packet>();
+        let thread_endpoint = endpoint.clone();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let thread_dropped = Arc::clone(&dropped);
+
+        thread::spawn(move || {
+            let mut writer: Option<BufWriter<Box<dyn Write + Send>>> = None;
+
+            for packet in packet_recv {
+                loop {
+                    if writer.is_none() {
+                        match connect(&thread_endpoint) {
+                            Ok(stream) => writer = Some(BufWriter::new(stream)),
+                            Err(err) => {
+                                warn!("Could not reach {}: {}", thread_endpoint, err);
+                                if thread_dropped.load(Ordering::Relaxed) {
+                                    return;
+                                }
+                                thread::sleep(RECONNECT_DELAY);
+                                continue;
+                            }
+                        }
+                    }
+                    let Some(connection) = writer.as_mut() else {
+                        continue;
+                    };
+
+                    match write_packet(connection, &packet) {
+                        Ok(()) => break,
+                        Err(err) => {
+                            warn!("Lost connection to {}: {}", thread_endpoint, err);
+                            writer = None;
+                        }
+                    }
+                }
+            }
+        });
+
+        Self {
+            endpoint,
+            queue,
+            dropped,
+        }
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+}
+
+impl Drop for SocketTransport {
+    fn drop(&mut self) {
+        self.dropped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Transport for SocketTransport {
+    fn send(&self, packet: Packet) -> Result<(), TransportError> {
+        self.queue
+            .send(packet)
+            .map_err(|_| TransportError::Disconnected)
+    }
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+/// Receives the packets nodes of other processes send over sockets.
+pub struct PacketListener {
+    listener: Listener,
+}
+
+impl PacketListener {
+    /// Binds to the endpoint.
+    ///
+    /// A Unix domain socket left behind by a listener that is gone, e.g. of a crashed process,
+    /// is removed first. Sockets still accepting connections, and files that are not sockets,
+    /// are left alone, so binding to them fails.
+    pub fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        let listener = match endpoint {
+            Endpoint::Tcp(addr) => Listener::Tcp(TcpListener::bind(addr)?),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                remove_stale_socket(path)?;
+                Listener::Unix(UnixListener::bind(path)?, path.clone())
+            }
+        };
+
+        Ok(Self { listener })
+    }
+
+    /// Returns the endpoint the listener is bound to, with the actual port if it was bound
+    /// to port `0`.
+    pub fn local_endpoint(&self) -> io::Result<Endpoint> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().map(Endpoint::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Endpoint::Unix(path.clone())),
+        }
+    }
+
+    /// Accepts connections in a background thread and forwards every packet received on them
+    /// to `packet_send`, usually the packet channel of a `Server`.
+    pub fn spawn(self, packet_send: Sender<Packet>) -> JoinHandle<()> {
+        thread::spawn(move || loop {
+            let stream: io::Result<Box<dyn Read + Send>> = match &self.listener {
+                Listener::Tcp(listener) => listener
+                    .accept()
+                    .map(|(stream, _)| Box::new(stream) as Box<dyn Read + Send>),
+                #[cfg(unix)]
+                Listener::Unix(listener, _) => listener
+                    .accept()
+                    .map(|(stream, _)| Box::new(stream) as Box<dyn Read + Send>),
+            };
+
+            match stream {
+                Ok(stream) => {
+                    let packet_send = packet_send.clone();
+                    thread::spawn(move || forward_incoming(stream, packet_send));
+                }
+                Err(err) => warn!("Could not accept a connection: {}", err),
+            }
+        })
+    }
+}
+
+/// Removes the socket at `path` if no listener accepts connections on it anymore.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(path).is_err() {
+                fs::remove_file(path)?;
+            }
+            Ok(())
+        }
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Forwards the packets read from a connection until it is closed.
+fn forward_incoming(stream: Box<dyn Read + Send>, packet_send: Sender<Packet>) {
+    let mut reader = BufReader::new(stream);
+
+    loop {
+        match read_packet(&mut reader) {
+            Ok(packet) => {
+                if packet_send.send(packet).is_err() {
+                    return;
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return,
+            Err(err) => {
+                warn!("Dropping connection: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::AssemblerStatus;
+    use crate::assemblers_manager::AssemblersManager;
+    use crate::fragmenter::Fragmenter;
+    use crate::specialized_behavior::{AssembledResponse, RequestKind};
+    use crate::{ControlCommand, Server};
+    use postcard::{from_bytes, to_allocvec};
+    use rust_roveri_api::{ChatRequest, ChatResponse, Request, Response};
+    use rust_roveri_api::{ServerCommand, ServerEvent, ServerType};
+    use std::env;
+    use std::io::BufRead;
+    use std::process::{Command, Stdio};
+    use wg_2024::network::{NodeId, SourceRoutingHeader};
+    use wg_2024::packet::{Ack, PacketType};
+
+    const PEER_ID: NodeId = 90;
+    const SERVER_ID: NodeId = 91;
+
+    /// Tells `peer_process` the endpoint of the server when it is run as a child process.
+    const PEER_ENV: &str = "ROVERI_TRANSPORT_PEER";
+    /// Prefix of the line on which the peer announces its own endpoint.
+    const ENDPOINT_LINE: &str = "peer endpoint: ";
+
+    #[test]
+    fn test_endpoint_parsing() {
+        let tcp: Endpoint = "127.0.0.1:7000".parse().expect("Valid TCP endpoint");
+        assert_eq!(tcp, Endpoint::Tcp("127.0.0.1:7000".parse().unwrap()));
+        assert_eq!(tcp.to_string(), "127.0.0.1:7000");
+
+        #[cfg(unix)]
+        {
+            let unix: Endpoint = "unix:/tmp/server.sock"
+                .parse()
+                .expect("Valid Unix endpoint");
+            assert_eq!(unix, Endpoint::Unix(PathBuf::from("/tmp/server.sock")));
+            assert_eq!(unix.to_string(), "unix:/tmp/server.sock");
+        }
+
+        assert!("not an endpoint".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn test_framing() {
+        let packet = Packet {
+            routing_header: SourceRoutingHeader {
+                hop_index: 1,
+                hops: vec![1, 2, 3],
+            },
+            session_id: 4,
+            pack_type: PacketType::Ack(Ack { fragment_index: 5 }),
+        };
+
+        let mut buffer = Vec::new();
+        write_packet(&mut buffer, &packet).expect("Could not write packet");
+        write_packet(&mut buffer, &packet).expect("Could not write packet");
+
+        let mut reader = buffer.as_slice();
+        for _ in 0..2 {
+            let read = read_packet(&mut reader).expect("Could not read packet");
+            assert_eq!(read.session_id, 4);
+            assert_eq!(read.routing_header.hops, vec![1, 2, 3]);
+        }
+        assert_eq!(
+            read_packet(&mut reader).map_err(|err| err.kind()).err(),
+            Some(io::ErrorKind::UnexpectedEof)
+        );
+
+        // An oversized length is rejected before anything is allocated
+        let mut oversized = ((MAX_FRAME_LEN + 1) as u32).to_le_bytes().to_vec();
+        oversized.extend([0; 16]);
+        assert_eq!(
+            read_packet(&mut oversized.as_slice())
+                .map_err(|err| err.kind())
+                .err(),
+            Some(io::ErrorKind::InvalidData)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stale_unix_socket() {
+        let path = env::temp_dir().join(format!("server_stale_{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let endpoint = Endpoint::Unix(path.clone());
+
+        let listener = PacketListener::bind(&endpoint).expect("Could not bind the listener");
+        // A live listener is not replaced
+        assert!(PacketListener::bind(&endpoint).is_err());
+
+        // Dropping the listener leaves the socket file behind
+        drop(listener);
+        assert!(path.exists());
+        let listener = PacketListener::bind(&endpoint).expect("Could not bind again");
+        drop(listener);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    /// The other side of `test_two_processes`: a client living in a separate process.
+    ///
+    /// Does nothing unless it is spawned by `test_two_processes`.
+    #[test]
+    fn peer_process() {
+        let server_endpoint: Endpoint = match env::var(PEER_ENV) {
+            Ok(endpoint) => endpoint.parse().expect("Invalid server endpoint"),
+            Err(_) => return,
+        };
+
+        let listener = PacketListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .expect("Could not bind the peer listener");
+        println!(
+            "{}{}",
+            ENDPOINT_LINE,
+            listener.local_endpoint().expect("Unbound listener")
+        );
+        let (packet_send, packet_recv) = unbounded();
+        listener.spawn(packet_send);
+
+        // Wait for the server to know about this peer
+        let mut line = String::new();
+        let _ = io::stdin().read_line(&mut line);
+
+        let transport = SocketTransport::connect(server_endpoint);
+        let request = Request::Chat(ChatRequest::Register(
+            "ciao".to_string(),
+            "cane".to_string(),
+        ));
+        let fragments = Fragmenter::new().to_fragment_vec(AssembledResponse {
+            data: to_allocvec(&request).expect("Could not serialize the request"),
+            dest: SERVER_ID,
+            kind: RequestKind::from(&request),
+        });
+        for to_be_sent in fragments {
+            let packet = Packet {
+                routing_header: SourceRoutingHeader {
+                    hop_index: 1,
+                    hops: vec![PEER_ID, SERVER_ID],
+                },
+                session_id: 1,
+                pack_type: PacketType::MsgFragment(to_be_sent.fragment),
+            };
+            assert!(transport.send(packet).is_ok());
+        }
+
+        let mut assemblers_manager = AssemblersManager::new();
+        loop {
+            let packet = packet_recv
+                .recv_timeout(Duration::from_secs(5))
+                .expect("The server did not answer");
+            let fragment = match packet.pack_type {
+                PacketType::MsgFragment(fragment) => fragment,
+                _ => continue,
+            };
+
+            if let Ok(AssemblerStatus::Complete) =
+                assemblers_manager.insert_fragment(fragment, packet.session_id)
+            {
+                let data = match assemblers_manager.retrieve_assembled(packet.session_id) {
+                    Ok(data) => data,
+                    Err(_) => panic!("Could not retrieve the assembled response"),
+                };
+                assert!(matches!(
+                    from_bytes::<Response>(&data),
+                    Ok(Response::Chat(ChatResponse::ClientList(..)))
+                ));
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_two_processes() {
+        let (command_send, command_recv) = unbounded::<ServerCommand>();
+        let (packet_send, packet_recv) = unbounded::<Packet>();
+        let (event_send, _event_recv) = unbounded::<ServerEvent>();
+        let (control_send, control_recv) = unbounded();
+        let (control_event_send, _control_event_recv) = unbounded();
+
+        let mut server = Server::new(
+            SERVER_ID,
+            command_recv,
+            packet_recv,
+            event_send,
+            ServerType::Chat,
+        );
+        server.set_control_channels(control_recv, control_event_send);
+
+        let listener = PacketListener::bind(&"127.0.0.1:0".parse().unwrap())
+            .expect("Could not bind the server listener");
+        let server_endpoint = listener.local_endpoint().expect("Unbound listener");
+        listener.spawn(packet_send);
+        let server_handle = thread::spawn(move || server.run());
+
+        let mut peer = Command::new(env::current_exe().expect("No test executable"))
+            .args([
+                "--exact",
+                "transport::tests::peer_process",
+                "--nocapture",
+                "--test-threads=1",
+            ])
+            .env(PEER_ENV, server_endpoint.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("Could not spawn the peer process");
+
+        // Keep reading the output of the peer, so that it never writes to a closed pipe
+        let stdout = peer.stdout.take().expect("No peer stdout");
+        let (endpoint_send, endpoint_recv) = unbounded::<String>();
+        thread::spawn(move || {
+            for line in io::BufReader::new(stdout).lines().map_while(Result::ok) {
+                if let Some(endpoint) = line.strip_prefix(ENDPOINT_LINE) {
+                    let _ = endpoint_send.send(endpoint.to_string());
+                }
+            }
+        });
+        let peer_endpoint: Endpoint = endpoint_recv
+            .recv_timeout(Duration::from_secs(10))
+            .expect("The peer did not announce its endpoint")
+            .parse()
+            .expect("Invalid peer endpoint");
+
+        let _ = control_send.send(ControlCommand::AddNeighbor(
+            PEER_ID,
+            Box::new(SocketTransport::connect(peer_endpoint)),
+        ));
+        let mut stdin = peer.stdin.take().expect("No peer stdin");
+        let _ = writeln!(stdin, "go");
+
+        let status = peer.wait().expect("Could not wait for the peer");
+        assert!(status.success(), "The peer process failed");
+
+        let _ = command_send.send(ServerCommand::Crash);
+        assert!(server_handle.join().is_ok(), "Server panicked");
+    }
+}